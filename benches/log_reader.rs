@@ -0,0 +1,68 @@
+//! Measures how quickly `LogReader` parses an uncompressed access log, both
+//! from memory and from a source that returns one byte per read.
+//!
+//! Run with `cargo bench --bench log_reader`.
+
+use std::io::{self, Read};
+use std::time::{Duration, Instant};
+
+use crabtrics::access_logs::LogReader;
+
+const LINE: &str = "172.56.208.121 - - [08/May/2023:15:08:30 +0000] \"GET /episode-001.m4a \
+                    HTTP/1.1\" 206 106500 \"https://wayofthecrab.com/\" \"Mozilla/5.0 (iPhone; \
+                    CPU iPhone OS 16_4_1 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) \
+                    Version/16.4 Mobile/15E148 Safari/604.1\"\n";
+const LINES: usize = 500_000;
+const RUNS: usize = 5;
+
+/// Returns at most one byte per read, like reading unbuffered from a file
+/// one byte at a time.
+struct OneByteAtATime<'a>(&'a [u8]);
+
+impl Read for OneByteAtATime<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some((first, remaining)) = self.0.split_first() else {
+            return Ok(0);
+        };
+        buf[0] = *first;
+        self.0 = remaining;
+        Ok(1)
+    }
+}
+
+/// Reads every line from `source`, returning how long the fastest of
+/// [`RUNS`] runs took.
+fn fastest_run<'a, R: Read>(log: &'a [u8], source: impl Fn(&'a [u8]) -> R) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            let mut reader = LogReader::new(source(log));
+            let mut lines = 0;
+            while reader.read_one().unwrap().is_some() {
+                lines += 1;
+            }
+            assert_eq!(lines, LINES);
+            start.elapsed()
+        })
+        .min()
+        .expect("at least one run")
+}
+
+fn main() {
+    let log = LINE.repeat(LINES);
+    let mebibytes = log.len() as f64 / f64::from(1 << 20);
+    for (name, elapsed) in [
+        ("in memory", fastest_run(log.as_bytes(), |log| log)),
+        (
+            "one byte per read",
+            fastest_run(log.as_bytes(), OneByteAtATime),
+        ),
+    ] {
+        println!(
+            "{name}: {LINES} lines ({mebibytes:.0} MiB) in {elapsed:.2?}, {:.0} MiB/s, {:.0} \
+             lines/s",
+            mebibytes / elapsed.as_secs_f64(),
+            LINES as f64 / elapsed.as_secs_f64(),
+        );
+    }
+}
@@ -1,13 +1,14 @@
+use std::collections::HashMap;
 use std::io::{self, ErrorKind, Read};
 use std::net::IpAddr;
+use std::ops::Range;
 use std::str;
 
-use time::format_description::modifier::{
-    Day, Hour, Minute, Month, MonthRepr, OffsetHour, OffsetMinute, Second, Year,
-};
+use serde::Deserialize;
+use time::format_description::modifier::{Day, Hour, Minute, Month, MonthRepr, Second, Year};
 use time::format_description::Component;
 use time::parsing::Parsed;
-use time::OffsetDateTime;
+use time::{OffsetDateTime, PrimitiveDateTime, UtcOffset};
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct LogEntry<'s> {
@@ -15,15 +16,101 @@ pub struct LogEntry<'s> {
     pub time: OffsetDateTime,
     pub method: &'s str,
     pub path: &'s str,
+    /// The HTTP version of the request, such as `HTTP/1.1`, or empty if the
+    /// request line didn't include one.
+    pub protocol: &'s str,
     pub response_code: u16,
     pub bytes_sent: u32,
     pub referrer: &'s str,
     pub user_agent: &'s str,
+    /// The request's `Range` header, such as `bytes=0-`, when the log has a
+    /// range field and the request had one.
+    pub range: Option<&'s str>,
 }
 
+/// The format access logs were written in.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[cfg_attr(not(target_arch = "wasm32"), derive(clap::ValueEnum))]
+pub enum LogFormat {
+    /// nginx's `combined` format, optionally followed by a `Range` header.
+    #[default]
+    NginxCombined,
+    /// Apache's `combined` format, whose identity and user fields can be
+    /// anything rather than just `-`.
+    ApacheCombined,
+    /// Caddy's default JSON access log, with one object per line.
+    CaddyJson,
+}
+
+/// The fields of a line of Caddy's JSON access log that are read.
+#[derive(Debug, Deserialize)]
+struct CaddyLine {
+    /// Seconds since the Unix epoch, with a fraction.
+    ts: f64,
+    request: CaddyRequest,
+    #[serde(default)]
+    size: u32,
+    status: u16,
+}
+
+#[derive(Debug, Deserialize)]
+struct CaddyRequest {
+    /// The address of the peer, which is a proxy if the request was proxied.
+    remote_ip: String,
+    /// The address of the client, which Caddy 2.7 and later log when it's
+    /// behind a trusted proxy.
+    #[serde(default)]
+    client_ip: String,
+    proto: String,
+    method: String,
+    uri: String,
+    #[serde(default)]
+    headers: HashMap<String, Vec<String>>,
+}
+
+impl CaddyRequest {
+    /// Returns the first value of the header `name`, which Caddy logs in its
+    /// canonical form, such as `User-Agent`.
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(name)
+            .and_then(|values| values.first())
+            .map(String::as_str)
+    }
+}
+
+/// Where each field of a line was found in the scratch buffer, once every
+/// field has been parsed and checked.
+struct LineFields {
+    requestor: IpAddr,
+    time: OffsetDateTime,
+    request: Range<usize>,
+    response_code: u16,
+    bytes_sent: u32,
+    referrer: Range<usize>,
+    user_agent: Range<usize>,
+    range: Option<Range<usize>>,
+}
+
+/// The number of bytes requested from the source at a time.
+const BLOCK_SIZE: usize = 16 * 1024;
+
 pub struct LogReader<R> {
     source: R,
     scratch: Vec<u8>,
+    block: Box<[u8]>,
+    block_start: usize,
+    block_end: usize,
+    month_names: HashMap<String, time::Month>,
+    format: LogFormat,
+    separator: u8,
+    assumed_offset: Option<UtcOffset>,
+    range_field: bool,
+    skip_malformed: bool,
+    malformed_lines: u64,
+    /// The last line read in Caddy's format, which the returned entry
+    /// borrows from.
+    caddy_line: Option<(IpAddr, OffsetDateTime, CaddyLine)>,
 }
 
 impl<R> LogReader<R>
@@ -34,10 +121,144 @@ where
         Self {
             source,
             scratch: Vec::new(),
+            block: vec![0; BLOCK_SIZE].into_boxed_slice(),
+            block_start: 0,
+            block_end: 0,
+            month_names: HashMap::new(),
+            format: LogFormat::NginxCombined,
+            separator: b' ',
+            assumed_offset: None,
+            range_field: false,
+            skip_malformed: false,
+            malformed_lines: 0,
+            caddy_line: None,
         }
     }
 
+    /// Reads lines written in `format` instead of nginx's `combined` format.
+    /// The separator, month names, assumed offset, and range field only apply
+    /// to the combined formats.
+    pub fn with_format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Expects fields to be separated by `separator` instead of a space, such
+    /// as `\t` for tab-delimited logs. The request line inside the quotes is
+    /// still split on spaces.
+    pub fn with_separator(mut self, separator: u8) -> Self {
+        self.separator = separator;
+        self
+    }
+
+    /// Accepts `month_names` as abbreviated months in addition to the English
+    /// ones, such as `mai` for May from a server logging in German. Names are
+    /// matched case-insensitively.
+    pub fn with_month_names(mut self, month_names: &HashMap<String, time::Month>) -> Self {
+        self.month_names = month_names
+            .iter()
+            .map(|(name, month)| (name.to_lowercase(), *month))
+            .collect();
+        self
+    }
+
+    /// Interprets timestamps logged without an offset, such as
+    /// `[08/May/2023:15:08:30]`, as being in `offset`. Timestamps with an
+    /// offset still use their own.
+    pub fn with_assumed_offset(mut self, offset: UtcOffset) -> Self {
+        self.assumed_offset = Some(offset);
+        self
+    }
+
+    /// Reads a quoted `Range` header after the user agent, as logged by an
+    /// nginx format like:
+    ///
+    /// ```nginx
+    /// log_format ranges '$remote_addr - $remote_user [$time_local] '
+    ///                   '"$request" $status $body_bytes_sent '
+    ///                   '"$http_referer" "$http_user_agent" "$http_range"';
+    /// ```
+    ///
+    /// nginx logs `-` for requests without a `Range` header. Lines without
+    /// the field are still read, without a range.
+    pub fn with_range_field(mut self) -> Self {
+        self.range_field = true;
+        self
+    }
+
+    /// Skips lines that can't be parsed, warning about each one, instead of
+    /// returning an error. Errors reading from the source are still returned.
+    pub fn with_malformed_lines_skipped(mut self) -> Self {
+        self.skip_malformed = true;
+        self
+    }
+
+    /// Returns how many malformed lines have been skipped so far.
+    pub fn malformed_lines(&self) -> u64 {
+        self.malformed_lines
+    }
+
     pub fn read_one(&mut self) -> anyhow::Result<Option<LogEntry<'_>>> {
+        if self.format == LogFormat::CaddyJson {
+            return self.read_caddy_entry();
+        }
+        let fields = loop {
+            match self.read_fields() {
+                Ok(Some(fields)) => break fields,
+                Ok(None) => return Ok(None),
+                Err(err) if !self.skip_malformed || err.is::<io::Error>() => return Err(err),
+                Err(err) => {
+                    eprintln!("Skipping a malformed log line: {err}");
+                    self.malformed_lines += 1;
+                    // The scratch buffer starts at the malformed line. A scan
+                    // that ran past its end has consumed the lines after it,
+                    // which are read again from its newline.
+                    match memchr::memchr(b'\n', &self.scratch) {
+                        Some(newline) => {
+                            let consumed = self.scratch.split_off(newline + 1);
+                            self.unread(&consumed);
+                        }
+                        None => match self.scan_until(b'\n') {
+                            Ok(_) => {}
+                            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                            Err(err) => anyhow::bail!(err),
+                        },
+                    }
+                }
+            }
+        };
+
+        let request = str::from_utf8(&self.scratch[fields.request])?;
+        let (method, path, protocol) = if request.is_empty() || fields.response_code == 400 {
+            ("", "", "")
+        } else {
+            let Some((method, remaining)) = request.split_once(' ') else { anyhow::bail!("invalid http request") };
+            let (path, protocol) = remaining.split_once(' ').unwrap_or((remaining, ""));
+            (method, path, protocol)
+        };
+
+        Ok(Some(LogEntry {
+            requestor: fields.requestor,
+            time: fields.time,
+            method,
+            path,
+            protocol,
+            response_code: fields.response_code,
+            bytes_sent: fields.bytes_sent,
+            referrer: str::from_utf8(&self.scratch[fields.referrer])?,
+            user_agent: str::from_utf8(&self.scratch[fields.user_agent])?,
+            range: match fields.range {
+                Some(range) if &self.scratch[range.clone()] != b"-" => {
+                    Some(str::from_utf8(&self.scratch[range])?)
+                }
+                _ => None,
+            },
+        }))
+    }
+
+    /// Reads the next line with a valid address, returning where its fields
+    /// are in the scratch buffer.
+    fn read_fields(&mut self) -> anyhow::Result<Option<LineFields>> {
         loop {
             self.scratch.clear();
 
@@ -51,92 +272,278 @@ where
                 Err(err) => anyhow::bail!(err),
             }
 
-            let requestor_end = self.scan_until_slice(b" - ")?;
-            let requestor: IpAddr = str::from_utf8(&self.scratch[0..requestor_end])?.parse()?;
+            let separator = self.separator;
+            let requestor_end = match self.format {
+                LogFormat::ApacheCombined => self.scan_until(separator)?,
+                _ => self.scan_until_slice(&[separator, b'-', separator])?,
+            };
+            // A line without the separators after its address is garbage, and
+            // the scan continued into the next one, whose address starts after
+            // the last newline.
+            let requestor_start = memchr::memrchr(b'\n', &self.scratch[..requestor_end])
+                .map_or(0, |newline| newline + 1);
+            if self.skip_malformed {
+                let skipped = memchr::memchr_iter(b'\n', &self.scratch[..requestor_end]).count();
+                if skipped > 0 {
+                    eprintln!("Skipping {skipped} log lines without any fields");
+                    self.malformed_lines += skipped as u64;
+                }
+            }
+            // Keep the scratch buffer starting at the line being read.
+            self.scratch.drain(..requestor_start);
+            let requestor_end = requestor_end - requestor_start;
+            let Some(requestor) = parse_requestor(&self.scratch[..requestor_end]) else {
+                // Skip the rest of a line with a malformed address.
+                if self.skip_malformed {
+                    eprintln!("Skipping a log line with a malformed address");
+                    self.malformed_lines += 1;
+                }
+                match self.scan_until(b'\n') {
+                    Ok(_) => continue,
+                    Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                    Err(err) => anyhow::bail!(err),
+                }
+            };
             self.scan_until(b'[')?;
-            self.scratch.clear();
-            let time_end = self.scan_until_slice(b"] \"")?;
-            let time = parse_log_date(&self.scratch[..time_end]).unwrap();
-            self.scratch.clear();
-            let request_end = self.scan_until_slice(b"\" ")?;
+            let time_start = self.scratch.len();
+            let time_end = self.scan_until_slice(&[b']', separator, b'"'])?;
+            let time = parse_log_date(
+                &self.scratch[time_start..time_end],
+                &self.month_names,
+                self.assumed_offset,
+            )?;
+            let request_start = self.scratch.len();
+            let request_end = self.scan_until_slice(&[b'"', separator])?;
 
             let response_code_start = self.scratch.len();
-            let response_code_end = self.scan_until(b' ')?;
+            let response_code_end = self.scan_until(separator)?;
             let response_code: u16 =
                 str::from_utf8(&self.scratch[response_code_start..response_code_end])?.parse()?;
             let bytes_sent_start = self.scratch.len();
-            let bytes_sent_end = self.scan_until_slice(b" \"")?;
-            let bytes_sent: u32 =
-                str::from_utf8(&self.scratch[bytes_sent_start..bytes_sent_end])?.parse()?;
+            let bytes_sent_end = self.scan_until_slice(&[separator, b'"'])?;
+            let bytes_sent = parse_byte_count(&self.scratch[bytes_sent_start..bytes_sent_end])?;
             let referrer_start = self.scratch.len();
-            let referrer_end = self.scan_until_slice(b"\" \"")?;
+            let referrer_end = self.scan_until_slice(&[b'"', separator, b'"'])?;
             let user_agent_start = self.scratch.len();
-            let user_agent_end = self.scan_until_slice(b"\"\n")?;
-
-            let request = str::from_utf8(&self.scratch[..request_end])?;
-            let (method, path) = if request.is_empty() || response_code == 400 {
-                ("", "")
-            } else {
-                let Some((method, remaining)) = request.split_once(' ') else { anyhow::bail!("invalid http request") };
-                let Some((path, _)) = remaining.split_once(' ') else { anyhow::bail!("invalid http request") };
-                (method, path)
-            };
+            let mut user_agent_end = self.scan_until_slice(b"\"\n")?;
+            // A line missing a field ends before its fields were all found,
+            // and they were read from the lines after it instead.
+            if memchr::memchr(b'\n', &self.scratch[..user_agent_end]).is_some() {
+                anyhow::bail!("the line ended before all of its fields");
+            }
+            let mut range = None;
+            if self.range_field {
+                if let Some(range_separator) = memchr::memmem::rfind(
+                    &self.scratch[user_agent_start..user_agent_end],
+                    &[b'"', separator, b'"'],
+                ) {
+                    range = Some(user_agent_start + range_separator + 3..user_agent_end);
+                    user_agent_end = user_agent_start + range_separator;
+                }
+            }
 
-            return Ok(Some(LogEntry {
+            let request = str::from_utf8(&self.scratch[request_start..request_end])?;
+            if !request.is_empty() && response_code != 400 && !request.contains(' ') {
+                anyhow::bail!("invalid http request");
+            }
+            str::from_utf8(&self.scratch[referrer_start..referrer_end])?;
+            str::from_utf8(&self.scratch[user_agent_start..user_agent_end])?;
+            if let Some(range) = &range {
+                str::from_utf8(&self.scratch[range.clone()])?;
+            }
+
+            return Ok(Some(LineFields {
                 requestor,
                 time,
-                method,
-                path,
+                request: request_start..request_end,
                 response_code,
                 bytes_sent,
-                referrer: str::from_utf8(&self.scratch[referrer_start..referrer_end])?,
-                user_agent: str::from_utf8(&self.scratch[user_agent_start..user_agent_end])?,
+                referrer: referrer_start..referrer_end,
+                user_agent: user_agent_start..user_agent_end,
+                range,
             }));
         }
     }
 
+    /// Reads the next line of Caddy's JSON access log.
+    ///
+    /// Caddy logs every request header, so the range is read without
+    /// `with_range_field`, and missing referrers and user agents are returned
+    /// as `-` like nginx logs them.
+    fn read_caddy_entry(&mut self) -> anyhow::Result<Option<LogEntry<'_>>> {
+        loop {
+            self.scratch.clear();
+            match self.scan_until(b'\n') {
+                Ok(_) => {}
+                // The last line is parsed whether or not it ends in a newline.
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                    if self.scratch.is_empty() {
+                        return Ok(None);
+                    }
+                }
+                Err(err) => anyhow::bail!(err),
+            }
+            if self.scratch.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+
+            match parse_caddy_line(&self.scratch) {
+                Ok(line) => {
+                    self.caddy_line = Some(line);
+                    break;
+                }
+                Err(err) if self.skip_malformed => {
+                    eprintln!("Skipping a malformed log line: {err}");
+                    self.malformed_lines += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        let (requestor, time, line) = self.caddy_line.as_ref().expect("line was just read");
+        Ok(Some(LogEntry {
+            requestor: *requestor,
+            time: *time,
+            method: &line.request.method,
+            path: &line.request.uri,
+            protocol: &line.request.proto,
+            response_code: line.status,
+            bytes_sent: line.size,
+            referrer: line.request.header("Referer").unwrap_or("-"),
+            user_agent: line.request.header("User-Agent").unwrap_or("-"),
+            range: line.request.header("Range"),
+        }))
+    }
+
+    /// Reads another block from the source if every byte of the current block
+    /// has been consumed.
+    fn fill_block(&mut self) -> io::Result<()> {
+        while self.block_start == self.block_end {
+            match self.source.read(&mut self.block) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(read) => {
+                    self.block_start = 0;
+                    self.block_end = read;
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Puts `bytes` back in front of the buffered block, so that they're read
+    /// again before anything else from the source.
+    fn unread(&mut self, bytes: &[u8]) {
+        if bytes.len() <= self.block_start {
+            self.block_start -= bytes.len();
+            self.block[self.block_start..self.block_start + bytes.len()].copy_from_slice(bytes);
+            return;
+        }
+
+        let mut block = [bytes, &self.block[self.block_start..self.block_end]].concat();
+        self.block_start = 0;
+        self.block_end = block.len();
+        block.resize(block.len().max(BLOCK_SIZE), 0);
+        self.block = block.into_boxed_slice();
+    }
+
     fn read_byte(&mut self) -> io::Result<u8> {
-        let mut bytes = [0];
-        self.source.read_exact(&mut bytes)?;
-        self.scratch.push(bytes[0]);
-        Ok(bytes[0])
+        self.fill_block()?;
+        let byte = self.block[self.block_start];
+        self.block_start += 1;
+        self.scratch.push(byte);
+        Ok(byte)
     }
 
     fn scan_until(&mut self, byte: u8) -> io::Result<usize> {
         loop {
-            let read = self.read_byte()?;
-            if read == byte {
+            self.fill_block()?;
+            let buffered = &self.block[self.block_start..self.block_end];
+            if let Some(index) = memchr::memchr(byte, buffered) {
+                let consumed = index + 1;
+                self.scratch.extend_from_slice(&buffered[..consumed]);
+                self.block_start += consumed;
                 return Ok(self.scratch.len() - 1);
             }
+
+            let consumed = buffered.len();
+            self.scratch.extend_from_slice(buffered);
+            self.block_start += consumed;
         }
     }
 
     fn scan_until_slice(&mut self, s: &[u8]) -> io::Result<usize> {
-        assert!(!s.is_empty());
-
-        'search: loop {
-            self.scan_until(s[0])?;
-            for byte in &s[1..] {
-                let read = self.read_byte()?;
-                if *byte != read {
-                    continue 'search;
-                }
-            }
+        let (&last, _) = s.split_last().expect("empty delimiter");
+        let start = self.scratch.len();
 
-            return Ok(self.scratch.len() - s.len());
+        // Scanning for the final byte and then looking backwards never skips
+        // over a match that overlaps a partial one, such as `""\n` when
+        // searching for `"\n`.
+        loop {
+            self.scan_until(last)?;
+            if self.scratch.len() - start >= s.len() && self.scratch.ends_with(s) {
+                return Ok(self.scratch.len() - s.len());
+            }
         }
     }
 }
 
-fn parse_log_date(bytes: &[u8]) -> anyhow::Result<OffsetDateTime> {
+/// Parses a client address, including IPv6 addresses wrapped in brackets like
+/// `[2001:db8::1]` or with a zone like `fe80::1%eth0`, neither of which
+/// `IpAddr` accepts on its own. Zones are dropped.
+fn parse_requestor(bytes: &[u8]) -> Option<IpAddr> {
+    let address = str::from_utf8(bytes).ok()?;
+    let address = address
+        .strip_prefix('[')
+        .and_then(|address| address.strip_suffix(']'))
+        .unwrap_or(address);
+    let address = address
+        .split_once('%')
+        .map_or(address, |(address, _)| address);
+    address.parse().ok()
+}
+
+/// Parses a line of Caddy's JSON access log, returning the client's address
+/// and the time of the request, truncated to the second like the other
+/// formats, along with the line.
+fn parse_caddy_line(bytes: &[u8]) -> anyhow::Result<(IpAddr, OffsetDateTime, CaddyLine)> {
+    let line: CaddyLine = serde_json::from_slice(bytes)?;
+    let address = if line.request.client_ip.is_empty() {
+        &line.request.remote_ip
+    } else {
+        &line.request.client_ip
+    };
+    let Some(requestor) = parse_requestor(address.as_bytes()) else {
+        anyhow::bail!("invalid client address {address:?}");
+    };
+    let time = OffsetDateTime::from_unix_timestamp(line.ts.floor() as i64)?;
+    Ok((requestor, time, line))
+}
+
+fn parse_log_date(
+    bytes: &[u8],
+    month_names: &HashMap<String, time::Month>,
+    assumed_offset: Option<UtcOffset>,
+) -> anyhow::Result<OffsetDateTime> {
     let mut time = Parsed::new();
     let time_bytes = time.parse_component(bytes, Component::Day(Day::default()))?;
     if time_bytes[0] != b'/' {
         anyhow::bail!("missing / after day");
     }
-    let mut month = Month::default();
-    month.repr = MonthRepr::Short;
-    let time_bytes = time.parse_component(&time_bytes[1..], Component::Month(month))?;
+    let time_bytes = &time_bytes[1..];
+    let month_end = memchr::memchr(b'/', time_bytes).unwrap_or(time_bytes.len());
+    let localized_month = str::from_utf8(&time_bytes[..month_end])
+        .ok()
+        .and_then(|name| month_names.get(&name.to_lowercase()));
+    let time_bytes = if let Some(month) = localized_month {
+        time.set_month(*month);
+        &time_bytes[month_end..]
+    } else {
+        let mut month = Month::default();
+        month.repr = MonthRepr::Short;
+        time.parse_component(time_bytes, Component::Month(month))?
+    };
     if time_bytes[0] != b'/' {
         anyhow::bail!("missing / after month");
     }
@@ -155,19 +562,47 @@ fn parse_log_date(bytes: &[u8]) -> anyhow::Result<OffsetDateTime> {
     }
     let time_bytes =
         time.parse_component(&time_bytes[1..], Component::Second(Second::default()))?;
+    if time_bytes.is_empty() {
+        let Some(offset) = assumed_offset else {
+            anyhow::bail!("missing offset after second");
+        };
+        return Ok(PrimitiveDateTime::try_from(time)?.assume_offset(offset));
+    }
     if time_bytes[0] != b' ' {
         anyhow::bail!("missing ` ` after second");
     }
-    let time_bytes = time.parse_component(
-        &time_bytes[1..],
-        Component::OffsetHour(OffsetHour::default()),
-    )?;
-    let time_bytes =
-        time.parse_component(time_bytes, Component::OffsetMinute(OffsetMinute::default()))?;
-    if !time_bytes.is_empty() {
-        anyhow::bail!("invalid time format: extra trailing data");
+    let offset = parse_offset(&time_bytes[1..])?;
+    Ok(PrimitiveDateTime::try_from(time)?.assume_offset(offset))
+}
+
+/// Parses an offset such as `-0700`.
+///
+/// The sign applies to the minutes as well as the hours, including when there
+/// are no hours, so `-0030` is half an hour behind UTC.
+fn parse_offset(bytes: &[u8]) -> anyhow::Result<UtcOffset> {
+    let (sign, digits) = match bytes {
+        [b'+', digits @ ..] => (1, digits),
+        [b'-', digits @ ..] => (-1, digits),
+        _ => anyhow::bail!("missing + or - before offset"),
+    };
+    let &[hours_tens, hours, minutes_tens, minutes] = digits else {
+        anyhow::bail!("invalid offset: expected four digits");
+    };
+    if !digits.iter().all(u8::is_ascii_digit) {
+        anyhow::bail!("invalid offset: expected four digits");
+    }
+    let hours = (hours_tens - b'0') * 10 + (hours - b'0');
+    let minutes = (minutes_tens - b'0') * 10 + (minutes - b'0');
+    Ok(UtcOffset::from_hms(sign * hours as i8, sign * minutes as i8, 0)?)
+}
+
+/// Parses the number of bytes sent, which nginx logs as `-` when a
+/// connection is closed before any of the body is sent.
+fn parse_byte_count(bytes: &[u8]) -> anyhow::Result<u32> {
+    match bytes {
+        b"" | b"-" => Ok(0),
+        bytes => Ok(str::from_utf8(bytes)?.parse()?),
     }
-    Ok(time.try_into()?)
 }
 
 #[test]
@@ -189,10 +624,12 @@ fn parsing() {
         .assume_utc(),
         method: "GET",
         path: "/episode-001.m4a",
+        protocol: "HTTP/1.1",
         response_code: 206,
         bytes_sent: 212_698,
         referrer: "https://wayofthecrab.com/",
-        user_agent: "Mozilla/5.0 (iPhone; CPU iPhone OS 16_4_1 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.4 Mobile/15E148 Safari/604.1"
+        user_agent: "Mozilla/5.0 (iPhone; CPU iPhone OS 16_4_1 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.4 Mobile/15E148 Safari/604.1",
+        range: None,
     });
     let line_two = reader.read_one().unwrap().unwrap();
     assert_eq!(line_two,
@@ -205,12 +642,419 @@ fn parsing() {
                 .assume_utc(),
                 method: "GET",
                 path: "/episode-001.m4a",
+                protocol: "HTTP/1.1",
                 response_code: 206,
                 bytes_sent: 303,
                 referrer: "https://wayofthecrab.com/",
-                user_agent: "Mozilla/5.0 (iPhone; CPU iPhone OS 16_4_1 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.4 Mobile/15E148 Safari/604.1"
+                user_agent: "Mozilla/5.0 (iPhone; CPU iPhone OS 16_4_1 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.4 Mobile/15E148 Safari/604.1",
+                range: None,
             }
 
     );
     assert!(reader.read_one().unwrap().is_none());
 }
+
+#[test]
+fn overlapping_delimiters() {
+    /// Returns one byte per read to exercise delimiters split across blocks.
+    struct OneByteAtATime<'a>(&'a [u8]);
+
+    impl Read for OneByteAtATime<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some((first, remaining)) = self.0.split_first() else { return Ok(0) };
+            buf[0] = *first;
+            self.0 = remaining;
+            Ok(1)
+        }
+    }
+
+    for (contents, delimiter, expected) in [
+        (&b"agent\"\"\nnext"[..], &b"\"\n"[..], 6),
+        (&b"referrer\"\" \"agent"[..], &b"\" \""[..], 9),
+        (&b"a - - ["[..], &b" - "[..], 1),
+        (&b"aab"[..], &b"ab"[..], 1),
+    ] {
+        let mut reader = LogReader::new(contents);
+        assert_eq!(reader.scan_until_slice(delimiter).unwrap(), expected);
+        assert_eq!(reader.scratch, &contents[..expected + delimiter.len()]);
+
+        let mut reader = LogReader::new(OneByteAtATime(contents));
+        assert_eq!(reader.scan_until_slice(delimiter).unwrap(), expected);
+    }
+
+    let mut reader = LogReader::new(&b"\"\" \"\""[..]);
+    assert_eq!(
+        reader.scan_until_slice(b"\"\n").unwrap_err().kind(),
+        ErrorKind::UnexpectedEof
+    );
+}
+
+#[test]
+fn missing_protocol() {
+    let mut reader = LogReader::new(
+        &br#"10.0.0.1 - - [08/May/2023:15:08:30 +0000] "GET /episode-001.m4a" 200 303 "-" "curl/8.1.2"
+"#[..],
+    );
+    let entry = reader.read_one().unwrap().unwrap();
+    assert_eq!(entry.path, "/episode-001.m4a");
+    assert_eq!(entry.protocol, "");
+}
+
+#[test]
+fn http2_protocol() {
+    let mut reader = LogReader::new(
+        &br#"10.0.0.1 - - [08/May/2023:15:08:30 +0000] "GET /episode-001.m4a HTTP/2.0" 200 303 "-" "Overcast/3.0"
+"#[..],
+    );
+    let entry = reader.read_one().unwrap().unwrap();
+    assert_eq!(entry.method, "GET");
+    assert_eq!(entry.path, "/episode-001.m4a");
+    assert_eq!(entry.protocol, "HTTP/2.0");
+}
+
+#[test]
+fn missing_bytes_sent() {
+    let mut reader = LogReader::new(
+        &br#"10.0.0.1 - - [08/May/2023:15:08:30 +0000] "GET /episode-001.m4a HTTP/1.1" 200 - "-" "curl/8.1.2"
+10.0.0.1 - - [08/May/2023:15:08:31 +0000] "GET /episode-001.m4a HTTP/1.1" 499 303 "-" "curl/8.1.2"
+"#[..],
+    );
+    let entry = reader.read_one().unwrap().unwrap();
+    assert_eq!(entry.response_code, 200);
+    assert_eq!(entry.bytes_sent, 0);
+    assert_eq!(entry.referrer, "-");
+    assert_eq!(entry.user_agent, "curl/8.1.2");
+    assert_eq!(reader.read_one().unwrap().unwrap().bytes_sent, 303);
+    assert!(reader.read_one().unwrap().is_none());
+
+    assert_eq!(parse_byte_count(b"").unwrap(), 0);
+    assert!(parse_byte_count(b"12x").is_err());
+}
+
+#[test]
+fn range_field() {
+    let logs = br#"10.0.0.1 - - [08/May/2023:15:08:30 +0000] "GET /episode-001.m4a HTTP/1.1" 206 303 "-" "AppleCoreMedia/1.0.0" "bytes=0-"
+10.0.0.1 - - [08/May/2023:15:08:31 +0000] "GET /episode-001.m4a HTTP/1.1" 200 303 "-" "AppleCoreMedia/1.0.0" "-"
+10.0.0.1 - - [08/May/2023:15:08:32 +0000] "GET /episode-001.m4a HTTP/1.1" 200 303 "-" "AppleCoreMedia/1.0.0"
+"#;
+    let mut reader = LogReader::new(&logs[..]).with_range_field();
+    let entry = reader.read_one().unwrap().unwrap();
+    assert_eq!(entry.user_agent, "AppleCoreMedia/1.0.0");
+    assert_eq!(entry.range, Some("bytes=0-"));
+    let entry = reader.read_one().unwrap().unwrap();
+    assert_eq!(entry.user_agent, "AppleCoreMedia/1.0.0");
+    assert_eq!(entry.range, None);
+    let entry = reader.read_one().unwrap().unwrap();
+    assert_eq!(entry.user_agent, "AppleCoreMedia/1.0.0");
+    assert_eq!(entry.range, None);
+    assert!(reader.read_one().unwrap().is_none());
+
+    // Without the option, the field is read as part of the user agent.
+    let mut reader = LogReader::new(&logs[..]);
+    assert_eq!(
+        reader.read_one().unwrap().unwrap().user_agent,
+        "AppleCoreMedia/1.0.0\" \"bytes=0-"
+    );
+}
+
+#[test]
+fn requestor_addresses() {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    assert_eq!(
+        parse_requestor(b"[2001:db8::1]"),
+        Some(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)))
+    );
+    assert_eq!(
+        parse_requestor(b"fe80::1%eth0"),
+        Some(IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1)))
+    );
+    assert_eq!(parse_requestor(b"[10.0.0.1"), None);
+
+    let line = |address: &str| {
+        format!(
+            "{address} - - [08/May/2023:15:08:30 +0000] \"GET /episode-001.m4a HTTP/1.1\" 206 \
+             303 \"-\" \"AppleCoreMedia/1.0.0\"\n"
+        )
+    };
+    let logs = [
+        line("[2001:db8::1]"),
+        line("not-an-address"),
+        String::from("garbage without any fields\n"),
+        line("10.0.0.1"),
+        line("10.0.0.999"),
+    ]
+    .concat();
+    let mut reader = LogReader::new(logs.as_bytes());
+    assert_eq!(
+        reader.read_one().unwrap().unwrap().requestor,
+        IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1))
+    );
+    assert_eq!(
+        reader.read_one().unwrap().unwrap().requestor,
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))
+    );
+    assert!(reader.read_one().unwrap().is_none());
+}
+
+#[test]
+fn localized_month_names() {
+    let month_names = HashMap::from([
+        (String::from("Mär"), time::Month::March),
+        (String::from("mai"), time::Month::May),
+    ]);
+    let date = parse_log_date(b"08/M\xc3\xa4r/2023:15:08:30 +0000", &month_names, None).unwrap();
+    assert_eq!(date.month(), time::Month::March);
+    let date = parse_log_date(b"08/Mai/2023:15:08:30 +0000", &month_names, None).unwrap();
+    assert_eq!(date.month(), time::Month::May);
+    let date = parse_log_date(b"08/Jun/2023:15:08:30 +0000", &month_names, None).unwrap();
+    assert_eq!(date.month(), time::Month::June);
+    assert!(parse_log_date(b"08/Mai/2023:15:08:30 +0000", &HashMap::new(), None).is_err());
+}
+
+#[test]
+fn assumed_offset() {
+    let offset = UtcOffset::from_hms(-7, 0, 0).unwrap();
+    let date = parse_log_date(b"08/May/2023:15:08:30", &HashMap::new(), Some(offset)).unwrap();
+    assert_eq!(date, time::macros::datetime!(2023-05-08 22:08:30 UTC));
+    assert_eq!(date.offset(), offset);
+    assert!(parse_log_date(b"08/May/2023:15:08:30", &HashMap::new(), None).is_err());
+
+    // An offset in the log takes precedence.
+    let date =
+        parse_log_date(b"08/May/2023:15:08:30 +0200", &HashMap::new(), Some(offset)).unwrap();
+    assert_eq!(date, time::macros::datetime!(2023-05-08 13:08:30 UTC));
+
+    const SAMPLE_LOG: &str = "172.56.208.121 - - [08/May/2023:15:08:30] \"GET /episode-001.m4a \
+                              HTTP/1.1\" 206 212698 \"-\" \"AppleCoreMedia/1.0.0\"\n";
+    let mut reader = LogReader::new(SAMPLE_LOG.as_bytes()).with_assumed_offset(UtcOffset::UTC);
+    let entry = reader.read_one().unwrap().unwrap();
+    assert_eq!(entry.time, time::macros::datetime!(2023-05-08 15:08:30 UTC));
+    assert_eq!(entry.bytes_sent, 212_698);
+}
+
+#[test]
+fn negative_offsets() {
+    let parse = |date: &[u8]| parse_log_date(date, &HashMap::new(), None);
+
+    let date = parse(b"08/May/2023:23:30:00 -0700").unwrap();
+    assert_eq!(date, time::macros::datetime!(2023-05-09 06:30:00 UTC));
+    assert_eq!(date.offset(), UtcOffset::from_hms(-7, 0, 0).unwrap());
+    let date = parse(b"08/May/2023:23:30:00 -0330").unwrap();
+    assert_eq!(date, time::macros::datetime!(2023-05-09 03:00:00 UTC));
+    let date = parse(b"08/May/2023:23:30:00 -0030").unwrap();
+    assert_eq!(date, time::macros::datetime!(2023-05-09 00:00:00 UTC));
+    assert_eq!(date.offset(), UtcOffset::from_hms(0, -30, 0).unwrap());
+    let date = parse(b"09/May/2023:00:30:00 +0530").unwrap();
+    assert_eq!(date, time::macros::datetime!(2023-05-08 19:00:00 UTC));
+
+    // The repeated hour when daylight saving time ends, first in MDT and then
+    // in MST, is two different hours.
+    let daylight = parse(b"05/Nov/2023:01:30:00 -0600").unwrap();
+    let standard = parse(b"05/Nov/2023:01:30:00 -0700").unwrap();
+    assert_eq!(daylight, time::macros::datetime!(2023-11-05 07:30:00 UTC));
+    assert_eq!(standard - daylight, time::Duration::HOUR);
+
+    for invalid in [
+        &b"08/May/2023:23:30:00 0700"[..],
+        b"08/May/2023:23:30:00 -07",
+        b"08/May/2023:23:30:00 -07:00",
+        b"08/May/2023:23:30:00 -0700 ",
+        b"08/May/2023:23:30:00 -2600",
+    ] {
+        assert!(
+            parse(invalid).is_err(),
+            "{}",
+            String::from_utf8_lossy(invalid)
+        );
+    }
+}
+
+#[test]
+fn tab_separated() {
+    use std::net::Ipv4Addr;
+
+    const SAMPLE_LOG: &str = "172.56.208.121\t-\t-\t[08/May/2023:15:08:30 +0000]\t\
+                              \"GET /episode-001.m4a HTTP/1.1\"\t206\t212698\t\
+                              \"https://wayofthecrab.com/\"\t\
+                              \"Mozilla/5.0 (iPhone; CPU iPhone OS 16_4_1 like Mac OS X)\"\n";
+    let mut reader = LogReader::new(SAMPLE_LOG.as_bytes()).with_separator(b'\t');
+    let entry = reader.read_one().unwrap().unwrap();
+    assert_eq!(entry.requestor, IpAddr::V4(Ipv4Addr::new(172, 56, 208, 121)));
+    assert_eq!(entry.method, "GET");
+    assert_eq!(entry.path, "/episode-001.m4a");
+    assert_eq!(entry.protocol, "HTTP/1.1");
+    assert_eq!(entry.response_code, 206);
+    assert_eq!(entry.bytes_sent, 212_698);
+    assert_eq!(entry.referrer, "https://wayofthecrab.com/");
+    assert_eq!(
+        entry.user_agent,
+        "Mozilla/5.0 (iPhone; CPU iPhone OS 16_4_1 like Mac OS X)"
+    );
+    assert!(reader.read_one().unwrap().is_none());
+}
+
+#[test]
+fn malformed_lines() {
+    const GOOD_LINE: &str = "10.0.0.1 - - [08/May/2023:15:08:30 +0000] \"GET /episode-001.m4a \
+                             HTTP/1.1\" 206 303 \"-\" \"AppleCoreMedia/1.0.0\"\n";
+    const BROKEN_LINE: &str = "10.0.0.2 - - [08/Mayo/2023:15:08:30 +0000] \"GET \
+                               /episode-001.m4a HTTP/1.1\" 206 303 \"-\" \"Overcast/3.0\"\n";
+    let logs = [GOOD_LINE, BROKEN_LINE, GOOD_LINE].concat();
+
+    let mut reader = LogReader::new(logs.as_bytes()).with_malformed_lines_skipped();
+    let mut entries = 0;
+    while let Some(entry) = reader.read_one().unwrap() {
+        assert_eq!(entry.user_agent, "AppleCoreMedia/1.0.0");
+        entries += 1;
+    }
+    assert_eq!(entries, 2);
+    assert_eq!(reader.malformed_lines(), 1);
+
+    // Without skipping, the broken line is an error rather than a panic.
+    let mut reader = LogReader::new(logs.as_bytes());
+    assert!(reader.read_one().unwrap().is_some());
+    assert!(reader.read_one().is_err());
+}
+
+#[test]
+fn malformed_line_before_a_good_one() {
+    const MISSING_TIME: &str = "10.0.0.2 - - \"GET /episode-001.m4a HTTP/1.1\" 206 303 \"-\" \
+                                \"Overcast/3.0\"\n";
+    const GOOD_LINE: &str = "10.0.0.1 - - [08/May/2023:15:08:30 +0000] \"GET /episode-001.m4a \
+                             HTTP/1.1\" 206 303 \"-\" \"AppleCoreMedia/1.0.0\"\n";
+    let logs = [MISSING_TIME, GOOD_LINE].concat();
+
+    // Looking for the first line's timestamp reads into the second line,
+    // which is still read on its own.
+    let mut reader = LogReader::new(logs.as_bytes()).with_malformed_lines_skipped();
+    let entry = reader.read_one().unwrap().unwrap();
+    assert_eq!(entry.requestor, "10.0.0.1".parse::<IpAddr>().unwrap());
+    assert_eq!(entry.user_agent, "AppleCoreMedia/1.0.0");
+    assert!(reader.read_one().unwrap().is_none());
+    assert_eq!(reader.malformed_lines(), 1);
+}
+
+#[test]
+fn year_boundaries() {
+    let parse = |date: &[u8]| parse_log_date(date, &HashMap::new(), None);
+
+    assert_eq!(
+        parse(b"31/Dec/2023:23:59:59 +0000").unwrap(),
+        time::macros::datetime!(2023-12-31 23:59:59 UTC)
+    );
+    assert_eq!(
+        parse(b"01/Jan/2024:00:00:00 +0000").unwrap(),
+        time::macros::datetime!(2024-01-01 00:00:00 UTC)
+    );
+    // An offset can move a timestamp into the previous or next year in UTC.
+    assert_eq!(
+        parse(b"01/Jan/2024:00:30:00 +0100").unwrap(),
+        time::macros::datetime!(2023-12-31 23:30:00 UTC)
+    );
+    assert_eq!(
+        parse(b"31/Dec/2023:23:59:59 -0100").unwrap(),
+        time::macros::datetime!(2024-01-01 00:59:59 UTC)
+    );
+    // Years are always four digits, so a two-digit year isn't guessed at.
+    assert!(parse(b"31/Dec/23:23:59:59 +0000").is_err());
+    assert!(parse(b"31/Dec/02023:23:59:59 +0000").is_err());
+}
+
+/// The entry that each format's sample line in the tests below is read as.
+#[cfg(test)]
+fn sample_entry() -> LogEntry<'static> {
+    use std::net::Ipv4Addr;
+
+    LogEntry {
+        requestor: IpAddr::V4(Ipv4Addr::new(172, 56, 208, 121)),
+        time: time::macros::datetime!(2023-05-08 15:08:30 UTC),
+        method: "GET",
+        path: "/episode-001.m4a",
+        protocol: "HTTP/1.1",
+        response_code: 206,
+        bytes_sent: 212_698,
+        referrer: "https://wayofthecrab.com/",
+        user_agent: "AppleCoreMedia/1.0.0",
+        range: Some("bytes=0-"),
+    }
+}
+
+#[test]
+fn nginx_combined_format() {
+    const SAMPLE_LOG: &str = "172.56.208.121 - - [08/May/2023:15:08:30 +0000] \"GET \
+                              /episode-001.m4a HTTP/1.1\" 206 212698 \
+                              \"https://wayofthecrab.com/\" \"AppleCoreMedia/1.0.0\" \
+                              \"bytes=0-\"\n";
+    let mut reader = LogReader::new(SAMPLE_LOG.as_bytes())
+        .with_format(LogFormat::NginxCombined)
+        .with_range_field();
+    assert_eq!(reader.read_one().unwrap().unwrap(), sample_entry());
+    assert!(reader.read_one().unwrap().is_none());
+}
+
+#[test]
+fn apache_combined_format() {
+    const SAMPLE_LOG: &str = "172.56.208.121 crab frank [08/May/2023:15:08:30 +0000] \"GET \
+                              /episode-001.m4a HTTP/1.1\" 206 212698 \
+                              \"https://wayofthecrab.com/\" \"AppleCoreMedia/1.0.0\" \
+                              \"bytes=0-\"\n";
+    let mut reader = LogReader::new(SAMPLE_LOG.as_bytes())
+        .with_format(LogFormat::ApacheCombined)
+        .with_range_field();
+    assert_eq!(reader.read_one().unwrap().unwrap(), sample_entry());
+    assert!(reader.read_one().unwrap().is_none());
+
+    // nginx only logs `-` for the identity.
+    let mut reader = LogReader::new(SAMPLE_LOG.as_bytes());
+    assert!(reader.read_one().is_err());
+}
+
+#[test]
+fn caddy_json_format() {
+    const SAMPLE_LOG: &str = r#"{"level":"info","ts":1683558510.6234567,"logger":"http.log.access","msg":"handled request","request":{"remote_ip":"10.0.0.1","remote_port":"51234","client_ip":"172.56.208.121","proto":"HTTP/1.1","method":"GET","host":"wayofthecrab.com","uri":"/episode-001.m4a","headers":{"Range":["bytes=0-"],"Referer":["https://wayofthecrab.com/"],"User-Agent":["AppleCoreMedia/1.0.0"]}},"bytes_read":0,"user_id":"","duration":0.0121,"size":212698,"status":206,"resp_headers":{"Content-Type":["audio/mp4"]}}
+
+{"level":"info","ts":1683558511.5,"request":{"remote_ip":"10.0.0.2","proto":"HTTP/2.0","method":"HEAD","uri":"/episode-001.m4a","headers":{}},"status":200}
+{"level":"info","ts":1683558512.5,"request":{"remote_ip":"not-an-address","proto":"HTTP/1.1","method":"GET","uri":"/","headers":{}},"status":200}
+not json
+"#;
+    let mut reader = LogReader::new(SAMPLE_LOG.as_bytes())
+        .with_format(LogFormat::CaddyJson)
+        .with_malformed_lines_skipped();
+    assert_eq!(reader.read_one().unwrap().unwrap(), sample_entry());
+    // Caddy before 2.7 only logs the peer's address, and missing headers are
+    // read like nginx logs them.
+    let entry = reader.read_one().unwrap().unwrap();
+    assert_eq!(entry.requestor, "10.0.0.2".parse::<IpAddr>().unwrap());
+    assert_eq!(entry.time, time::macros::datetime!(2023-05-08 15:08:31 UTC));
+    assert_eq!(entry.method, "HEAD");
+    assert_eq!(entry.protocol, "HTTP/2.0");
+    assert_eq!(entry.bytes_sent, 0);
+    assert_eq!(entry.referrer, "-");
+    assert_eq!(entry.user_agent, "-");
+    assert_eq!(entry.range, None);
+    assert!(reader.read_one().unwrap().is_none());
+    assert_eq!(reader.malformed_lines(), 2);
+}
+
+#[test]
+fn caddy_json_without_final_newline() {
+    const LINE: &str = r#"{"level":"info","ts":1683558511.5,"request":{"remote_ip":"10.0.0.2","proto":"HTTP/2.0","method":"HEAD","uri":"/episode-001.m4a","headers":{}},"status":200}"#;
+
+    let mut reader = LogReader::new(LINE.as_bytes()).with_format(LogFormat::CaddyJson);
+    let entry = reader.read_one().unwrap().unwrap();
+    assert_eq!(entry.requestor, "10.0.0.2".parse::<IpAddr>().unwrap());
+    assert!(reader.read_one().unwrap().is_none());
+
+    // A truncated final line is malformed like any other.
+    let truncated = format!("{LINE}\n{}", &LINE[..LINE.len() / 2]);
+    let mut reader = LogReader::new(truncated.as_bytes()).with_format(LogFormat::CaddyJson);
+    assert!(reader.read_one().unwrap().is_some());
+    assert!(reader.read_one().is_err());
+    let mut reader = LogReader::new(truncated.as_bytes())
+        .with_format(LogFormat::CaddyJson)
+        .with_malformed_lines_skipped();
+    assert!(reader.read_one().unwrap().is_some());
+    assert!(reader.read_one().unwrap().is_none());
+    assert_eq!(reader.malformed_lines(), 1);
+}
@@ -0,0 +1,1872 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::Read;
+use std::net::IpAddr;
+use std::ops::Range;
+use std::time::SystemTime;
+
+use bonsaidb::core::key::time::TimestampAsDays;
+use interner::global::{GlobalPool, GlobalString};
+use time::{OffsetDateTime, UtcOffset};
+
+use crate::access_logs::LogReader;
+use crate::clients::{classify_user_agent, PodcastClient};
+use crate::config::{Config, FutureTimestamps};
+use crate::episodes::{normalize_path, parse_slug_path};
+use crate::hll::HyperLogLog;
+use crate::players::{referrer_source, Player};
+use crate::query::{query_value, split_query};
+use crate::schema::{EpisodeDateKey, PodcastDownloads, RequestorTotal, RequestorTotals};
+use crate::sizes::EpisodeSizes;
+use crate::visitors::{requestor_key, VisitorKey};
+
+static STRINGS: GlobalPool<String> = GlobalPool::new();
+
+/// The requests read from access logs, grouped by episode file and day, that
+/// haven't been classified into downloads yet.
+///
+/// Each requestor's address and user agent are held until the aggregation is
+/// tallied with [`tally_downloads`], and are never stored.
+#[derive(Debug, Default)]
+pub struct Aggregation {
+    files: HashMap<FileDateKey, EpisodeDownloads>,
+}
+
+impl Aggregation {
+    /// Returns the days with requests that were counted.
+    pub fn days(&self) -> BTreeSet<TimestampAsDays> {
+        self.files
+            .iter()
+            .filter(|(_, downloads)| downloads.has_requests())
+            .map(|(key, _)| key.date)
+            .collect()
+    }
+
+    /// Returns an aggregation without any requests that continues each
+    /// requestor's current session from these, so that the next log file's
+    /// requests can be staged in it before being merged into these.
+    pub fn continuing_sessions(&self) -> Self {
+        Self {
+            files: self
+                .files
+                .iter()
+                .map(|(key, downloads)| {
+                    let staged = EpisodeDownloads {
+                        sessions: downloads.sessions.clone(),
+                        ..EpisodeDownloads::default()
+                    };
+                    (key.clone(), staged)
+                })
+                .collect(),
+        }
+    }
+
+    /// Combines the requests in `other` with these.
+    pub fn merge(&mut self, other: Self) {
+        for (key, downloads) in other.files {
+            self.files.entry(key).or_default().merge(downloads);
+        }
+    }
+}
+
+/// The key requests are aggregated by. Identifiers are resolved to episode
+/// numbers when tallying, using the configured bonus episodes.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+struct FileDateKey {
+    identifier: GlobalString,
+    date: TimestampAsDays,
+}
+
+#[derive(Debug, Default)]
+struct EpisodeDownloads {
+    bytes_per_requestor: HashMap<Requestor, HashMap<GlobalString, Downloaded>>,
+    first_requests: HashMap<Requestor, FirstRequest>,
+    sizes: HashMap<GlobalString, u32>,
+    /// Every request, which is only kept when a grace period is configured.
+    requests: Vec<TimedRequest>,
+    /// The bytes sent by every response, regardless of who requested them.
+    bytes_sent: u64,
+    /// The requests from bots, which are only counted when configured.
+    bot_requests: u32,
+    /// The `HEAD` and `304 Not Modified` requests checking the episode.
+    probes: u32,
+    /// The current session of each address and user agent, keyed by its
+    /// first session, along with when it last made a request.
+    sessions: HashMap<Requestor, (u32, OffsetDateTime)>,
+}
+
+/// A single request, kept so that the requests made during an episode's
+/// grace period can be left out after every log has been read.
+#[derive(Debug, Clone)]
+struct TimedRequest {
+    time: OffsetDateTime,
+    requestor: Requestor,
+    kind: GlobalString,
+    response_code: u16,
+    bytes: u32,
+    range_start: Option<u32>,
+}
+
+/// The parts of a file a requestor has downloaded.
+///
+/// A response to a request with a single range is recorded as the span of the
+/// file it sent, so that bytes sent more than once, such as when seeking back
+/// or replaying an episode, are only counted once. Players streaming an
+/// episode request `bytes=0-` and close the connection once they've buffered
+/// enough, which would otherwise add up to a full download over a few plays.
+/// Responses without a range can't be placed in the file, so their bytes are
+/// summed instead.
+#[derive(Debug, Clone, Default)]
+struct Downloaded {
+    /// The bytes sent in responses without a range.
+    bytes: u32,
+    /// The spans sent in responses to ranged requests, sorted by their start
+    /// and neither overlapping nor touching.
+    ranges: Vec<Range<u32>>,
+}
+
+impl Downloaded {
+    /// Records that `range` of the file was sent.
+    fn add_range(&mut self, range: Range<u32>) {
+        if range.is_empty() {
+            return;
+        }
+        let mut merged = range;
+        self.ranges.retain(|existing| {
+            if existing.start > merged.end || merged.start > existing.end {
+                return true;
+            }
+            merged = merged.start.min(existing.start)..merged.end.max(existing.end);
+            false
+        });
+        let index = self
+            .ranges
+            .partition_point(|existing| existing.start < merged.start);
+        self.ranges.insert(index, merged);
+    }
+
+    /// Adds the parts of the file in `other` to these.
+    fn merge(&mut self, other: &Self) {
+        self.bytes = self.bytes.saturating_add(other.bytes);
+        for range in &other.ranges {
+            self.add_range(range.clone());
+        }
+    }
+
+    /// Returns how much of a `size` byte file was downloaded, at most `size`.
+    fn bytes(&self, size: u32) -> u32 {
+        let covered = self
+            .ranges
+            .iter()
+            .map(|range| range.end.min(size).saturating_sub(range.start))
+            .sum::<u32>();
+        self.bytes.saturating_add(covered).min(size)
+    }
+}
+
+/// Details of the earliest request from a requestor.
+#[derive(Debug, Clone)]
+struct FirstRequest {
+    time: OffsetDateTime,
+    /// The request's own address, before it was masked to the requestor's
+    /// network, which is what countries are looked up by.
+    address: IpAddr,
+    referrer: GlobalString,
+    protocol: GlobalString,
+    /// The `utm_campaign` query parameter, or `organic` if there wasn't one.
+    campaign: GlobalString,
+}
+
+/// A distinct network and user agent seen in the logs, with each address
+/// masked by [`requestor_key`] to the configured prefixes. Requestors are
+/// grouped into visitors using a [`VisitorKey`] when tallying.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+struct Requestor {
+    address: IpAddr,
+    user_agent: GlobalString,
+    /// Counts up each time the requestor returns after more than the
+    /// configured dedup window, so that each session's bytes are classified
+    /// as a separate download.
+    session: u32,
+}
+
+/// The combined requests of every requestor identified as one visitor.
+#[derive(Debug)]
+struct Visit {
+    bytes_per_kind: HashMap<GlobalString, Downloaded>,
+    first_request: FirstRequest,
+    user_agent: GlobalString,
+}
+
+impl EpisodeDownloads {
+    /// Groups requestors into visits by their visitor key and session.
+    fn visits(&self, visitor_key: &dyn VisitorKey) -> HashMap<(u64, u32), Visit> {
+        let mut visits = HashMap::<(u64, u32), Visit>::new();
+        for (requestor, by_kind) in &self.bytes_per_requestor {
+            let first_request = &self.first_requests[requestor];
+            let visit = visits
+                .entry((
+                    visitor_key.visitor_key(requestor.address, &requestor.user_agent),
+                    requestor.session,
+                ))
+                .or_insert_with(|| Visit {
+                    bytes_per_kind: HashMap::new(),
+                    first_request: first_request.clone(),
+                    user_agent: requestor.user_agent.clone(),
+                });
+            if first_request.time < visit.first_request.time {
+                visit.first_request = first_request.clone();
+                visit.user_agent = requestor.user_agent.clone();
+            }
+            for (kind, downloaded) in by_kind {
+                visit
+                    .bytes_per_kind
+                    .entry(kind.clone())
+                    .or_default()
+                    .merge(downloaded);
+            }
+        }
+        visits
+    }
+
+    /// Returns the visits that were only Apple Podcasts probing the episode
+    /// with at most `apple_probe_bytes` before the same visitor downloaded all
+    /// of it in a later visit.
+    fn apple_probes(
+        &self,
+        visits: &HashMap<(u64, u32), Visit>,
+        config: &Config,
+    ) -> HashSet<(u64, u32)> {
+        let max_bytes = config.apple_probe_bytes;
+        if max_bytes == 0 {
+            return HashSet::new();
+        }
+        let is_full = |visit: &Visit| {
+            visit.bytes_per_kind.iter().any(|(kind, downloaded)| {
+                let size = self.sizes[kind];
+                is_full_download(downloaded.bytes(size), size, config)
+            })
+        };
+        let mut last_full_download = HashMap::new();
+        for (&(visitor, _), visit) in visits {
+            if is_full(visit) {
+                let last = last_full_download
+                    .entry(visitor)
+                    .or_insert(visit.first_request.time);
+                *last = (*last).max(visit.first_request.time);
+            }
+        }
+        visits
+            .iter()
+            .filter(|(&(visitor, _), visit)| {
+                visit
+                    .bytes_per_kind
+                    .iter()
+                    .map(|(kind, downloaded)| u64::from(downloaded.bytes(self.sizes[kind])))
+                    .sum::<u64>()
+                    <= u64::from(max_bytes)
+                    && !is_full(visit)
+                    && classify_user_agent(&visit.user_agent) == PodcastClient::ApplePodcasts
+                    && last_full_download
+                        .get(&visitor)
+                        .is_some_and(|full| *full > visit.first_request.time)
+            })
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    fn tally(
+        self,
+        episode: u16,
+        config: &Config,
+        visitor_key: &dyn VisitorKey,
+    ) -> PodcastDownloads {
+        let mut downloads = PodcastDownloads {
+            visitors: HyperLogLog::new(config.hll_precision),
+            bytes_sent: self.bytes_sent,
+            bot_requests: self.bot_requests,
+            probes: self.probes,
+            ..PodcastDownloads::default()
+        };
+        let duration = config.episode_durations.get(&episode).copied();
+        let visits = self.visits(visitor_key);
+        let probes = self.apple_probes(&visits, config);
+        for (id, visit) in visits {
+            downloads.visitors.insert(id.0);
+            if probes.contains(&id) {
+                continue;
+            }
+            // A visitor who fetched several formats of the episode made one
+            // download, which is full if any one format was downloaded
+            // entirely.
+            let mut full_kind = None::<String>;
+            let mut listening_seconds = 0;
+            for (kind, downloaded) in visit.bytes_per_kind {
+                let size = *self.sizes.get(&kind).expect("size not computed");
+                let bytes = downloaded.bytes(size);
+                if let Some(duration) = duration {
+                    listening_seconds =
+                        listening_seconds.max(estimated_listening_seconds(bytes, size, duration));
+                }
+                if is_full_download(bytes, size, config)
+                    && full_kind
+                        .as_deref()
+                        .map_or(true, |full| kind.as_str() < full)
+                {
+                    full_kind = Some(kind.to_string());
+                }
+            }
+            downloads.listening_seconds += listening_seconds;
+
+            let Some(kind) = full_kind else {
+                downloads.partial_downloads += 1;
+                continue;
+            };
+            downloads.full_downloads += 1;
+            downloads.full_downloads_by_weekday
+                [weekday_index(visit.first_request.time, config.report_utc_offset)] += 1;
+            let player = Player::classify(
+                &visit.first_request.referrer,
+                &visit.user_agent,
+                &config.players,
+            );
+            downloads.full_downloads_by_player[player as usize] += 1;
+            *downloads
+                .full_downloads_by_protocol
+                .entry(visit.first_request.protocol.to_string())
+                .or_default() += 1;
+            *downloads
+                .full_downloads_by_campaign
+                .entry(visit.first_request.campaign.to_string())
+                .or_default() += 1;
+            *downloads
+                .full_downloads_by_referrer
+                .entry(referrer_source(&visit.first_request.referrer))
+                .or_default() += 1;
+            if let Some(country) = config
+                .countries
+                .as_ref()
+                .and_then(|countries| countries.country_for(visit.first_request.address))
+            {
+                *downloads
+                    .full_downloads_by_country
+                    .entry(country.to_string())
+                    .or_default() += 1;
+            }
+            *downloads
+                .full_downloads_by_client
+                .entry(classify_user_agent(&visit.user_agent).name().to_string())
+                .or_default() += 1;
+            let (extension, variant) = kind.split_once('/').unwrap_or((kind.as_str(), "plain"));
+            *downloads
+                .full_downloads_by_extension
+                .entry(extension.to_string())
+                .or_default() += 1;
+            if !config.episode_variants.is_empty() {
+                *downloads
+                    .full_downloads_by_variant
+                    .entry(variant.to_string())
+                    .or_default() += 1;
+            }
+        }
+        downloads
+    }
+
+    /// Returns true if any request was counted, rather than only the sessions
+    /// continued from another aggregation.
+    fn has_requests(&self) -> bool {
+        !self.bytes_per_requestor.is_empty() || self.bot_requests > 0 || self.probes > 0
+    }
+
+    fn merge(&mut self, other: Self) {
+        for (requestor, by_kind) in other.bytes_per_requestor {
+            let bytes_per_kind = self.bytes_per_requestor.entry(requestor).or_default();
+            for (kind, downloaded) in by_kind {
+                bytes_per_kind.entry(kind).or_default().merge(&downloaded);
+            }
+        }
+        for (requestor, request) in other.first_requests {
+            self.first_requests
+                .entry(requestor)
+                .and_modify(|first| {
+                    if request.time < first.time {
+                        *first = request.clone();
+                    }
+                })
+                .or_insert(request);
+        }
+        self.sizes.extend(other.sizes);
+        self.requests.extend(other.requests);
+        self.bytes_sent += other.bytes_sent;
+        self.bot_requests += other.bot_requests;
+        self.probes += other.probes;
+        for (requestor, (session, last_request)) in other.sessions {
+            let current = self
+                .sessions
+                .entry(requestor)
+                .or_insert((session, last_request));
+            if last_request > current.1 {
+                *current = (session, last_request);
+            }
+        }
+    }
+
+    /// Recounts each requestor's bytes without the requests made before
+    /// `end`, leaving out requestors who made no other requests.
+    fn exclude_requests_before(&mut self, end: OffsetDateTime, config: &Config) {
+        if self.requests.iter().all(|request| request.time >= end) {
+            return;
+        }
+
+        self.bytes_per_requestor.clear();
+        self.bytes_sent = 0;
+        for request in self.requests.iter().filter(|request| request.time >= end) {
+            self.bytes_sent += u64::from(request.bytes);
+            let size = self.sizes[&request.kind];
+            let downloaded = self
+                .bytes_per_requestor
+                .entry(request.requestor.clone())
+                .or_default()
+                .entry(request.kind.clone())
+                .or_default();
+            add_response(
+                downloaded,
+                request.response_code,
+                request.bytes,
+                request.range_start,
+                size,
+                config,
+            );
+        }
+        let bytes_per_requestor = &self.bytes_per_requestor;
+        self.first_requests
+            .retain(|requestor, _| bytes_per_requestor.contains_key(requestor));
+    }
+}
+
+/// Leaves out the requests made within the configured grace period after
+/// each episode file was first requested.
+///
+/// `first_seen` holds the Unix timestamp of each file's first request from
+/// previous imports, and is updated with any earlier requests in
+/// `aggregation`.
+pub fn apply_grace_period(
+    aggregation: &mut Aggregation,
+    first_seen: &mut HashMap<String, i64>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    for (key, downloads) in &aggregation.files {
+        let earliest = downloads
+            .first_requests
+            .values()
+            .map(|request| request.time.unix_timestamp())
+            .min();
+        if let Some(earliest) = earliest {
+            let first = first_seen
+                .entry(key.identifier.to_string())
+                .or_insert(earliest);
+            *first = (*first).min(earliest);
+        }
+    }
+
+    let grace = time::Duration::minutes(i64::from(config.new_episode_grace_minutes));
+    for (key, downloads) in &mut aggregation.files {
+        if let Some(first) = first_seen.get(key.identifier.as_str()) {
+            downloads.exclude_requests_before(
+                OffsetDateTime::from_unix_timestamp(*first)? + grace,
+                config,
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Classifies the aggregated requests into downloads, counting aliased
+/// episodes towards their canonical episode.
+///
+/// Each file is still compared against its own size before being combined, so
+/// a download of an old episode's file is only full if all of that file was
+/// downloaded.
+pub fn tally_downloads(
+    aggregation: Aggregation,
+    config: &Config,
+) -> HashMap<EpisodeDateKey, PodcastDownloads> {
+    tally_downloads_with(aggregation, config, &config.visitor_identity)
+}
+
+/// Classifies the aggregated requests like [`tally_downloads`], grouping
+/// requestors into visitors with `visitor_key` instead of the configured
+/// `[visitor_identity]`.
+pub fn tally_downloads_with(
+    mut aggregation: Aggregation,
+    config: &Config,
+    visitor_key: &dyn VisitorKey,
+) -> HashMap<EpisodeDateKey, PodcastDownloads> {
+    if config.reconcile_partial_downloads {
+        reconcile_across_days(&mut aggregation.files);
+    }
+
+    let mut downloads = HashMap::<EpisodeDateKey, PodcastDownloads>::new();
+    let mut unknown = BTreeSet::new();
+    for (key, info) in aggregation.files {
+        let Some(episode) = config.episode_number(&key.identifier) else {
+            unknown.insert(key.identifier);
+            continue;
+        };
+        let tally = info.tally(episode, config, visitor_key);
+        downloads
+            .entry(EpisodeDateKey {
+                episode: config.canonical_episode(episode),
+                date: key.date,
+            })
+            .or_default()
+            .accumulate(&tally);
+    }
+    for identifier in unknown {
+        eprintln!(
+            "Skipping downloads of episode {identifier}, which isn't a number or a configured \
+             bonus episode"
+        );
+    }
+    downloads
+}
+
+/// Summarizes the bytes each visit in `aggregation` downloaded, without
+/// classifying them, keyed by the episode and day they're tallied under.
+pub fn requestor_totals(
+    aggregation: &Aggregation,
+    config: &Config,
+) -> HashMap<EpisodeDateKey, RequestorTotals> {
+    let mut totals = HashMap::<EpisodeDateKey, RequestorTotals>::new();
+    for (key, info) in &aggregation.files {
+        let Some(episode) = config.episode_number(&key.identifier) else {
+            continue;
+        };
+        let day = totals
+            .entry(EpisodeDateKey {
+                episode: config.canonical_episode(episode),
+                date: key.date,
+            })
+            .or_default();
+        for (kind, size) in &info.sizes {
+            day.sizes.insert(kind.to_string(), *size);
+        }
+        for ((visitor, session), visit) in info.visits(&config.visitor_identity) {
+            day.requestors.push(RequestorTotal {
+                visitor,
+                session,
+                first_request: visit.first_request.time.unix_timestamp(),
+                bytes: visit
+                    .bytes_per_kind
+                    .iter()
+                    .map(|(kind, downloaded)| {
+                        (kind.to_string(), downloaded.bytes(info.sizes[kind]))
+                    })
+                    .collect(),
+            });
+        }
+    }
+    totals
+}
+
+/// Tallies the downloads in `aggregation` into `tallied`, leaving
+/// `aggregation` empty so that none of its requestors are kept any longer.
+pub fn flush_visitor_data(
+    aggregation: &mut Aggregation,
+    tallied: &mut HashMap<EpisodeDateKey, PodcastDownloads>,
+    config: &Config,
+) {
+    for (key, downloads) in tally_downloads(std::mem::take(aggregation), config) {
+        tallied.entry(key).or_default().accumulate(&downloads);
+    }
+}
+
+/// Moves each requestor's bytes for an episode onto the last day they
+/// requested it, so that a download spread over several days is classified
+/// once using all of its bytes.
+fn reconcile_across_days(aggregation: &mut HashMap<FileDateKey, EpisodeDownloads>) {
+    let mut totals =
+        HashMap::<(GlobalString, Requestor, GlobalString), (Downloaded, TimestampAsDays)>::new();
+    for (key, info) in aggregation.iter() {
+        for (requestor, by_kind) in &info.bytes_per_requestor {
+            for (kind, downloaded) in by_kind {
+                let (total, last_day) = totals
+                    .entry((key.identifier.clone(), requestor.clone(), kind.clone()))
+                    .or_insert((Downloaded::default(), key.date));
+                total.merge(downloaded);
+                *last_day = (*last_day).max(key.date);
+            }
+        }
+    }
+
+    for (key, info) in aggregation.iter_mut() {
+        for (requestor, by_kind) in &mut info.bytes_per_requestor {
+            by_kind.retain(|kind, downloaded| {
+                let (total, last_day) =
+                    &totals[&(key.identifier.clone(), requestor.clone(), kind.clone())];
+                *downloaded = total.clone();
+                *last_day == key.date
+            });
+        }
+        info.bytes_per_requestor
+            .retain(|_, by_kind| !by_kind.is_empty());
+    }
+}
+
+/// Estimates how many seconds of an episode `duration` seconds long were
+/// listened to by a requestor that downloaded `bytes` of its `size` byte file.
+///
+/// This assumes a constant bitrate and that everything downloaded was
+/// listened to, and caps repeated downloads at the episode's duration.
+fn estimated_listening_seconds(bytes: u32, size: u32, duration: u32) -> u32 {
+    if size == 0 {
+        return 0;
+    }
+
+    let listened = u64::from(bytes.min(size)) * u64::from(duration) / u64::from(size);
+    u32::try_from(listened).expect("bounded by duration")
+}
+
+/// Returns the index of the weekday, starting with Monday, that `time` falls
+/// on in `offset`.
+fn weekday_index(time: OffsetDateTime, offset: UtcOffset) -> usize {
+    usize::from(time.to_offset(offset).weekday().number_days_from_monday())
+}
+
+/// The lines read from logs, and how many of them were counted towards an
+/// episode's downloads.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct LineCounts {
+    pub read: u64,
+    pub counted: u64,
+    /// Lines that couldn't be parsed, which aren't included in `read`.
+    pub malformed: u64,
+}
+
+/// Aggregates the requests for episode files in the access logs read from
+/// `source` into `aggregation`, leaving out requests made before `threshold`.
+pub fn aggregate_logs<R: Read>(
+    source: R,
+    aggregation: &mut Aggregation,
+    sizes: &mut EpisodeSizes,
+    threshold: OffsetDateTime,
+    config: &Config,
+) -> anyhow::Result<LineCounts> {
+    anyhow::ensure!(
+        config.log_field_separator.is_ascii(),
+        "log field separator must be an ascii character"
+    );
+    anyhow::ensure!(
+        config.full_download_percent > 0.0 && config.full_download_percent <= 100.0,
+        "full_download_percent must be more than 0 and at most 100"
+    );
+    let mut logs = LogReader::new(source)
+        .with_format(config.log_format)
+        .with_malformed_lines_skipped()
+        .with_month_names(&config.month_names)
+        .with_separator(config.log_field_separator as u8);
+    if let Some(offset) = config.log_utc_offset {
+        logs = logs.with_assumed_offset(offset);
+    }
+    if config.log_range_field {
+        logs = logs.with_range_field();
+    }
+    let mut unknown_extensions = BTreeSet::new();
+    let mut unmatched_slugs = BTreeSet::new();
+    let dedup_window = time::Duration::minutes(i64::from(config.dedup_window_minutes));
+    let mut lines = LineCounts::default();
+    let now = OffsetDateTime::now_utc();
+    let latest = now + time::Duration::minutes(i64::from(config.future_tolerance_minutes));
+    let mut future_requests = 0;
+    while let Some(mut log) = logs.read_one()? {
+        lines.read += 1;
+        // Apps check episodes with HEAD requests and conditional GETs, which
+        // are counted separately from downloads.
+        let is_probe = (log.method == "HEAD" && (200..300).contains(&log.response_code))
+            || (log.method == "GET" && log.response_code == 304);
+        // Filter errors.
+        if !is_probe && (log.response_code < 200 || log.response_code > 299 || log.method != "GET")
+        {
+            continue;
+        }
+        if log.time > latest {
+            future_requests += 1;
+            match config.future_timestamps {
+                FutureTimestamps::Accept => {}
+                FutureTimestamps::Clamp => log.time = now,
+                FutureTimestamps::Reject => continue,
+            }
+        }
+        if log.time < threshold {
+            continue;
+        }
+        // Filter old logs we've already aggreg
+        let (path, query) = split_query(log.path);
+        let Some(path) = normalize_path(path) else {
+            continue;
+        };
+        let file = match config
+            .episode_paths
+            .iter()
+            .find_map(|pattern| pattern.parse(&path))
+        {
+            Some(file) => file,
+            // Paths that aren't numbered can name an episode by its slug.
+            None => match parse_slug_path(&path) {
+                Some(file) if config.episode_slugs.contains_key(file.identifier) => file,
+                Some(file)
+                    if config
+                        .episode_extensions
+                        .iter()
+                        .any(|extension| *extension == file.extension) =>
+                {
+                    if !unmatched_slugs.contains(file.identifier) {
+                        unmatched_slugs.insert(file.identifier.to_string());
+                    }
+                    continue;
+                }
+                _ => continue,
+            },
+        };
+        if !config
+            .episode_extensions
+            .iter()
+            .any(|extension| *extension == file.extension)
+        {
+            if !unknown_extensions.contains(file.extension) {
+                eprintln!(
+                    "Skipping downloads of .{} files, which isn't a configured episode extension",
+                    file.extension
+                );
+                unknown_extensions.insert(file.extension.to_string());
+            }
+            continue;
+        }
+        let is_bot = config.bots.is_bot(log.user_agent);
+        if is_bot && !config.bots.count_requests {
+            continue;
+        }
+
+        let episode_downloads = aggregation
+            .files
+            .entry(FileDateKey {
+                identifier: STRINGS.get(file.identifier),
+                date: TimestampAsDays::try_from(SystemTime::from(log.time))?,
+            })
+            .or_default();
+        if is_bot {
+            episode_downloads.bot_requests += 1;
+            continue;
+        }
+        if is_probe {
+            episode_downloads.probes += 1;
+            continue;
+        }
+
+        // Each variant is a separate file, so it is downloaded as a separate
+        // kind, named like `m4a/chaptered`.
+        let kind = match file.suffix.filter(|suffix| {
+            config
+                .episode_variants
+                .iter()
+                .any(|variant| variant == suffix)
+        }) {
+            Some(variant) => STRINGS.get(format!("{}/{variant}", file.extension).as_str()),
+            None => STRINGS.get(file.extension),
+        };
+        // Lookup the file size to be able to compute complete downloads.
+        let size = match episode_downloads.sizes.get(&kind) {
+            Some(size) => *size,
+            None => {
+                let size = sizes.size(&path[1..])?;
+                episode_downloads.sizes.insert(kind.clone(), size);
+                size
+            }
+        };
+
+        // A listener roaming between addresses in one network is one
+        // requestor, whose bytes add up to a single download.
+        let address = requestor_key(
+            log.requestor,
+            config.visitor_identity.ipv4_prefix,
+            config.visitor_identity.ipv6_prefix,
+        );
+        let (session, last_request) = episode_downloads
+            .sessions
+            .entry(Requestor {
+                address,
+                user_agent: STRINGS.get(log.user_agent),
+                session: 0,
+            })
+            .or_insert((0, log.time));
+        if log.time - *last_request > dedup_window {
+            *session += 1;
+        }
+        *last_request = (*last_request).max(log.time);
+        let requestor = Requestor {
+            address,
+            user_agent: STRINGS.get(log.user_agent),
+            session: *session,
+        };
+        episode_downloads
+            .first_requests
+            .entry(requestor.clone())
+            .or_insert_with(|| FirstRequest {
+                time: log.time,
+                address: log.requestor,
+                referrer: STRINGS.get(log.referrer),
+                protocol: STRINGS.get(log.protocol),
+                campaign: STRINGS.get(
+                    query
+                        .and_then(|query| query_value(query, "utm_campaign"))
+                        .filter(|campaign| !campaign.is_empty())
+                        .as_deref()
+                        .unwrap_or("organic"),
+                ),
+            });
+        // A server that ignores a range responds with the file from its start.
+        let range_start = log.range.and_then(|range| {
+            if log.response_code == 206 {
+                range_start(range, size)
+            } else {
+                Some(0)
+            }
+        });
+        if config.new_episode_grace_minutes > 0 {
+            episode_downloads.requests.push(TimedRequest {
+                time: log.time,
+                requestor: requestor.clone(),
+                kind: kind.clone(),
+                response_code: log.response_code,
+                bytes: log.bytes_sent,
+                range_start,
+            });
+        }
+        episode_downloads.bytes_sent += u64::from(log.bytes_sent);
+        let downloaded = episode_downloads
+            .bytes_per_requestor
+            .entry(requestor)
+            .or_default()
+            .entry(kind)
+            .or_default();
+        add_response(
+            downloaded,
+            log.response_code,
+            log.bytes_sent,
+            range_start,
+            size,
+            config,
+        );
+        lines.counted += 1;
+    }
+    for slug in unmatched_slugs {
+        eprintln!("Skipping downloads of {slug}, which isn't a configured episode slug");
+    }
+    if future_requests > 0 {
+        let handling = match config.future_timestamps {
+            FutureTimestamps::Accept => "counted on the day they were logged",
+            FutureTimestamps::Clamp => "counted as of now",
+            FutureTimestamps::Reject => "skipped",
+        };
+        eprintln!(
+            "Warning: {future_requests} requests were logged more than {} minutes in the future \
+             and were {handling}",
+            config.future_tolerance_minutes
+        );
+    }
+    lines.malformed = logs.malformed_lines();
+    Ok(lines)
+}
+
+/// Adds the `bytes` sent in a response to what a requestor has `downloaded`
+/// of a `size` byte file, starting at `range_start` if the request had a
+/// range.
+///
+/// Responses are counted by the bytes they sent regardless of their status, so
+/// a 206 for an open-ended range like `bytes=0-` that sends the entire file is
+/// a full download on its own. Capping the total at the file size keeps
+/// repeated full responses from counting as more than one download's worth of
+/// bytes. With `restart_on_full_response`, a 200 starts the count over from
+/// its own bytes.
+fn add_response(
+    downloaded: &mut Downloaded,
+    response_code: u16,
+    bytes: u32,
+    range_start: Option<u32>,
+    size: u32,
+    config: &Config,
+) {
+    if config.restart_on_full_response && response_code == 200 {
+        *downloaded = Downloaded {
+            bytes: bytes.min(size),
+            ranges: Vec::new(),
+        };
+    } else if let Some(start) = range_start {
+        downloaded.add_range(start..start.saturating_add(bytes));
+    } else {
+        downloaded.bytes = downloaded.bytes.saturating_add(bytes).min(size);
+    }
+}
+
+/// Returns where in a `size` byte file a `Range` header such as `bytes=0-`,
+/// `bytes=500-999`, or `bytes=-500` starts, or None if it isn't a single
+/// range of bytes.
+fn range_start(range: &str, size: u32) -> Option<u32> {
+    let (start, end) = range.strip_prefix("bytes=")?.trim().split_once('-')?;
+    if end.contains(',') {
+        return None;
+    }
+    if start.is_empty() {
+        // A suffix range requests the last bytes of the file.
+        return Some(size.saturating_sub(end.parse().ok()?));
+    }
+    if !end.is_empty() {
+        end.parse::<u64>().ok()?;
+    }
+    start.parse().ok()
+}
+
+/// Returns true if `bytes` of a `size` byte file is enough of it to count as a
+/// full download.
+fn is_full_download(bytes: u32, size: u32, config: &Config) -> bool {
+    f64::from(bytes) >= f64::from(size) * config.full_download_percent / 100.0
+}
+
+#[cfg(test)]
+use std::collections::BTreeMap;
+#[cfg(test)]
+use std::fs;
+
+#[cfg(test)]
+use crate::hll;
+#[cfg(test)]
+use crate::testing::{memory_database, tally_logs, tally_sample, test_episodes_dir, SAMPLE_LOG};
+
+#[test]
+fn weekday_buckets() {
+    use time::macros::datetime;
+
+    // 2023-05-08 was a Monday.
+    let monday = datetime!(2023-05-08 15:08:30 UTC);
+    assert_eq!(weekday_index(monday, UtcOffset::UTC), 0);
+    let early_monday = datetime!(2023-05-08 02:00:00 UTC);
+    let pacific = UtcOffset::from_hms(-7, 0, 0).unwrap();
+    assert_eq!(weekday_index(early_monday, pacific), 6);
+    let late_sunday = datetime!(2023-05-07 23:00:00 UTC);
+    let tokyo = UtcOffset::from_hms(9, 0, 0).unwrap();
+    assert_eq!(weekday_index(late_sunday, tokyo), 0);
+
+    let downloads = tally_sample(SAMPLE_LOG, 213_001, &Config::default());
+    assert_eq!(downloads.full_downloads, 1);
+    assert_eq!(downloads.full_downloads_by_weekday, [1, 0, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn episode_aliases() {
+    let dir = test_episodes_dir("episode-aliases", 213_001);
+
+    let unaliased = tally_logs(SAMPLE_LOG, &dir, &Config::default());
+    let (key, downloads) = unaliased.into_iter().next().unwrap();
+    assert_eq!(key.episode, 1);
+    assert_eq!(downloads.full_downloads, 1);
+
+    let mut config = Config::default();
+    config.episode_aliases.insert(1, 2);
+    let aliased = tally_logs(SAMPLE_LOG, &dir, &config);
+    let (key, downloads) = aliased.into_iter().next().unwrap();
+    assert_eq!(key.episode, 2);
+    assert_eq!(downloads.full_downloads, 1);
+}
+
+#[test]
+fn listening_minutes() {
+    assert_eq!(estimated_listening_seconds(1_000, 1_000, 600), 600);
+    assert_eq!(estimated_listening_seconds(500, 1_000, 600), 300);
+    assert_eq!(estimated_listening_seconds(2_500, 1_000, 600), 600);
+
+    let mut config = Config::default();
+    config.episode_durations.insert(1, 1_800);
+    let downloads = tally_sample(SAMPLE_LOG, 426_002, &config);
+    assert_eq!(downloads.partial_downloads, 1);
+    assert_eq!(downloads.listening_seconds, 900);
+}
+
+#[test]
+fn reconciling_downloads_across_days() {
+    let dir = test_episodes_dir("reconciling-downloads", 213_001);
+    let (first_day, second_day) = SAMPLE_LOG.split_once('\n').unwrap();
+    let logs = format!(
+        "{first_day}\n{}",
+        second_day.replace("08/May/2023", "09/May/2023")
+    );
+
+    let per_day = tally_logs(&logs, &dir, &Config::default());
+    assert_eq!(per_day.len(), 2);
+    assert!(per_day
+        .values()
+        .all(|downloads| downloads.full_downloads == 0 && downloads.partial_downloads == 1));
+
+    let mut config = Config::default();
+    config.reconcile_partial_downloads = true;
+    let reconciled = tally_logs(&logs, &dir, &config);
+    let mut reconciled = reconciled.into_iter().collect::<Vec<_>>();
+    reconciled.sort_by_key(|(key, _)| key.date);
+    assert_eq!(reconciled[0].1.full_downloads, 0);
+    assert_eq!(reconciled[0].1.partial_downloads, 0);
+    assert_eq!(reconciled[1].1.full_downloads, 1);
+    assert_eq!(reconciled[1].1.partial_downloads, 0);
+}
+
+#[test]
+fn player_classification() {
+    let downloads = tally_sample(SAMPLE_LOG, 213_001, &Config::default());
+    assert_eq!(downloads.full_downloads_by_player, [1, 0, 0]);
+}
+
+#[test]
+fn visitor_identity() {
+    let (first, second) = SAMPLE_LOG.split_once('\n').unwrap();
+    let logs = format!(
+        "{first}\n{}",
+        second.replace("Mobile/15E148", "Mobile/20A362")
+    );
+
+    let downloads = tally_sample(&logs, 213_001, &Config::default());
+    assert_eq!(downloads.full_downloads, 1);
+    assert_eq!(downloads.partial_downloads, 0);
+    assert_eq!(downloads.visitors.estimate(), 1);
+
+    let mut config = Config::default();
+    config.visitor_identity.include_user_agent = true;
+    let downloads = tally_sample(&logs, 213_001, &config);
+    assert_eq!(downloads.full_downloads, 0);
+    assert_eq!(downloads.partial_downloads, 2);
+    assert_eq!(downloads.visitors.estimate(), 2);
+}
+
+#[test]
+fn per_file_visitor_retention() {
+    let dir = test_episodes_dir("per-file-visitor-retention", 213_001);
+    let mut sizes = EpisodeSizes::from_directory(&dir);
+    let mut aggregation = Aggregation::default();
+    let mut tallied = HashMap::new();
+    for log in SAMPLE_LOG.lines() {
+        aggregate_logs(
+            format!("{log}\n").as_bytes(),
+            &mut aggregation,
+            &mut sizes,
+            OffsetDateTime::UNIX_EPOCH,
+            &Config::default(),
+        )
+        .unwrap();
+        assert!(!aggregation.files.is_empty());
+        flush_visitor_data(&mut aggregation, &mut tallied, &Config::default());
+        assert!(aggregation.files.is_empty());
+    }
+
+    // Each file only had part of the download, but the visitor is still only
+    // counted once.
+    let (_, downloads) = tallied.into_iter().next().unwrap();
+    assert_eq!(downloads.full_downloads, 0);
+    assert_eq!(downloads.partial_downloads, 2);
+    assert_eq!(downloads.visitors.estimate(), 1);
+}
+
+#[test]
+fn episode_extensions() {
+    let dir = test_episodes_dir("episode-extensions", 1_000);
+    fs::write(dir.join("episode-001.mp3"), vec![0; 800]).unwrap();
+    fs::write(dir.join("episode-001.ogg"), vec![0; 600]).unwrap();
+    let request = |address: &str, extension: &str, bytes: u32| {
+        format!(
+            "{address} - - [08/May/2023:15:00:00 +0000] \"GET /episode-001.{extension} HTTP/1.1\" \
+             200 {bytes} \"-\" \"AppleCoreMedia/1.0.0\"\n"
+        )
+    };
+    let logs = [
+        request("10.0.1.1", "m4a", 1_000),
+        // Part of one format and all of another is one full download.
+        request("10.0.2.1", "mp3", 700),
+        request("10.0.2.1", "ogg", 600),
+        request("10.0.3.1", "mp3", 799),
+        request("10.0.4.1", "wav", 5_000),
+    ]
+    .concat();
+
+    let (_, downloads) = tally_logs(&logs, &dir, &Config::default())
+        .into_iter()
+        .next()
+        .unwrap();
+    assert_eq!(downloads.full_downloads, 2);
+    assert_eq!(downloads.partial_downloads, 1);
+    assert_eq!(
+        downloads.full_downloads_by_extension,
+        BTreeMap::from([(String::from("m4a"), 1), (String::from("ogg"), 1)])
+    );
+    assert_eq!(downloads.visitors.estimate(), 3);
+}
+
+#[test]
+fn apple_probes() {
+    let request = |address: &str, time: &str, bytes: u32, user_agent: &str| {
+        format!(
+            "{address} - - [08/May/2023:{time} +0000] \"GET /episode-001.m4a HTTP/1.1\" 206 \
+             {bytes} \"-\" \"{user_agent}\"\n"
+        )
+    };
+    let logs = [
+        // Probed, then played after the dedup window.
+        request("10.0.1.1", "08:00:00", 2, "AppleCoreMedia/1.0.0"),
+        request("10.0.1.1", "12:00:00", 1_000, "AppleCoreMedia/1.0.0"),
+        // Probed without being played.
+        request("10.0.2.1", "08:00:00", 2, "AppleCoreMedia/1.0.0"),
+        // Another app's small request is a partial download.
+        request("10.0.3.1", "08:00:00", 2, "Overcast/3.0"),
+        request("10.0.3.1", "12:00:00", 1_000, "Overcast/3.0"),
+    ]
+    .concat();
+    let tally = |config: &Config| {
+        let downloads = tally_sample(&logs, 1_000, config);
+        (downloads.full_downloads, downloads.partial_downloads)
+    };
+
+    let config = Config {
+        dedup_window_minutes: 60,
+        ..Config::default()
+    };
+    assert_eq!(tally(&config), (2, 3));
+    let config = Config {
+        apple_probe_bytes: 65_536,
+        ..config
+    };
+    assert_eq!(tally(&config), (2, 2));
+}
+
+#[test]
+fn roaming_requestors() {
+    let logs = ["172.56.208.121", "172.56.208.9"]
+        .map(|address| {
+            format!(
+                "{address} - - [08/May/2023:15:00:00 +0000] \"GET /episode-001.m4a HTTP/1.1\" \
+                 206 500 \"-\" \"AppleCoreMedia/1.0.0\"\n"
+            )
+        })
+        .concat();
+    let tally = |config: &Config| {
+        let downloads = tally_sample(&logs, 1_000, config);
+        (downloads.full_downloads, downloads.partial_downloads)
+    };
+
+    // By default both addresses are in the listener's /24.
+    assert_eq!(tally(&Config::default()), (1, 0));
+    let config = Config {
+        visitor_identity: crate::config::VisitorIdentity {
+            ipv4_prefix: 32,
+            ipv6_prefix: 128,
+            include_user_agent: false,
+        },
+        ..Config::default()
+    };
+    assert_eq!(tally(&config), (0, 2));
+}
+
+#[test]
+fn bot_requests() {
+    let googlebot = SAMPLE_LOG.replace("172.56.208.121", "66.249.66.1").replace(
+        "Mozilla/5.0 (iPhone; CPU iPhone OS 16_4_1 like Mac OS X) AppleWebKit/605.1.15 \
+             (KHTML, like Gecko) Version/16.4 Mobile/15E148 Safari/604.1",
+        "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+    );
+    let logs = format!("{googlebot}{SAMPLE_LOG}");
+    let tally = |config: &Config| tally_sample(&logs, 213_001, config);
+
+    let downloads = tally(&Config::default());
+    assert_eq!(downloads.full_downloads, 1);
+    assert_eq!(downloads.partial_downloads, 0);
+    assert_eq!(downloads.visitors.estimate(), 1);
+    assert_eq!(downloads.bot_requests, 0);
+
+    let config = Config {
+        bots: crate::config::BotRules {
+            count_requests: true,
+            ..crate::config::BotRules::default()
+        },
+        ..Config::default()
+    };
+    let downloads = tally(&config);
+    assert_eq!(downloads.full_downloads, 1);
+    assert_eq!(downloads.partial_downloads, 0);
+    assert_eq!(downloads.bot_requests, 2);
+
+    // Replacing the defaults counts Googlebot, while an extra pattern leaves
+    // out the iPhone.
+    let config = Config {
+        bots: crate::config::BotRules {
+            user_agents: Vec::new(),
+            extra_user_agents: vec![String::from("iPhone OS")],
+            count_requests: false,
+        },
+        ..Config::default()
+    };
+    let downloads = tally(&config);
+    assert_eq!(downloads.full_downloads, 1);
+    assert_eq!(downloads.bot_requests, 0);
+}
+
+#[test]
+fn probes() {
+    let dir = test_episodes_dir("probes", 213_001);
+    let logs = [
+        "10.0.1.1 - - [08/May/2023:15:00:00 +0000] \"HEAD /episode-002.m4a HTTP/1.1\" 200 0 \"-\" \
+         \"AppleCoreMedia/1.0.0\"\n",
+        "10.0.2.1 - - [08/May/2023:15:00:00 +0000] \"GET /episode-002.m4a HTTP/1.1\" 304 0 \"-\" \
+         \"Overcast/3.0\"\n",
+        "10.0.3.1 - - [08/May/2023:15:00:00 +0000] \"HEAD /episode-002.m4a HTTP/1.1\" 404 0 \"-\" \
+         \"Overcast/3.0\"\n",
+    ]
+    .concat();
+    let mut aggregation = Aggregation::default();
+    let lines = aggregate_logs(
+        logs.as_bytes(),
+        &mut aggregation,
+        &mut EpisodeSizes::from_directory(&dir),
+        OffsetDateTime::UNIX_EPOCH,
+        &Config::default(),
+    )
+    .unwrap();
+    assert_eq!(lines.counted, 0);
+    let (key, downloads) = tally_downloads(aggregation, &Config::default())
+        .into_iter()
+        .next()
+        .unwrap();
+    assert_eq!(key.episode, 2);
+    assert_eq!(downloads.probes, 2);
+    assert_eq!(downloads.full_downloads, 0);
+    assert_eq!(downloads.partial_downloads, 0);
+}
+
+#[test]
+fn episode_variants() {
+    let dir = test_episodes_dir("episode-variants", 1_000);
+    fs::write(dir.join("episode-001-chaptered.m4a"), vec![0; 1_200]).unwrap();
+    fs::write(dir.join("episode-001-final.m4a"), vec![0; 1_000]).unwrap();
+    let request = |address: &str, file: &str, bytes: u32| {
+        format!(
+            "{address} - - [08/May/2023:15:00:00 +0000] \"GET /{file} HTTP/1.1\" 200 {bytes} \
+             \"-\" \"AppleCoreMedia/1.0.0\"\n"
+        )
+    };
+    let logs = [
+        request("10.0.1.1", "episode-001.m4a", 1_000),
+        // The variant is larger, so the plain file's size isn't a full
+        // download of it.
+        request("10.0.2.1", "episode-001-chaptered.m4a", 1_000),
+        request("10.0.3.1", "episode-001-chaptered.m4a", 1_200),
+        // Unconfigured suffixes are the plain file.
+        request("10.0.4.1", "episode-001-final.m4a", 1_000),
+    ]
+    .concat();
+    let config = Config {
+        episode_variants: vec![String::from("chaptered")],
+        ..Config::default()
+    };
+
+    let (key, downloads) = tally_logs(&logs, &dir, &config).into_iter().next().unwrap();
+    assert_eq!(key.episode, 1);
+    assert_eq!(downloads.full_downloads, 3);
+    assert_eq!(downloads.partial_downloads, 1);
+    assert_eq!(
+        downloads.full_downloads_by_extension,
+        BTreeMap::from([(String::from("m4a"), 3)])
+    );
+    assert_eq!(
+        downloads.full_downloads_by_variant,
+        BTreeMap::from([(String::from("chaptered"), 1), (String::from("plain"), 2)])
+    );
+}
+
+#[test]
+fn dedup_window() {
+    let request = |time: &str, bytes: u32| {
+        format!(
+            "10.0.1.1 - - [08/May/2023:{time} +0000] \"GET /episode-001.m4a HTTP/1.1\" 206 {bytes} \
+             \"-\" \"AppleCoreMedia/1.0.0\"\n"
+        )
+    };
+    let config = Config {
+        dedup_window_minutes: 60,
+        ..Config::default()
+    };
+    let classify = |logs: &[String]| {
+        let downloads = tally_sample(&logs.concat(), 1_000, &config);
+        assert_eq!(downloads.visitors.estimate(), 1);
+        (downloads.full_downloads, downloads.partial_downloads)
+    };
+
+    // Within the window, the halves of the file add up to one download.
+    assert_eq!(
+        classify(&[request("15:00:00", 500), request("15:59:00", 500)]),
+        (1, 0)
+    );
+    // Past the window, each half is a separate attempt.
+    assert_eq!(
+        classify(&[request("15:00:00", 500), request("16:01:00", 500)]),
+        (0, 2)
+    );
+    // Listening twice is two downloads.
+    assert_eq!(
+        classify(&[
+            request("09:00:00", 1_000),
+            request("12:00:00", 400),
+            request("12:30:00", 600),
+        ]),
+        (2, 0)
+    );
+}
+
+#[test]
+fn custom_visitor_key() {
+    // The end of the download from another address on the same device, and
+    // another device requesting the end from that address too.
+    let (first, second) = SAMPLE_LOG.split_once('\n').unwrap();
+    let second = second.replace("172.56.208.121", "10.0.1.1");
+    let logs = format!(
+        "{first}\n{second}{}",
+        second.replace("Mobile/15E148", "Mobile/20A362")
+    );
+
+    let downloads = tally_sample(&logs, 213_001, &Config::default());
+    assert_eq!(downloads.full_downloads, 0);
+    assert_eq!(downloads.partial_downloads, 2);
+
+    let dir = test_episodes_dir("custom-visitor-key", 213_001);
+    let mut aggregation = Aggregation::default();
+    aggregate_logs(
+        logs.as_bytes(),
+        &mut aggregation,
+        &mut EpisodeSizes::from_directory(&dir),
+        OffsetDateTime::UNIX_EPOCH,
+        &Config::default(),
+    )
+    .unwrap();
+    let user_agent_only = |_: IpAddr, user_agent: &str| hll::hash(user_agent.as_bytes());
+    let by_user_agent = tally_downloads_with(aggregation, &Config::default(), &user_agent_only);
+    let (_, downloads) = by_user_agent.into_iter().next().unwrap();
+    assert_eq!(downloads.full_downloads, 1);
+    assert_eq!(downloads.partial_downloads, 1);
+    assert_eq!(downloads.visitors.estimate(), 2);
+}
+
+#[test]
+fn kept_requestor_totals() {
+    let dir = test_episodes_dir("kept-requestor-totals", 213_001);
+    let logs = [
+        SAMPLE_LOG.to_string(),
+        SAMPLE_LOG
+            .lines()
+            .next()
+            .unwrap()
+            .replace("172.56.208.121", "10.0.1.1")
+            + "\n",
+    ]
+    .concat();
+    let mut aggregation = Aggregation::default();
+    aggregate_logs(
+        logs.as_bytes(),
+        &mut aggregation,
+        &mut EpisodeSizes::from_directory(&dir),
+        OffsetDateTime::UNIX_EPOCH,
+        &Config::default(),
+    )
+    .unwrap();
+    let config = Config::default();
+    let totals = requestor_totals(&aggregation, &config);
+    assert_eq!(totals.len(), 1);
+    let (key, day) = totals.into_iter().next().unwrap();
+    assert_eq!(key.episode, 1);
+    assert_eq!(day.sizes, BTreeMap::from([(String::from("m4a"), 213_001)]));
+    let mut bytes = day
+        .requestors
+        .iter()
+        .map(|requestor| requestor.bytes["m4a"])
+        .collect::<Vec<_>>();
+    bytes.sort_unstable();
+    assert_eq!(bytes, [106_500, 213_001]);
+
+    // The totals classify the same way as the aggregation they came from.
+    let full = bytes
+        .iter()
+        .filter(|&&bytes| is_full_download(bytes, day.sizes["m4a"], &config))
+        .count();
+    let downloads = tally_downloads(aggregation, &config).remove(&key).unwrap();
+    assert_eq!(full, usize::from(downloads.full_downloads));
+    assert_eq!(bytes.len() - full, usize::from(downloads.partial_downloads));
+}
+
+#[test]
+fn referrer_downloads() {
+    let logs = [
+        SAMPLE_LOG.to_string(),
+        SAMPLE_LOG
+            .replace("172.56.208.121", "10.0.1.1")
+            .replace("\"https://wayofthecrab.com/\"", "\"-\""),
+    ]
+    .concat();
+    let downloads = tally_sample(&logs, 213_001, &Config::default());
+    assert_eq!(downloads.full_downloads, 2);
+    assert_eq!(
+        downloads.full_downloads_by_referrer,
+        BTreeMap::from([
+            (String::from("direct"), 1),
+            (String::from("wayofthecrab.com"), 1)
+        ])
+    );
+}
+
+#[test]
+fn country_downloads() {
+    use crate::geoip::{CountryCode, CountryLookup};
+
+    #[derive(Debug)]
+    struct Countries(HashMap<IpAddr, CountryCode>);
+
+    impl CountryLookup for Countries {
+        fn country_for(&self, ip: IpAddr) -> Option<CountryCode> {
+            self.0.get(&ip).copied()
+        }
+    }
+
+    let logs = ["10.0.1.1", "10.0.2.1", "10.0.3.1"]
+        .map(|address| SAMPLE_LOG.replace("172.56.208.121", address))
+        .concat();
+    let tally = |config: &Config| tally_sample(&logs, 213_001, config);
+
+    let downloads = tally(&Config::default());
+    assert_eq!(downloads.full_downloads, 3);
+    assert!(downloads.full_downloads_by_country.is_empty());
+
+    let config = Config {
+        countries: Some(Box::new(Countries(HashMap::from([
+            (IpAddr::from([10, 0, 1, 1]), CountryCode::new("NZ").unwrap()),
+            (IpAddr::from([10, 0, 2, 1]), CountryCode::new("NZ").unwrap()),
+            (IpAddr::from([10, 0, 3, 1]), CountryCode::new("AU").unwrap()),
+        ])))),
+        ..Config::default()
+    };
+    let downloads = tally(&config);
+    assert_eq!(downloads.full_downloads, 3);
+    assert_eq!(
+        downloads.full_downloads_by_country,
+        BTreeMap::from([(String::from("AU"), 1), (String::from("NZ"), 2)])
+    );
+}
+
+#[test]
+fn bonus_episodes() {
+    let dir = test_episodes_dir("bonus-episodes", 213_001);
+    fs::write(dir.join("episode-012.m4a"), vec![0; 100]).unwrap();
+    fs::write(dir.join("episode-012b.m4a"), vec![0; 100]).unwrap();
+    let logs = SAMPLE_LOG
+        .replacen("/episode-001.m4a", "/episode-012.m4a", 1)
+        .replacen("/episode-001.m4a", "/episode-012b.m4a", 1);
+
+    let unconfigured = tally_logs(&logs, &dir, &Config::default());
+    assert_eq!(unconfigured.len(), 1);
+    assert!(unconfigured.keys().all(|key| key.episode == 12));
+
+    let mut config = Config::default();
+    config.bonus_episodes.insert(String::from("012b"), 1012);
+    let tallied = tally_logs(&logs, &dir, &config);
+    let mut episodes = tallied
+        .iter()
+        .map(|(key, downloads)| (key.episode, downloads.full_downloads))
+        .collect::<Vec<_>>();
+    episodes.sort_unstable();
+    assert_eq!(episodes, [(12, 1), (1012, 1)]);
+}
+
+#[test]
+fn range_requests() {
+    assert_eq!(range_start("bytes=0-", 1_000), Some(0));
+    assert_eq!(range_start("bytes=0-999", 1_000), Some(0));
+    assert_eq!(range_start("bytes=500-", 1_000), Some(500));
+    assert_eq!(range_start("bytes=-300", 1_000), Some(700));
+    assert_eq!(range_start("bytes=0-1,500-", 1_000), None);
+    assert_eq!(range_start("bytes=a-", 1_000), None);
+    assert_eq!(range_start("items=0-", 1_000), None);
+
+    let tally = |ranges: [&str; 2]| {
+        let logs = SAMPLE_LOG
+            .lines()
+            .zip(ranges)
+            .map(|(line, range)| format!("{line} \"{range}\"\n"))
+            .collect::<String>();
+        let mut config = Config::default();
+        config.log_range_field = true;
+        let downloads = tally_sample(&logs, 213_001, &config);
+        (downloads.full_downloads, downloads.partial_downloads)
+    };
+
+    // Two plays that each stopped halfway through aren't one full download.
+    assert_eq!(tally(["bytes=0-", "bytes=0-"]), (0, 1));
+    // Consecutive ranges and requests without one still add up.
+    assert_eq!(tally(["bytes=0-106499", "bytes=106500-"]), (1, 0));
+    assert_eq!(tally(["-", "-"]), (1, 0));
+    // Overlapping ranges sent more than the file's size in total, but only
+    // the bytes up to 156,501 were ever downloaded.
+    assert_eq!(tally(["bytes=0-", "bytes=50000-"]), (0, 1));
+    // Ranges cover the file regardless of the order they're requested in.
+    assert_eq!(tally(["bytes=106500-", "bytes=0-"]), (1, 0));
+    // Ranges that can't be placed in the file are summed.
+    assert_eq!(tally(["bytes=0-1,500-", "bytes=0-"]), (1, 0));
+}
+
+#[test]
+fn overlapping_ranges() {
+    let mut downloaded = Downloaded::default();
+    downloaded.add_range(100..200);
+    downloaded.add_range(300..400);
+    downloaded.add_range(150..250);
+    assert_eq!(downloaded.ranges, [100..250, 300..400]);
+    assert_eq!(downloaded.bytes(1_000), 250);
+    downloaded.add_range(250..300);
+    assert_eq!(downloaded.ranges, [100..400]);
+    downloaded.add_range(0..50);
+    downloaded.add_range(40..60);
+    downloaded.add_range(900..1_100);
+    assert_eq!(downloaded.ranges, [0..60, 100..400, 900..1_100]);
+    assert_eq!(downloaded.bytes(1_000), 60 + 300 + 100);
+
+    // Bytes without a range are added to the covered bytes, up to the size.
+    downloaded.bytes = 600;
+    assert_eq!(downloaded.bytes(1_000), 1_000);
+}
+
+#[test]
+fn episode_path_patterns() {
+    let dir = test_episodes_dir("episode-path-patterns", 213_001);
+    for file in ["podcasts/crab/ep12.m4a", "s2/episode-012.m4a"] {
+        fs::create_dir_all(dir.join(file).parent().unwrap()).unwrap();
+        fs::write(dir.join(file), vec![0; 213_001]).unwrap();
+    }
+    let logs = [
+        SAMPLE_LOG.replace("/episode-001.m4a", "/podcasts/crab/ep12.m4a"),
+        SAMPLE_LOG
+            .replace("/episode-001.m4a", "/s2/episode-012.m4a")
+            .replace("172.56.208.121", "172.56.209.122"),
+    ]
+    .concat();
+
+    assert!(tally_logs(&logs, &dir, &Config::default()).is_empty());
+
+    let config: Config = toml::from_str(
+        r#"episode_paths = ["/podcasts/crab/ep{episode}.{ext}", "/s2/episode-{episode}.{ext}"]"#,
+    )
+    .unwrap();
+    let tallied = tally_logs(&logs, &dir, &config);
+    assert_eq!(tallied.len(), 1);
+    let (key, downloads) = tallied.iter().next().unwrap();
+    assert_eq!(key.episode, 12);
+    assert_eq!(downloads.full_downloads, 2);
+
+    assert!(toml::from_str::<Config>(r#"episode_paths = ["/ep{episode}"]"#).is_err());
+}
+
+#[test]
+fn episode_slugs() {
+    let dir = test_episodes_dir("episode-slugs", 213_001);
+    fs::write(dir.join("the-great-crab-migration.m4a"), vec![0; 213_001]).unwrap();
+    let logs = [
+        SAMPLE_LOG.replace("/episode-001.m4a", "/the-great-crab-migration.m4a"),
+        SAMPLE_LOG.replace("/episode-001.m4a", "/unlisted-slug.m4a"),
+        SAMPLE_LOG.replace("/episode-001.m4a", "/favicon.ico"),
+    ]
+    .concat();
+    let aggregate = |config: &Config| {
+        let mut aggregation = Aggregation::default();
+        aggregate_logs(
+            logs.as_bytes(),
+            &mut aggregation,
+            &mut EpisodeSizes::from_directory(&dir),
+            OffsetDateTime::UNIX_EPOCH,
+            config,
+        )
+        .unwrap();
+        aggregation
+    };
+
+    // Without a mapping, slug requests aren't counted towards any episode.
+    assert!(aggregate(&Config::default()).files.is_empty());
+
+    let mut config = Config::default();
+    config
+        .episode_slugs
+        .insert(String::from("the-great-crab-migration"), 7);
+    let aggregation = aggregate(&config);
+    assert_eq!(aggregation.files.len(), 1);
+    let tallied = tally_downloads(aggregation, &config);
+    let (key, downloads) = tallied.iter().next().unwrap();
+    assert_eq!(key.episode, 7);
+    assert_eq!(downloads.full_downloads, 1);
+}
+
+#[test]
+fn open_ended_range_is_full() {
+    const FULL_RANGE: &str = r#"172.56.208.121 - - [08/May/2023:15:08:30 +0000] "GET /episode-001.m4a HTTP/1.1" 206 213001 "-" "AppleCoreMedia/1.0.0"
+"#;
+
+    let config = Config {
+        episode_durations: HashMap::from([(1, 600)]),
+        ..Config::default()
+    };
+    for repetitions in [1, 3] {
+        let downloads = tally_sample(&FULL_RANGE.repeat(repetitions), 213_001, &config);
+        assert_eq!(downloads.full_downloads, 1);
+        assert_eq!(downloads.partial_downloads, 0);
+        assert_eq!(downloads.listening_seconds, 600);
+    }
+}
+
+#[test]
+fn nearly_full_downloads() {
+    let request = |address: &str, bytes: u32| {
+        format!(
+            "{address} - - [08/May/2023:15:00:00 +0000] \"GET /episode-001.m4a HTTP/1.1\" 206 \
+             {bytes} \"-\" \"AppleCoreMedia/1.0.0\"\n"
+        )
+    };
+    let logs = [request("10.0.1.1", 995), request("10.0.2.1", 980)].concat();
+    let tally = |config: &Config| {
+        let downloads = tally_sample(&logs, 1_000, config);
+        (downloads.full_downloads, downloads.partial_downloads)
+    };
+
+    assert_eq!(tally(&Config::default()), (1, 1));
+    let exact = Config {
+        full_download_percent: 100.0,
+        ..Config::default()
+    };
+    assert_eq!(tally(&exact), (0, 2));
+}
+
+#[test]
+fn future_timestamps() {
+    const FUTURE_RANGE: &str = r#"172.56.208.121 - - [08/May/2999:15:08:30 +0000] "GET /episode-001.m4a HTTP/1.1" 206 213001 "-" "AppleCoreMedia/1.0.0"
+"#;
+
+    let dir = test_episodes_dir("future-timestamps", 213_001);
+    for (future_timestamps, expected) in [
+        (
+            FutureTimestamps::Accept,
+            Some(
+                TimestampAsDays::try_from(SystemTime::from(
+                    time::macros::datetime!(2999-05-08 0:00 UTC),
+                ))
+                .unwrap(),
+            ),
+        ),
+        (FutureTimestamps::Clamp, Some(TimestampAsDays::now())),
+        (FutureTimestamps::Reject, None),
+    ] {
+        let config = Config {
+            future_timestamps,
+            ..Config::default()
+        };
+        let mut aggregation = Aggregation::default();
+        let lines = aggregate_logs(
+            FUTURE_RANGE.as_bytes(),
+            &mut aggregation,
+            &mut EpisodeSizes::from_directory(&dir),
+            OffsetDateTime::UNIX_EPOCH,
+            &config,
+        )
+        .unwrap();
+        assert_eq!(lines.counted, u64::from(expected.is_some()));
+        assert_eq!(
+            aggregation.files.keys().map(|key| key.date).next(),
+            expected,
+            "{future_timestamps:?}"
+        );
+    }
+}
+
+#[test]
+fn utc_day_buckets() {
+    use time::macros::date;
+
+    let dir = test_episodes_dir("utc-day-buckets", 213_001);
+    let day = |date: time::Date| {
+        TimestampAsDays::try_from(SystemTime::from(date.midnight().assume_utc())).unwrap()
+    };
+    for (timestamp, expected) in [
+        ("08/May/2023:23:30:00 -0700", date!(2023 - 05 - 09)),
+        ("08/May/2023:16:59:59 -0700", date!(2023 - 05 - 08)),
+        ("08/May/2023:17:00:00 -0700", date!(2023 - 05 - 09)),
+        ("09/May/2023:00:30:00 +0530", date!(2023 - 05 - 08)),
+        ("31/Dec/2023:23:30:00 -0030", date!(2024 - 01 - 01)),
+        // The last second of a year and the first second of the next.
+        ("31/Dec/2023:23:59:59 +0000", date!(2023 - 12 - 31)),
+        ("01/Jan/2024:00:00:00 +0000", date!(2024 - 01 - 01)),
+        ("31/Dec/2023:23:59:59 +0100", date!(2023 - 12 - 31)),
+        ("01/Jan/2024:00:59:59 +0100", date!(2023 - 12 - 31)),
+        ("01/Jan/2024:01:00:00 +0100", date!(2024 - 01 - 01)),
+        ("31/Dec/2023:23:59:59 -0100", date!(2024 - 01 - 01)),
+    ] {
+        let log = format!(
+            "172.56.208.121 - - [{timestamp}] \"GET /episode-001.m4a HTTP/1.1\" 200 213001 \"-\" \
+             \"AppleCoreMedia/1.0.0\"\n"
+        );
+        let mut aggregation = Aggregation::default();
+        aggregate_logs(
+            log.as_bytes(),
+            &mut aggregation,
+            &mut EpisodeSizes::from_directory(&dir),
+            OffsetDateTime::UNIX_EPOCH,
+            &Config::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            aggregation.days(),
+            BTreeSet::from([day(expected)]),
+            "{timestamp}"
+        );
+    }
+}
+
+#[test]
+fn full_responses_restart_downloads() {
+    let request = |address: &str, status: u16, bytes: u32| {
+        format!(
+            "{address} - - [08/May/2023:15:00:00 +0000] \"GET /episode-001.m4a HTTP/1.1\" {status} \
+             {bytes} \"-\" \"AppleCoreMedia/1.0.0\"\n"
+        )
+    };
+    let logs = [
+        // A range, then an `If-Range` mismatch that restarted the transfer
+        // but was cancelled, then a range that didn't reach the end.
+        request("10.0.1.1", 206, 600),
+        request("10.0.1.1", 200, 500),
+        request("10.0.1.1", 206, 400),
+        // A range followed by a complete transfer.
+        request("10.0.2.1", 206, 300),
+        request("10.0.2.1", 200, 1_000),
+        // A complete transfer followed by a range past its end.
+        request("10.0.3.1", 200, 1_000),
+        request("10.0.3.1", 206, 100),
+    ]
+    .concat();
+    let classify = |restart_on_full_response| {
+        let config = Config {
+            restart_on_full_response,
+            ..Config::default()
+        };
+        let downloads = tally_sample(&logs, 1_000, &config);
+        (downloads.full_downloads, downloads.partial_downloads)
+    };
+
+    assert_eq!(classify(false), (3, 0));
+    assert_eq!(classify(true), (2, 1));
+}
+
+#[test]
+fn grace_period() {
+    let dir = test_episodes_dir("grace-period", 213_001);
+    let request = |address: &str, time: &str| {
+        format!(
+            "{address} - - [08/May/2023:{time} +0000] \"GET /episode-001.m4a HTTP/1.1\" 200 213001 \
+             \"-\" \"AppleCoreMedia/1.0.0\"\n"
+        )
+    };
+    // Publishing checks right after release, then two listeners.
+    let logs = [
+        request("10.0.1.1", "15:00:00"),
+        request("10.0.2.1", "15:29:59"),
+        request("10.0.3.1", "15:30:00"),
+        request("10.0.4.1", "17:00:00"),
+    ]
+    .concat();
+    let config = Config {
+        new_episode_grace_minutes: 30,
+        ..Config::default()
+    };
+    let aggregate = || {
+        let mut aggregation = Aggregation::default();
+        aggregate_logs(
+            logs.as_bytes(),
+            &mut aggregation,
+            &mut EpisodeSizes::from_directory(&dir),
+            OffsetDateTime::UNIX_EPOCH,
+            &config,
+        )
+        .unwrap();
+        aggregation
+    };
+
+    let mut aggregation = aggregate();
+    let mut first_seen = HashMap::new();
+    apply_grace_period(&mut aggregation, &mut first_seen, &config).unwrap();
+    let first_request = time::macros::datetime!(2023-05-08 15:00:00 UTC);
+    assert_eq!(
+        first_seen,
+        HashMap::from([(String::from("001"), first_request.unix_timestamp())])
+    );
+    let (_, downloads) = tally_downloads(aggregation, &config)
+        .into_iter()
+        .next()
+        .unwrap();
+    assert_eq!(downloads.full_downloads, 2);
+    assert_eq!(downloads.partial_downloads, 0);
+
+    // A grace period that ended during a previous import leaves every request
+    // counted.
+    let mut aggregation = aggregate();
+    let mut first_seen = HashMap::from([(String::from("001"), 0)]);
+    apply_grace_period(&mut aggregation, &mut first_seen, &config).unwrap();
+    let (_, downloads) = tally_downloads(aggregation, &config)
+        .into_iter()
+        .next()
+        .unwrap();
+    assert_eq!(downloads.full_downloads, 4);
+}
+
+#[test]
+fn sizes_from_database() {
+    let dir = test_episodes_dir("sizes-from-database", 213_001);
+    let db = memory_database();
+    let mut sizes = EpisodeSizes::load(&db, Some(&dir)).unwrap();
+    aggregate_logs(
+        SAMPLE_LOG.as_bytes(),
+        &mut Aggregation::default(),
+        &mut sizes,
+        OffsetDateTime::UNIX_EPOCH,
+        &Config::default(),
+    )
+    .unwrap();
+    sizes.save(&db).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    let mut aggregation = Aggregation::default();
+    aggregate_logs(
+        SAMPLE_LOG.as_bytes(),
+        &mut aggregation,
+        &mut EpisodeSizes::load(&db, None).unwrap(),
+        OffsetDateTime::UNIX_EPOCH,
+        &Config::default(),
+    )
+    .unwrap();
+    let (_, downloads) = tally_downloads(aggregation, &Config::default())
+        .into_iter()
+        .next()
+        .unwrap();
+    assert_eq!(downloads.full_downloads, 1);
+    assert_eq!(downloads.partial_downloads, 0);
+
+    let unrecorded = SAMPLE_LOG.replace("/episode-001.m4a", "/episode-002.m4a");
+    assert!(aggregate_logs(
+        unrecorded.as_bytes(),
+        &mut Aggregation::default(),
+        &mut EpisodeSizes::load(&db, None).unwrap(),
+        OffsetDateTime::UNIX_EPOCH,
+        &Config::default(),
+    )
+    .is_err());
+}
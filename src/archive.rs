@@ -0,0 +1,157 @@
+use std::io::{BufRead, BufWriter, Write};
+use std::time::{Duration, SystemTime};
+
+use bonsaidb::core::key::time::TimestampAsDays;
+use bonsaidb::core::schema::SerializedCollection;
+use bonsaidb::core::transaction::{Operation, Transaction};
+use bonsaidb::local::Database;
+use crabtrics::export::DAY;
+use crabtrics::schema::{EpisodeDateKey, PodcastDownloads};
+use serde::{Deserialize, Serialize};
+
+/// The first line of every archive.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+struct ArchiveHeader {
+    format: String,
+    version: u32,
+}
+
+const FORMAT: &str = "crabtrics-archive";
+const VERSION: u32 = 1;
+
+/// A single document, keyed without relying on BonsaiDb's key encoding.
+#[derive(Debug, Serialize, Deserialize)]
+struct ArchivedDownloads {
+    episode: u16,
+    /// The number of days since the Unix epoch.
+    day: u64,
+    downloads: PodcastDownloads,
+}
+
+/// Writes every document to `output` as lines of JSON, preceded by a header
+/// identifying the archive's version.
+///
+/// Archives don't depend on BonsaiDb's storage format, so they can restore a
+/// database after upgrading to a version of BonsaiDb that can't read the
+/// existing files.
+pub fn export_archive<W: Write>(db: &Database, output: W) -> anyhow::Result<()> {
+    let mut output = BufWriter::new(output);
+    serde_json::to_writer(
+        &mut output,
+        &ArchiveHeader {
+            format: String::from(FORMAT),
+            version: VERSION,
+        },
+    )?;
+    output.write_all(b"\n")?;
+    for document in PodcastDownloads::all(db).query()? {
+        let since_epoch = SystemTime::try_from(document.header.id.date)?
+            .duration_since(SystemTime::UNIX_EPOCH)?;
+        serde_json::to_writer(
+            &mut output,
+            &ArchivedDownloads {
+                episode: document.header.id.episode,
+                day: since_epoch.as_secs() / DAY.as_secs(),
+                downloads: document.contents,
+            },
+        )?;
+        output.write_all(b"\n")?;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+/// Inserts every document in an archive written by [`export_archive`] into
+/// `db`, which must be empty.
+///
+/// Returns the number of documents imported.
+pub fn import_archive<R: BufRead>(db: &Database, input: R) -> anyhow::Result<usize> {
+    anyhow::ensure!(
+        PodcastDownloads::all(db).count()? == 0,
+        "archives can only be imported into an empty database"
+    );
+
+    let mut lines = input.lines();
+    let Some(header) = lines.next() else {
+        anyhow::bail!("archive is empty")
+    };
+    let header: ArchiveHeader = serde_json::from_str(&header?)?;
+    anyhow::ensure!(header.format == FORMAT, "not a crabtrics archive");
+    anyhow::ensure!(
+        header.version <= VERSION,
+        "archive version {} is newer than this version of crabtrics supports",
+        header.version
+    );
+
+    let mut tx = Transaction::new();
+    let mut imported = 0;
+    for line in lines {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let archived: ArchivedDownloads = serde_json::from_str(&line)?;
+        let date = TimestampAsDays::try_from(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(archived.day * DAY.as_secs()),
+        )?;
+        tx.push(Operation::overwrite_serialized::<PodcastDownloads, _>(
+            &EpisodeDateKey {
+                episode: archived.episode,
+                date,
+            },
+            &archived.downloads,
+        )?);
+        imported += 1;
+    }
+    tx.apply(db)?;
+    Ok(imported)
+}
+
+#[test]
+fn round_trip() {
+    use std::collections::BTreeMap;
+
+    use crabtrics::testing::{insert_downloads, memory_database};
+
+    let db = memory_database();
+    for episode in 1..=3 {
+        for days_ago in 0..2 {
+            insert_downloads(
+                &db,
+                episode,
+                TimestampAsDays::try_from(SystemTime::UNIX_EPOCH + DAY * (19_485 - days_ago))
+                    .unwrap(),
+                PodcastDownloads {
+                    full_downloads: episode * 10,
+                    partial_downloads: episode,
+                    full_downloads_by_weekday: [episode, 0, 0, 0, 0, 0, 0],
+                    full_downloads_by_extension: BTreeMap::from([(String::from("m4a"), episode)]),
+                    listening_seconds: u32::from(episode) * 600,
+                    full_downloads_by_player: [0, episode, 0],
+                    full_downloads_by_protocol: BTreeMap::from([(
+                        String::from("HTTP/2.0"),
+                        episode,
+                    )]),
+                    full_downloads_by_client: BTreeMap::from([(String::from("Overcast"), episode)]),
+                    written_at: 1_686_000_000,
+                    ..PodcastDownloads::default()
+                },
+            );
+        }
+    }
+
+    let mut archive = Vec::new();
+    export_archive(&db, &mut archive).unwrap();
+    let restored = memory_database();
+    assert_eq!(import_archive(&restored, &archive[..]).unwrap(), 6);
+
+    let original = PodcastDownloads::all(&db).query().unwrap();
+    let imported = PodcastDownloads::all(&restored).query().unwrap();
+    assert_eq!(original.len(), imported.len());
+    for (original, imported) in original.iter().zip(&imported) {
+        assert_eq!(original.header.id, imported.header.id);
+        assert_eq!(original.contents, imported.contents);
+    }
+
+    assert!(import_archive(&restored, &archive[..]).is_err());
+}
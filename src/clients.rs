@@ -0,0 +1,105 @@
+/// The podcast app or other client a download was made with.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PodcastClient {
+    ApplePodcasts,
+    Overcast,
+    Spotify,
+    PocketCasts,
+    AntennaPod,
+    GPodder,
+    /// A web browser, such as one playing the episode in an embedded player.
+    Browser,
+    /// A client that isn't recognized.
+    Unknown,
+}
+
+impl PodcastClient {
+    /// The name shown in the report, which is also what downloads are
+    /// grouped by in the database.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::ApplePodcasts => "Apple Podcasts",
+            Self::Overcast => "Overcast",
+            Self::Spotify => "Spotify",
+            Self::PocketCasts => "Pocket Casts",
+            Self::AntennaPod => "AntennaPod",
+            Self::GPodder => "gPodder",
+            Self::Browser => "Browser",
+            Self::Unknown => "Unknown",
+        }
+    }
+}
+
+/// Classifies a request by the client named in its user agent.
+///
+/// Podcast apps are checked before browsers, since apps that play episodes
+/// in a web view often include `Mozilla/` in their user agent too.
+pub fn classify_user_agent(ua: &str) -> PodcastClient {
+    let ua = ua.to_ascii_lowercase();
+    if ua.contains("overcast") {
+        PodcastClient::Overcast
+    } else if ua.contains("pocketcasts") || ua.contains("pocket casts") {
+        PodcastClient::PocketCasts
+    } else if ua.contains("antennapod") {
+        PodcastClient::AntennaPod
+    } else if ua.contains("gpodder") {
+        PodcastClient::GPodder
+    } else if ua.contains("spotify") {
+        PodcastClient::Spotify
+    } else if ua.starts_with("podcasts/")
+        || ua.starts_with("itunes/")
+        || ua.contains("applepodcasts")
+        // Apple Podcasts streams episodes with the system media player.
+        || ua.starts_with("applecoremedia/")
+    {
+        PodcastClient::ApplePodcasts
+    } else if ua.starts_with("mozilla/") {
+        PodcastClient::Browser
+    } else {
+        PodcastClient::Unknown
+    }
+}
+
+#[test]
+fn user_agents() {
+    for (ua, expected) in [
+        (
+            "Podcasts/1.1.0 CFNetwork/1408.0.4 Darwin/22.5.0",
+            PodcastClient::ApplePodcasts,
+        ),
+        (
+            "AppleCoreMedia/1.0.0.20E252 (iPhone; U; CPU OS 16_4_1 like Mac OS X; en_us)",
+            PodcastClient::ApplePodcasts,
+        ),
+        (
+            "iTunes/12.12 (Macintosh; OS X 13.3)",
+            PodcastClient::ApplePodcasts,
+        ),
+        (
+            "Overcast/3.0 (+http://overcast.fm/; iOS podcast app)",
+            PodcastClient::Overcast,
+        ),
+        (
+            "Spotify/8.8.0 iOS/16.4.1 (iPhone14,2)",
+            PodcastClient::Spotify,
+        ),
+        ("Pocket Casts", PodcastClient::PocketCasts),
+        (
+            "Mozilla/5.0 (Linux; Android 13) PocketCasts/7.38",
+            PodcastClient::PocketCasts,
+        ),
+        ("AntennaPod/3.0.1", PodcastClient::AntennaPod),
+        (
+            "gPodder/3.11.1 (+http://gpodder.org/) Linux",
+            PodcastClient::GPodder,
+        ),
+        (
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:109.0) Gecko/20100101 Firefox/113.0",
+            PodcastClient::Browser,
+        ),
+        ("curl/8.1.2", PodcastClient::Unknown),
+        ("", PodcastClient::Unknown),
+    ] {
+        assert_eq!(classify_user_agent(ua), expected, "{ua}");
+    }
+}
@@ -0,0 +1,635 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use time::{Month, UtcOffset};
+
+use crate::access_logs::LogFormat;
+use crate::episodes::EpisodePattern;
+use crate::geoip::CountryLookup;
+use crate::hll;
+
+/// Settings loaded from `crabtrics.toml`.
+///
+/// Every setting is optional, and a missing file is equivalent to an empty
+/// one.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The offset used when bucketing downloads by local time, such as
+    /// `"-07:00"`.
+    #[serde(deserialize_with = "deserialize_utc_offset")]
+    pub report_utc_offset: UtcOffset,
+    /// The offset of access log timestamps that are logged without one, such
+    /// as `"+02:00"`. Logs without offsets can't be read unless this is set.
+    #[serde(deserialize_with = "deserialize_optional_utc_offset")]
+    pub log_utc_offset: Option<UtcOffset>,
+    /// Abbreviated month names to accept in access log timestamps in addition
+    /// to English ones, mapped to the month's number, such as `mai = 5` for a
+    /// server logging in German. Names are matched case-insensitively.
+    #[serde(deserialize_with = "deserialize_month_names")]
+    pub month_names: HashMap<String, Month>,
+    /// The ASCII character between the fields of each access log line, such
+    /// as `"\t"` for tab-delimited logs.
+    pub log_field_separator: char,
+    /// When true, each access log line ends with the quoted `Range` header of
+    /// the request, such as from nginx's `"$http_range"`. Lines without it are
+    /// still read.
+    pub log_range_field: bool,
+    /// The request paths episode files are served at, where `{episode}` is
+    /// the file's identifier and `{ext}` its extension, such as
+    /// `"/podcasts/crab/ep{episode}.{ext}"`. A request is matched against
+    /// each pattern in order.
+    #[serde(deserialize_with = "deserialize_episode_paths")]
+    pub episode_paths: Vec<EpisodePattern>,
+    /// The file extensions episodes are published with. Requests for episode
+    /// files with any other extension are skipped.
+    pub episode_extensions: Vec<String>,
+    /// Suffixes that name an alternate version of an episode, such as
+    /// `chaptered` for `episode-012-chaptered.m4a`. Downloads of a variant
+    /// count towards its episode, and full downloads are also totaled by
+    /// variant. Any other suffix is ignored.
+    pub episode_variants: Vec<String>,
+    /// Episode numbers whose downloads are counted towards another episode,
+    /// such as after merging two episodes and renumbering them.
+    #[serde(deserialize_with = "deserialize_episode_keys")]
+    pub episode_aliases: HashMap<u16, u16>,
+    /// Subtracted from every episode number shown in the HTML report and the
+    /// episode pages, for shows whose numbering didn't start at 1. Stored
+    /// records, exports, and `report.json` keep the original numbers.
+    pub episode_number_offset: i32,
+    /// The episode number to track each bonus episode or trailer as, keyed by
+    /// the identifier in its file name, such as `012b` for `episode-012b.m4a`.
+    /// Files whose identifier isn't a number are only counted if they're
+    /// listed here.
+    pub bonus_episodes: HashMap<String, u16>,
+    /// The episode number of each file named by a slug rather than a number,
+    /// keyed by the slug, such as `the-great-crab-migration` for
+    /// `/the-great-crab-migration.m4a`. Requests for slugs that aren't listed
+    /// are reported and skipped.
+    pub episode_slugs: HashMap<String, u16>,
+    /// The length of each episode in seconds, used to estimate listening
+    /// time.
+    #[serde(deserialize_with = "deserialize_episode_keys")]
+    pub episode_durations: HashMap<u16, u32>,
+    /// The season each episode belongs to, used to total downloads by season.
+    #[serde(deserialize_with = "deserialize_episode_keys")]
+    pub episode_seasons: HashMap<u16, String>,
+    /// Download totals to show each episode's progress towards.
+    pub milestones: Vec<u32>,
+    /// The public URL of each episode, where `{episode}` is replaced by the
+    /// episode number, or `{episode:03}` by the number zero-padded to three
+    /// digits.
+    pub episode_url_template: String,
+    /// When true, a requestor's downloads of an episode across every imported
+    /// day are classified together and counted on the last day they made a
+    /// request, instead of once per day.
+    pub reconcile_partial_downloads: bool,
+    /// The number of minutes after an episode is first requested during which
+    /// its downloads aren't counted, to leave out publishing checks and feed
+    /// validators.
+    pub new_episode_grace_minutes: u32,
+    /// When true, a `200 OK` response replaces the bytes a requestor has
+    /// downloaded so far instead of adding to them, since it is a complete
+    /// transfer on its own, such as when an `If-Range` validator didn't match
+    /// and the whole file was sent instead of the requested range. `206
+    /// Partial Content` responses still accumulate.
+    pub restart_on_full_response: bool,
+    /// The percentage of an episode's bytes a requestor must download for it
+    /// to count as a full download, which leaves room for clients whose
+    /// ranges end a few bytes short of the file's size.
+    pub full_download_percent: f64,
+    /// The longest gap in minutes between a requestor's requests for an
+    /// episode that are still part of one download. A request after a longer
+    /// gap starts a separate download, such as a second listen later the same
+    /// day.
+    pub dedup_window_minutes: u32,
+    /// The most bytes Apple Podcasts can request of an episode to read its
+    /// metadata before fetching it to play. Such a probe isn't counted as a
+    /// partial download when the same visitor downloads all of the episode
+    /// later that day, in a separate session. 0 counts probes like any other
+    /// request.
+    pub apple_probe_bytes: u32,
+    /// What to do with requests logged more than `future_tolerance_minutes`
+    /// after the import started, such as by a server with a skewed clock.
+    pub future_timestamps: FutureTimestamps,
+    /// How many minutes after the import started a request can be logged at
+    /// before it's handled by `future_timestamps`.
+    pub future_tolerance_minutes: u32,
+    /// How long each requestor's address, user agent, and bytes are kept in
+    /// memory while importing.
+    pub visitor_data_retention: VisitorDataRetention,
+    /// The format of the exported `downloads.csv`.
+    pub csv: CsvConfig,
+    /// How downloads are classified by the kind of player they were played
+    /// with.
+    pub players: PlayerRules,
+    /// Which requests are from bots and crawlers rather than listeners.
+    pub bots: BotRules,
+    /// Which requests are counted as coming from the same device.
+    pub visitor_identity: VisitorIdentity,
+    /// The names of the files the report is written to.
+    pub report_files: ReportFiles,
+    /// The Shields.io badge written alongside the report.
+    pub badge: BadgeConfig,
+    /// The price used to estimate what serving the episodes costs.
+    pub bandwidth_cost: BandwidthCostConfig,
+    /// Settings for `crabtrics serve`.
+    pub server: ServerConfig,
+    /// Where to send metrics after each run.
+    #[cfg(feature = "statsd")]
+    pub statsd: StatsdConfig,
+    /// The precision of the sketches counting distinct visitors. This is set
+    /// by `--hll-precision` rather than in the file.
+    #[serde(skip)]
+    pub hll_precision: u8,
+    /// The order of the rows in `downloads.csv`. This is set by `--sort`
+    /// rather than in the file.
+    #[serde(skip)]
+    pub csv_order: RowOrder,
+    /// The format the access logs were written in. This is set by `--format`
+    /// rather than in the file.
+    #[serde(skip)]
+    pub log_format: LogFormat,
+    /// Finds the country of each full download's requestor, or None to not
+    /// count downloads by country. This is set by `--geoip` rather than in
+    /// the file.
+    #[serde(skip)]
+    pub countries: Option<Box<dyn CountryLookup>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            report_utc_offset: UtcOffset::UTC,
+            log_utc_offset: None,
+            month_names: HashMap::new(),
+            log_field_separator: ' ',
+            log_range_field: false,
+            episode_paths: EpisodePattern::defaults(),
+            episode_extensions: ["m4a", "mp3", "ogg", "opus"].map(String::from).to_vec(),
+            episode_variants: Vec::new(),
+            episode_aliases: HashMap::new(),
+            episode_number_offset: 0,
+            bonus_episodes: HashMap::new(),
+            episode_slugs: HashMap::new(),
+            episode_durations: HashMap::new(),
+            episode_seasons: HashMap::new(),
+            milestones: vec![
+                10, 50, 100, 500, 1_000, 5_000, 10_000, 50_000, 100_000, 500_000, 1_000_000,
+            ],
+            episode_url_template: String::from("https://wayofthecrab.com/episode-{episode:03}.m4a"),
+            reconcile_partial_downloads: false,
+            new_episode_grace_minutes: 0,
+            restart_on_full_response: false,
+            full_download_percent: 99.0,
+            apple_probe_bytes: 0,
+            future_timestamps: FutureTimestamps::Accept,
+            future_tolerance_minutes: 5,
+            dedup_window_minutes: 24 * 60,
+            visitor_data_retention: VisitorDataRetention::Run,
+            csv: CsvConfig::default(),
+            players: PlayerRules::default(),
+            bots: BotRules::default(),
+            visitor_identity: VisitorIdentity::default(),
+            report_files: ReportFiles::default(),
+            badge: BadgeConfig::default(),
+            bandwidth_cost: BandwidthCostConfig::default(),
+            server: ServerConfig::default(),
+            #[cfg(feature = "statsd")]
+            statsd: StatsdConfig::default(),
+            hll_precision: hll::DEFAULT_PRECISION,
+            csv_order: RowOrder::Episode,
+            log_format: LogFormat::NginxCombined,
+            countries: None,
+        }
+    }
+}
+
+/// Settings for the `[csv]` table, controlling the format of
+/// `downloads.csv`.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CsvConfig {
+    /// The ASCII character separating each field.
+    pub delimiter: char,
+    /// When fields are surrounded by quotes.
+    pub quote_style: CsvQuoteStyle,
+    /// Whether the first row names each column.
+    pub write_header: bool,
+    /// The names of the date, episode, full download, and partial download
+    /// columns.
+    pub columns: [String; 4],
+    /// The name of an extra column with each day's estimated distinct
+    /// visitors, or None to leave it out.
+    pub unique_column: Option<String>,
+    /// The name of an extra column with the bytes sent for each day's
+    /// downloads, or None to leave it out.
+    pub bytes_column: Option<String>,
+}
+
+impl Default for CsvConfig {
+    fn default() -> Self {
+        Self {
+            delimiter: ',',
+            quote_style: CsvQuoteStyle::Necessary,
+            write_header: true,
+            columns: ["date", "episode", "full", "partial"].map(String::from),
+            unique_column: None,
+            bytes_column: None,
+        }
+    }
+}
+
+/// How long per-requestor data is kept while importing. It is never written to
+/// the database either way.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VisitorDataRetention {
+    /// Keep every requestor's data until all of the logs have been read, so
+    /// that a download split across log files is counted once.
+    Run,
+    /// Classify each log file's downloads as soon as it has been read and
+    /// discard its requestors, so that no visitor's data outlives the file it
+    /// came from. A download split across files counts once per file, and
+    /// `reconcile_partial_downloads` and `new_episode_grace_minutes` can't be
+    /// used, as they need every requestor at once.
+    File,
+}
+
+/// How requests logged in the future are handled. Each import warns about how
+/// many there were either way.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FutureTimestamps {
+    /// Count them on the day they were logged.
+    Accept,
+    /// Count them as if they were logged when the import started.
+    Clamp,
+    /// Skip them.
+    Reject,
+}
+
+/// The order per-day records are exported in.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, clap::ValueEnum)]
+pub enum RowOrder {
+    /// By episode, then by date.
+    Episode,
+    /// By date, then by episode.
+    Date,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CsvQuoteStyle {
+    /// Quote every field.
+    Always,
+    /// Only quote fields containing a delimiter, quote, or newline.
+    Necessary,
+    /// Quote every field that isn't a number.
+    NonNumeric,
+    /// Never quote fields, even if doing so produces an invalid file.
+    Never,
+}
+
+/// Settings for the `[players]` table. User agent patterns are matched
+/// case-insensitively anywhere in the user agent.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlayerRules {
+    /// Referrer hosts that belong to the podcast's website. Subdomains are
+    /// also matched.
+    pub first_party_hosts: Vec<String>,
+    /// Patterns identifying web browsers.
+    pub browser_user_agents: Vec<String>,
+    /// Patterns identifying podcast apps. These take priority over
+    /// `browser_user_agents`, as many apps identify themselves as a browser
+    /// too.
+    pub app_user_agents: Vec<String>,
+}
+
+impl Default for PlayerRules {
+    fn default() -> Self {
+        Self {
+            first_party_hosts: vec![String::from("wayofthecrab.com")],
+            browser_user_agents: vec![String::from("Mozilla/")],
+            app_user_agents: [
+                "AppleCoreMedia",
+                "Podcasts",
+                "Overcast",
+                "PocketCasts",
+                "Pocket Casts",
+                "Castro",
+                "Spotify",
+                "AntennaPod",
+                "PodcastAddict",
+                "Podcast Addict",
+                "CastBox",
+                "Podbean",
+                "Stitcher",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+}
+
+/// Settings for the `[bots]` table. User agent patterns are matched
+/// case-insensitively anywhere in the user agent.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BotRules {
+    /// Patterns identifying bots, whose requests aren't counted as downloads.
+    pub user_agents: Vec<String>,
+    /// More patterns identifying bots, added to `user_agents` so that the
+    /// defaults don't need to be repeated.
+    pub extra_user_agents: Vec<String>,
+    /// When true, requests from bots are counted in each record's
+    /// `bot_requests`, to check what the patterns are filtering.
+    pub count_requests: bool,
+}
+
+impl BotRules {
+    /// Returns true if `user_agent` matches any of the patterns.
+    pub fn is_bot(&self, user_agent: &str) -> bool {
+        let user_agent = user_agent.to_ascii_lowercase();
+        self.user_agents
+            .iter()
+            .chain(&self.extra_user_agents)
+            .any(|pattern| user_agent.contains(&pattern.to_ascii_lowercase()))
+    }
+}
+
+impl Default for BotRules {
+    fn default() -> Self {
+        Self {
+            user_agents: [
+                "bot",
+                "crawler",
+                "spider",
+                "facebookexternalhit",
+                "HeadlessChrome",
+                "PhantomJS",
+                "python-requests",
+                "Go-http-client",
+                "Scrapy",
+            ]
+            .map(String::from)
+            .to_vec(),
+            extra_user_agents: Vec::new(),
+            count_requests: false,
+        }
+    }
+}
+
+/// Settings for the `[visitor_identity]` table.
+///
+/// By default addresses in the same IPv4 /24 or IPv6 /64 are one device, so
+/// that a listener roaming between addresses in their provider's network is
+/// one requestor, whose bytes add up to one download. Lengthening the prefixes
+/// tells nearby addresses apart, and including the user agent separates
+/// devices sharing an address.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VisitorIdentity {
+    /// The number of leading bits of an IPv4 address that identify a device.
+    pub ipv4_prefix: u8,
+    /// The number of leading bits of an IPv6 address that identify a device.
+    pub ipv6_prefix: u8,
+    /// When true, requests with different user agents are different devices,
+    /// so two apps downloading parts of an episode from one address are two
+    /// partial downloads rather than one full one. User agents are hashed and
+    /// never stored.
+    pub include_user_agent: bool,
+}
+
+impl Default for VisitorIdentity {
+    fn default() -> Self {
+        Self {
+            ipv4_prefix: 24,
+            ipv6_prefix: 64,
+            include_user_agent: false,
+        }
+    }
+}
+
+/// Settings for the `[report_files]` table, naming each file written to the
+/// reports directory, such as to match an existing site's URLs.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReportFiles {
+    /// The HTML report, which `crabtrics serve` also serves at `/`.
+    /// `index.html` by default.
+    pub html: String,
+    /// The report's totals as JSON. `report.json` by default.
+    pub json: String,
+    /// The downloads badge, in the JSON format of a shields.io endpoint.
+    /// `badge.json` by default.
+    pub badge: String,
+    /// Each episode's downloads on each day, as CSV. `downloads.csv` by
+    /// default.
+    pub csv: String,
+    /// Every episode's downloads on each day as JSON, with the totals.
+    /// `downloads.json` by default.
+    pub downloads_json: String,
+    /// Each episode's downloads and bytes sent across every day, as CSV.
+    /// `totals.csv` by default.
+    pub totals_csv: String,
+}
+
+impl Default for ReportFiles {
+    fn default() -> Self {
+        Self {
+            html: String::from("index.html"),
+            json: String::from("report.json"),
+            badge: String::from("badge.json"),
+            csv: String::from("downloads.csv"),
+            downloads_json: String::from("downloads.json"),
+            totals_csv: String::from("totals.csv"),
+        }
+    }
+}
+
+/// Settings for the `[badge]` table.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BadgeConfig {
+    /// The episode whose full downloads are shown, or every episode's when
+    /// unset.
+    pub episode: Option<u16>,
+    pub label: String,
+    /// Any color Shields.io accepts, such as `blue` or `#2A2D34`.
+    pub color: String,
+}
+
+impl Default for BadgeConfig {
+    fn default() -> Self {
+        Self {
+            episode: None,
+            label: String::from("downloads"),
+            color: String::from("blue"),
+        }
+    }
+}
+
+/// Settings for the `[bandwidth_cost]` table.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BandwidthCostConfig {
+    /// The price of sending one GiB, or None to leave the estimate out of the
+    /// report.
+    pub price_per_gib: Option<f64>,
+    /// The currency the price is in, which is only used as a label.
+    pub currency: String,
+}
+
+impl Default for BandwidthCostConfig {
+    fn default() -> Self {
+        Self {
+            price_per_gib: None,
+            currency: String::from("USD"),
+        }
+    }
+}
+
+/// Settings for the `[server]` table. The `CRABTRICS_USERNAME` and
+/// `CRABTRICS_PASSWORD` environment variables take priority over these, to
+/// keep the password out of the file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// The username required to view the report.
+    pub username: Option<String>,
+    /// The password required to view the report.
+    pub password: Option<String>,
+}
+
+/// Settings for the `[statsd]` table.
+#[cfg(feature = "statsd")]
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatsdConfig {
+    /// The `host:port` to send metrics to over UDP, or none to not send any.
+    pub host: Option<String>,
+    /// The prefix of every metric's name.
+    pub prefix: String,
+}
+
+#[cfg(feature = "statsd")]
+impl Default for StatsdConfig {
+    fn default() -> Self {
+        Self {
+            host: None,
+            prefix: String::from("crabtrics"),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Ok(toml::from_str(&contents)?),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Returns the episode number that downloads of the file identified by
+    /// `identifier` are tracked as, or None if it isn't a number, a
+    /// configured bonus episode, or a configured slug.
+    pub fn episode_number(&self, identifier: &str) -> Option<u16> {
+        self.bonus_episodes
+            .get(identifier)
+            .copied()
+            .or_else(|| identifier.parse().ok())
+            .or_else(|| self.episode_slugs.get(identifier).copied())
+    }
+
+    /// Returns the episode that downloads of `episode` are counted as.
+    pub fn canonical_episode(&self, episode: u16) -> u16 {
+        self.episode_aliases
+            .get(&episode)
+            .copied()
+            .unwrap_or(episode)
+    }
+}
+
+/// Parses an offset in the form `+HH:MM`, `-HH:MM`, `+HH`, or `Z`.
+pub fn parse_utc_offset(offset: &str) -> anyhow::Result<UtcOffset> {
+    if offset == "Z" || offset.eq_ignore_ascii_case("UTC") {
+        return Ok(UtcOffset::UTC);
+    }
+
+    let (sign, offset) = if let Some(offset) = offset.strip_prefix('+') {
+        (1, offset)
+    } else if let Some(offset) = offset.strip_prefix('-') {
+        (-1, offset)
+    } else {
+        anyhow::bail!("utc offset must begin with + or -")
+    };
+    let (hours, minutes) = offset.split_once(':').unwrap_or((offset, "0"));
+    let hours: i8 = hours.parse()?;
+    let minutes: i8 = minutes.parse()?;
+    Ok(UtcOffset::from_hms(sign * hours, sign * minutes, 0)?)
+}
+
+fn deserialize_utc_offset<'de, D>(deserializer: D) -> Result<UtcOffset, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let offset = String::deserialize(deserializer)?;
+    parse_utc_offset(&offset).map_err(D::Error::custom)
+}
+
+fn deserialize_optional_utc_offset<'de, D>(deserializer: D) -> Result<Option<UtcOffset>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_utc_offset(deserializer).map(Some)
+}
+
+fn deserialize_month_names<'de, D>(deserializer: D) -> Result<HashMap<String, Month>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    HashMap::<String, u8>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(name, month)| {
+            Month::try_from(month)
+                .map(|month| (name, month))
+                .map_err(D::Error::custom)
+        })
+        .collect()
+}
+
+/// Deserializes a list of episode path patterns, checking that each is valid.
+fn deserialize_episode_paths<'de, D>(deserializer: D) -> Result<Vec<EpisodePattern>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|pattern| EpisodePattern::new(pattern).map_err(D::Error::custom))
+        .collect()
+}
+
+/// Deserializes a table keyed by episode number. TOML keys are always
+/// strings, so they are parsed after deserializing.
+fn deserialize_episode_keys<'de, D, T>(deserializer: D) -> Result<HashMap<u16, T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    HashMap::<String, T>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(episode, value)| {
+            episode
+                .parse()
+                .map(|episode| (episode, value))
+                .map_err(D::Error::custom)
+        })
+        .collect()
+}
@@ -0,0 +1,524 @@
+use std::collections::HashMap;
+
+use bonsaidb::core::connection::StorageConnection;
+use bonsaidb::core::document::CollectionDocument;
+use bonsaidb::core::key::time::TimestampAsDays;
+use bonsaidb::core::keyvalue::KeyValue;
+use bonsaidb::core::schema::{Schema, SerializedCollection};
+use bonsaidb::core::transaction::{Operation, Transaction};
+use bonsaidb::local::config::{Builder, StorageConfiguration};
+use bonsaidb::local::{Database, Storage};
+use crabtrics::schema::{
+    Crabtrics, EpisodeDateKey, ImportRun, ImportedLog, PodcastDownloads, RequestorTotals,
+};
+use crabtrics::sizes::EpisodeSizes;
+
+/// The database that `Database::open` uses.
+const DATABASE_NAME: &str = "default";
+/// A copy of every document, kept until a rebuild finishes.
+const BACKUP_NAME: &str = "rebuild-backup";
+/// The key-value entry storing the schema's view names as of the last open.
+const VIEWS_KEY: &str = "schema-views";
+/// The key-value entry storing the schema version the views were last built
+/// with.
+const VERSION_KEY: &str = "schema-version";
+/// The version of the views' map and reduce functions. Increase this whenever
+/// an existing view changes what it emits, so that databases indexed by an
+/// older binary are flagged as needing `--migrate`.
+pub const SCHEMA_VERSION: u32 = 1;
+/// The key-value entry storing when each episode file was first requested.
+const FIRST_SEEN_KEY: &str = "first-seen";
+
+/// Opens the database, rebuilding it when a view has been removed from the
+/// schema since it was last opened.
+///
+/// If the views were built with an older [`SCHEMA_VERSION`], a warning is
+/// printed since the existing indexes may be stale, unless `migrate` is true,
+/// in which case the database is rebuilt to reindex every view.
+///
+/// BonsaiDb leaves a removed view's index in storage indefinitely, so every
+/// document and key-value entry is copied into a backup database, the
+/// database is recreated without the orphaned index, and everything is copied
+/// back. If a rebuild is interrupted, the backup is restored the next time the
+/// database is opened.
+pub fn open(configuration: StorageConfiguration, migrate: bool) -> anyhow::Result<Database> {
+    let storage = Storage::open(configuration.with_schema::<Crabtrics>()?)?;
+    let views = view_names::<Crabtrics>()?;
+
+    let has_backup = storage
+        .list_databases()?
+        .iter()
+        .any(|database| database.name == BACKUP_NAME);
+    if has_backup {
+        let backup = storage.database::<Crabtrics>(BACKUP_NAME)?;
+        let snapshot = Snapshot::read(&backup)?;
+        if !snapshot.is_empty() {
+            eprintln!("Restoring the database from an interrupted rebuild");
+            recreate(&storage, &snapshot)?;
+        }
+        storage.delete_database(BACKUP_NAME)?;
+    }
+
+    let db = storage.create_database::<Crabtrics>(DATABASE_NAME, true)?;
+    let db = if let Some(rebuild) = rebuild_needed(&db, &views, migrate)? {
+        match rebuild {
+            Rebuild::RemovedViews => eprintln!(
+                "Rebuilding the database to remove views that are no longer in the schema"
+            ),
+            Rebuild::OutdatedSchema => {
+                eprintln!("Rebuilding the database to reindex views from an older version")
+            }
+        }
+        let snapshot = Snapshot::read(&db)?;
+        drop(db);
+
+        let backup = storage.create_database::<Crabtrics>(BACKUP_NAME, true)?;
+        snapshot.write_to(&backup)?;
+        let db = recreate(&storage, &snapshot)?;
+        storage.delete_database(BACKUP_NAME)?;
+        db
+    } else {
+        db
+    };
+    db.set_key(VIEWS_KEY, &views).execute()?;
+    if schema_outdated(&db)? {
+        eprintln!(
+            "Warning: the database was indexed by an older version of crabtrics and some totals \
+             may be stale. Run with --migrate to reindex it."
+        );
+    } else {
+        db.set_key(VERSION_KEY, &SCHEMA_VERSION).execute()?;
+    }
+    Ok(db)
+}
+
+/// Why [`open`] rebuilds a database.
+#[derive(Debug, PartialEq)]
+enum Rebuild {
+    /// A view the database was indexed with is no longer in the schema.
+    RemovedViews,
+    /// The views were built with an older [`SCHEMA_VERSION`], and `--migrate`
+    /// was given.
+    OutdatedSchema,
+}
+
+/// Returns why `db` needs rebuilding, or None if it can be used as it is.
+/// `views` are the names of the views in the current schema.
+fn rebuild_needed(
+    db: &Database,
+    views: &[String],
+    migrate: bool,
+) -> anyhow::Result<Option<Rebuild>> {
+    let previous_views: Option<Vec<String>> = db.get_key(VIEWS_KEY).into()?;
+    if previous_views
+        .is_some_and(|previous_views| previous_views.iter().any(|view| !views.contains(view)))
+    {
+        return Ok(Some(Rebuild::RemovedViews));
+    }
+    if migrate && schema_outdated(db)? {
+        return Ok(Some(Rebuild::OutdatedSchema));
+    }
+    Ok(None)
+}
+
+/// Returns true if the database's views were built with an older
+/// [`SCHEMA_VERSION`]. Databases from before versions were recorded are
+/// assumed to be current.
+pub fn schema_outdated(db: &Database) -> anyhow::Result<bool> {
+    let version: Option<u32> = db.get_key(VERSION_KEY).into()?;
+    Ok(version.is_some_and(|version| version < SCHEMA_VERSION))
+}
+
+/// Returns the downloads of `episode` on `date`, or None if none were
+/// recorded.
+pub fn downloads_on(
+    db: &Database,
+    episode: u16,
+    date: TimestampAsDays,
+) -> anyhow::Result<Option<PodcastDownloads>> {
+    let document = PodcastDownloads::get(&EpisodeDateKey { episode, date }, db)?;
+    Ok(document.map(|document| document.contents))
+}
+
+/// Returns the Unix timestamp of the first request of each episode file, keyed
+/// by its identifier.
+pub fn first_seen(db: &Database) -> anyhow::Result<HashMap<String, i64>> {
+    let first_seen: Option<HashMap<String, i64>> = db.get_key(FIRST_SEEN_KEY).into()?;
+    Ok(first_seen.unwrap_or_default())
+}
+
+pub fn set_first_seen(db: &Database, first_seen: &HashMap<String, i64>) -> anyhow::Result<()> {
+    db.set_key(FIRST_SEEN_KEY, first_seen).execute()?;
+    Ok(())
+}
+
+/// Writes the requestor totals kept by an import. They're added to the totals
+/// stored for the same episode and day when `append` is true, and replace
+/// them otherwise.
+pub fn write_requestor_totals(
+    db: &Database,
+    totals: HashMap<EpisodeDateKey, RequestorTotals>,
+    append: bool,
+) -> anyhow::Result<()> {
+    let mut tx = Transaction::new();
+    for (key, mut day) in totals {
+        if append {
+            if let Some(stored) = RequestorTotals::get(&key, db)? {
+                let mut stored = stored.contents;
+                stored.merge(day);
+                day = stored;
+            }
+        }
+        tx.push(Operation::overwrite_serialized::<RequestorTotals, _>(
+            &key, &day,
+        )?);
+    }
+    tx.apply(db)?;
+    Ok(())
+}
+
+/// Returns the name of every view in `DB`.
+fn view_names<DB: Schema>() -> anyhow::Result<Vec<String>> {
+    Ok(DB::schematic()?
+        .views()
+        .map(|view| view.view_name().to_string())
+        .collect())
+}
+
+/// Every document and key-value entry kept in the database, which a rebuild
+/// copies into the recreated one.
+struct Snapshot {
+    downloads: Vec<CollectionDocument<PodcastDownloads>>,
+    requestor_totals: Vec<CollectionDocument<RequestorTotals>>,
+    import_runs: Vec<CollectionDocument<ImportRun>>,
+    imported_logs: Vec<CollectionDocument<ImportedLog>>,
+    first_seen: HashMap<String, i64>,
+    episode_sizes: EpisodeSizes,
+}
+
+impl Snapshot {
+    fn read(db: &Database) -> anyhow::Result<Self> {
+        Ok(Self {
+            downloads: PodcastDownloads::all(db).query()?,
+            requestor_totals: RequestorTotals::all(db).query()?,
+            import_runs: ImportRun::all(db).query()?,
+            imported_logs: ImportedLog::all(db).query()?,
+            first_seen: first_seen(db)?,
+            episode_sizes: EpisodeSizes::load(db, None)?,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.downloads.is_empty()
+            && self.requestor_totals.is_empty()
+            && self.import_runs.is_empty()
+            && self.imported_logs.is_empty()
+            && self.first_seen.is_empty()
+            && self.episode_sizes.is_empty()
+    }
+
+    fn write_to(&self, db: &Database) -> anyhow::Result<()> {
+        let mut tx = Transaction::new();
+        for document in &self.downloads {
+            tx.push(Operation::overwrite_serialized::<PodcastDownloads, _>(
+                &document.header.id,
+                &document.contents,
+            )?);
+        }
+        for document in &self.requestor_totals {
+            tx.push(Operation::overwrite_serialized::<RequestorTotals, _>(
+                &document.header.id,
+                &document.contents,
+            )?);
+        }
+        for document in &self.import_runs {
+            tx.push(Operation::overwrite_serialized::<ImportRun, _>(
+                &document.header.id,
+                &document.contents,
+            )?);
+        }
+        for document in &self.imported_logs {
+            tx.push(Operation::overwrite_serialized::<ImportedLog, _>(
+                &document.header.id,
+                &document.contents,
+            )?);
+        }
+        tx.apply(db)?;
+        if !self.first_seen.is_empty() {
+            set_first_seen(db, &self.first_seen)?;
+        }
+        if !self.episode_sizes.is_empty() {
+            self.episode_sizes.save(db)?;
+        }
+        Ok(())
+    }
+}
+
+/// Deletes and recreates the database, then writes `snapshot` into it.
+fn recreate(storage: &Storage, snapshot: &Snapshot) -> anyhow::Result<Database> {
+    storage.delete_database(DATABASE_NAME)?;
+    let db = storage.create_database::<Crabtrics>(DATABASE_NAME, false)?;
+    snapshot.write_to(&db)?;
+    Ok(db)
+}
+
+#[test]
+fn downloads_on_date() {
+    use crabtrics::testing::{insert_downloads, memory_database};
+
+    let db = memory_database();
+    let today = TimestampAsDays::now();
+    insert_downloads(
+        &db,
+        12,
+        today,
+        PodcastDownloads {
+            full_downloads: 5,
+            ..PodcastDownloads::default()
+        },
+    );
+
+    let downloads = downloads_on(&db, 12, today).unwrap().unwrap();
+    assert_eq!(downloads.full_downloads, 5);
+    assert_eq!(downloads_on(&db, 11, today).unwrap(), None);
+}
+
+#[test]
+fn outdated_schema_version() {
+    use bonsaidb::core::key::time::TimestampAsDays;
+
+    let path = std::env::temp_dir().join("crabtrics-outdated-schema-version.bonsaidb");
+    let _ = std::fs::remove_dir_all(&path);
+    {
+        let db = open(StorageConfiguration::new(&path), false).unwrap();
+        assert!(!schema_outdated(&db).unwrap());
+        PodcastDownloads {
+            full_downloads: 3,
+            ..PodcastDownloads::default()
+        }
+        .insert_into(
+            &EpisodeDateKey {
+                episode: 1,
+                date: TimestampAsDays::now(),
+            },
+            &db,
+        )
+        .unwrap();
+        ImportedLog {
+            size: 100,
+            modified: 1_683_558_510_000_000_000,
+            days: [1_683_504_000].into(),
+        }
+        .insert_into(&String::from("access.log"), &db)
+        .unwrap();
+        db.set_key(VERSION_KEY, &(SCHEMA_VERSION - 1))
+            .execute()
+            .unwrap();
+    }
+
+    // Without --migrate the database is left as it was, and stays flagged.
+    let db = open(StorageConfiguration::new(&path), false).unwrap();
+    assert!(schema_outdated(&db).unwrap());
+    drop(db);
+
+    let db = open(StorageConfiguration::new(&path), true).unwrap();
+    assert!(!schema_outdated(&db).unwrap());
+    let documents = PodcastDownloads::all(&db).query().unwrap();
+    assert_eq!(documents.len(), 1);
+    assert_eq!(documents[0].contents.full_downloads, 3);
+    let imported = ImportedLog::get(&String::from("access.log"), &db)
+        .unwrap()
+        .unwrap();
+    assert_eq!(imported.contents.size, 100);
+    assert_eq!(imported.contents.days.len(), 1);
+}
+
+#[test]
+fn removed_views() {
+    use bonsaidb::core::document::Emit;
+    use bonsaidb::core::key::time::TimestampAsDays;
+    use bonsaidb::core::schema::{
+        Collection, CollectionMapReduce, SerializedView, View, ViewSchema,
+    };
+    use crabtrics::schema::{CompleteDownloads, EpisodeDateKey};
+    use serde::{Deserialize, Serialize};
+
+    /// A previous version of the schema with a view that has since been
+    /// removed.
+    #[derive(Schema, Debug)]
+    #[schema(name = "crabtrics", collections = [OldPodcastDownloads])]
+    struct OldCrabtrics;
+
+    #[derive(Debug, Collection, Serialize, Deserialize)]
+    #[collection(name = "podcast-downloads", primary_key = EpisodeDateKey, views = [OldPartialDownloads])]
+    struct OldPodcastDownloads {
+        full_downloads: u16,
+        partial_downloads: u16,
+    }
+
+    #[derive(Debug, Clone, View, ViewSchema)]
+    #[view(name = "old-partial", key = u16, value = u32, collection = OldPodcastDownloads)]
+    struct OldPartialDownloads;
+
+    impl CollectionMapReduce for OldPartialDownloads {
+        fn map<'doc>(
+            &self,
+            document: CollectionDocument<<Self::View as View>::Collection>,
+        ) -> bonsaidb::core::schema::ViewMapResult<'doc, Self> {
+            document.header.emit_key_and_value(
+                document.header.id.episode,
+                u32::from(document.contents.partial_downloads),
+            )
+        }
+    }
+
+    let path = std::env::temp_dir().join("crabtrics-removed-views.bonsaidb");
+    let _ = std::fs::remove_dir_all(&path);
+    {
+        let db = Database::open::<OldCrabtrics>(StorageConfiguration::new(&path)).unwrap();
+        db.set_key(VIEWS_KEY, &view_names::<OldCrabtrics>().unwrap())
+            .execute()
+            .unwrap();
+        OldPodcastDownloads {
+            full_downloads: 3,
+            partial_downloads: 2,
+        }
+        .insert_into(
+            &EpisodeDateKey {
+                episode: 1,
+                date: TimestampAsDays::now(),
+            },
+            &db,
+        )
+        .unwrap();
+        // Build the view's index.
+        OldPartialDownloads::entries(&db).query().unwrap();
+    }
+
+    let db = Database::open::<Crabtrics>(StorageConfiguration::new(&path)).unwrap();
+    let views = view_names::<Crabtrics>().unwrap();
+    assert_eq!(
+        rebuild_needed(&db, &views, false).unwrap(),
+        Some(Rebuild::RemovedViews)
+    );
+    drop(db);
+
+    let db = open(StorageConfiguration::new(&path), false).unwrap();
+    let documents = PodcastDownloads::all(&db).query().unwrap();
+    assert_eq!(documents.len(), 1);
+    assert_eq!(documents[0].contents.full_downloads, 3);
+    assert_eq!(documents[0].contents.partial_downloads, 2);
+    let complete = CompleteDownloads::entries(&db).reduce_grouped().unwrap();
+    assert_eq!(complete[0].value, 3);
+    let stored_views: Option<Vec<String>> = db.get_key(VIEWS_KEY).into().unwrap();
+    assert_eq!(stored_views, Some(view_names::<Crabtrics>().unwrap()));
+    assert_eq!(rebuild_needed(&db, &views, false).unwrap(), None);
+    drop(db);
+
+    // Reopening without any schema changes keeps the data as-is.
+    let db = open(StorageConfiguration::new(&path), false).unwrap();
+    assert_eq!(PodcastDownloads::all(&db).query().unwrap().len(), 1);
+}
+
+#[test]
+fn rebuilds_keep_requestor_totals() {
+    use bonsaidb::core::key::time::TimestampAsDays;
+    use crabtrics::schema::RequestorTotal;
+
+    let path = std::env::temp_dir().join("crabtrics-rebuilds-keep-requestor-totals.bonsaidb");
+    let _ = std::fs::remove_dir_all(&path);
+    let key = EpisodeDateKey {
+        episode: 1,
+        date: TimestampAsDays::now(),
+    };
+    let totals = RequestorTotals {
+        sizes: [(String::from("m4a"), 1_000)].into(),
+        requestors: vec![RequestorTotal {
+            visitor: 42,
+            session: 0,
+            first_request: 1_683_558_510,
+            bytes: [(String::from("m4a"), 600)].into(),
+        }],
+    };
+    {
+        let db = open(StorageConfiguration::new(&path), false).unwrap();
+        write_requestor_totals(&db, HashMap::from([(key, totals)]), false).unwrap();
+        db.set_key(VERSION_KEY, &(SCHEMA_VERSION - 1))
+            .execute()
+            .unwrap();
+    }
+
+    let db = open(StorageConfiguration::new(&path), true).unwrap();
+    assert!(!schema_outdated(&db).unwrap());
+    let stored = RequestorTotals::get(&key, &db).unwrap().unwrap();
+    assert_eq!(stored.contents.requestors.len(), 1);
+    assert_eq!(stored.contents.requestors[0].visitor, 42);
+    assert_eq!(stored.contents.sizes["m4a"], 1_000);
+}
+
+#[test]
+fn rebuilds_keep_import_runs() {
+    let path = std::env::temp_dir().join("crabtrics-rebuilds-keep-import-runs.bonsaidb");
+    let _ = std::fs::remove_dir_all(&path);
+    {
+        let db = open(StorageConfiguration::new(&path), false).unwrap();
+        ImportRun {
+            started_at: 1_683_558_510,
+            version: String::from("1.0.0"),
+            files: vec![String::from("access.log")],
+            lines_read: 10,
+            lines_skipped: 4,
+            records_written: 2,
+        }
+        .push_into(&db)
+        .unwrap();
+        db.set_key(VERSION_KEY, &(SCHEMA_VERSION - 1))
+            .execute()
+            .unwrap();
+    }
+
+    let db = open(StorageConfiguration::new(&path), true).unwrap();
+    let runs = ImportRun::all(&db).query().unwrap();
+    assert_eq!(runs.len(), 1);
+    assert_eq!(runs[0].contents.files, ["access.log"]);
+    assert_eq!(runs[0].contents.lines_read, 10);
+}
+
+#[test]
+fn rebuilds_keep_first_seen() {
+    let path = std::env::temp_dir().join("crabtrics-rebuilds-keep-first-seen.bonsaidb");
+    let _ = std::fs::remove_dir_all(&path);
+    let recorded = HashMap::from([(String::from("episode-1.m4a"), 1_683_558_510)]);
+    {
+        let db = open(StorageConfiguration::new(&path), false).unwrap();
+        set_first_seen(&db, &recorded).unwrap();
+        db.set_key(VERSION_KEY, &(SCHEMA_VERSION - 1))
+            .execute()
+            .unwrap();
+    }
+
+    let db = open(StorageConfiguration::new(&path), true).unwrap();
+    assert_eq!(first_seen(&db).unwrap(), recorded);
+}
+
+#[test]
+fn rebuilds_keep_episode_sizes() {
+    let directory = std::env::temp_dir().join("crabtrics-rebuilds-keep-episode-sizes");
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    std::fs::write(directory.join("episode-001.m4a"), vec![0; 1_000]).unwrap();
+    let path = directory.join("crabtrics.bonsaidb");
+    {
+        let db = open(StorageConfiguration::new(&path), false).unwrap();
+        let mut sizes = EpisodeSizes::load(&db, Some(&directory)).unwrap();
+        sizes.size("episode-001.m4a").unwrap();
+        sizes.save(&db).unwrap();
+        db.set_key(VERSION_KEY, &(SCHEMA_VERSION - 1))
+            .execute()
+            .unwrap();
+    }
+
+    let db = open(StorageConfiguration::new(&path), true).unwrap();
+    let mut sizes = EpisodeSizes::load(&db, None).unwrap();
+    assert_eq!(sizes.size("episode-001.m4a").unwrap(), 1_000);
+}
@@ -0,0 +1,270 @@
+use std::borrow::Cow;
+
+/// An episode's audio file, as requested by a client.
+#[derive(Debug, Eq, PartialEq)]
+pub struct EpisodeFile<'a> {
+    /// The episode's identifier, which is usually its number but can also
+    /// name a bonus episode, such as `012b`.
+    pub identifier: &'a str,
+    /// Whatever followed an `_` or `-` after the identifier, such as
+    /// `chaptered` in `episode-012-chaptered.m4a`.
+    pub suffix: Option<&'a str>,
+    pub extension: &'a str,
+}
+
+impl EpisodeFile<'_> {
+    /// Returns the episode number, if the identifier is a number.
+    pub fn number(&self) -> Option<u16> {
+        self.identifier.parse().ok()
+    }
+}
+
+/// The patterns episode files are requested at unless others are configured.
+pub const DEFAULT_EPISODE_PATHS: [&str; 2] = [
+    "/episode-{episode}.{ext}",
+    "/way_of_the_crab_{episode}.{ext}",
+];
+
+/// A request path that names an episode's file, such as
+/// `/podcasts/crab/ep{episode}.{ext}`, where `{episode}` matches the file's
+/// identifier and `{ext}` its extension.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct EpisodePattern {
+    /// What comes before `{episode}`.
+    prefix: String,
+    /// What comes between `{episode}` and `{ext}`.
+    separator: String,
+    /// What comes after `{ext}`.
+    trailer: String,
+}
+
+impl EpisodePattern {
+    /// Parses a pattern containing `{episode}` followed by `{ext}`, with
+    /// something other than a placeholder between them.
+    pub fn new(pattern: &str) -> anyhow::Result<Self> {
+        let Some((prefix, remaining)) = pattern.split_once("{episode}") else {
+            anyhow::bail!("episode path pattern {pattern:?} doesn't contain {{episode}}")
+        };
+        let Some((separator, trailer)) = remaining.split_once("{ext}") else {
+            anyhow::bail!(
+                "episode path pattern {pattern:?} doesn't contain {{ext}} after {{episode}}"
+            )
+        };
+        anyhow::ensure!(
+            !separator.is_empty(),
+            "episode path pattern {pattern:?} needs a separator between {{episode}} and {{ext}}"
+        );
+        anyhow::ensure!(
+            ![prefix, separator, trailer]
+                .iter()
+                .any(|literal| literal.contains(['{', '}'])),
+            "episode path pattern {pattern:?} can only contain {{episode}} and {{ext}} once each"
+        );
+        Ok(Self {
+            prefix: prefix.to_string(),
+            separator: separator.to_string(),
+            trailer: trailer.to_string(),
+        })
+    }
+
+    /// Returns the patterns in [`DEFAULT_EPISODE_PATHS`].
+    pub fn defaults() -> Vec<Self> {
+        DEFAULT_EPISODE_PATHS
+            .iter()
+            .map(|pattern| Self::new(pattern).expect("default episode paths are valid"))
+            .collect()
+    }
+
+    /// Parses `path` if it matches this pattern, following the rules of
+    /// [`parse_episode_path`] for the identifier and its suffix.
+    pub fn parse<'a>(&self, path: &'a str) -> Option<EpisodeFile<'a>> {
+        let file = path.strip_prefix(self.prefix.as_str())?;
+        let file = file.strip_suffix(self.trailer.as_str())?;
+        let (identifier, extension) = file.split_once(self.separator.as_str())?;
+        parse_identifier(identifier, extension)
+    }
+}
+
+/// Parses request paths matching `/episode-{identifier}.{extension}` or
+/// `/way_of_the_crab_{identifier}.{extension}`, where the identifier is made of
+/// ASCII letters and digits.
+///
+/// Anything following an `_` or `-` after the identifier is kept as the
+/// file's suffix rather than being part of the identifier.
+pub fn parse_episode_path(path: &str) -> Option<EpisodeFile<'_>> {
+    let file = path
+        .strip_prefix("/episode-")
+        .or_else(|| path.strip_prefix("/way_of_the_crab_"))?;
+    let (identifier, extension) = file.split_once('.')?;
+    parse_identifier(identifier, extension)
+}
+
+/// Splits the suffix off `identifier`, returning None if what's left isn't
+/// made of ASCII letters and digits.
+fn parse_identifier<'a>(identifier: &'a str, extension: &'a str) -> Option<EpisodeFile<'a>> {
+    let (identifier, suffix) = match identifier.split_once(['_', '-']) {
+        Some((identifier, suffix)) => (identifier, Some(suffix)),
+        None => (identifier, None),
+    };
+    if identifier.is_empty() || !identifier.bytes().all(|byte| byte.is_ascii_alphanumeric()) {
+        return None;
+    }
+    Some(EpisodeFile {
+        identifier,
+        suffix,
+        extension,
+    })
+}
+
+/// Parses request paths matching `/{slug}.{extension}`, such as
+/// `/the-great-crab-migration.m4a`, where the slug is made of ASCII letters,
+/// digits, `-`, and `_`. The slug is returned as the file's identifier.
+pub fn parse_slug_path(path: &str) -> Option<EpisodeFile<'_>> {
+    let (identifier, extension) = path.strip_prefix('/')?.split_once('.')?;
+    let is_slug_byte = |byte: u8| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_';
+    if identifier.is_empty() || !identifier.bytes().all(is_slug_byte) || extension.contains('/') {
+        return None;
+    }
+    Some(EpisodeFile {
+        identifier,
+        suffix: None,
+        extension,
+    })
+}
+
+/// Collapses repeated slashes and resolves `.` and `..` segments in an
+/// absolute request path, so that `//episode-012.m4a` and
+/// `/files/../episode-012.m4a` both become `/episode-012.m4a`.
+///
+/// Returns None if the path isn't absolute or a `..` would escape the root.
+pub fn normalize_path(path: &str) -> Option<Cow<'_, str>> {
+    let relative = path.strip_prefix('/')?;
+    let is_normal = !path.contains("//")
+        && !relative
+            .split('/')
+            .any(|segment| segment == "." || segment == "..");
+    if is_normal {
+        return Some(Cow::Borrowed(path));
+    }
+
+    let mut segments = Vec::new();
+    for segment in relative.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let mut normalized = format!("/{}", segments.join("/"));
+    let is_directory = path.ends_with('/') || path.ends_with("/.") || path.ends_with("/..");
+    if is_directory && !segments.is_empty() {
+        normalized.push('/');
+    }
+    Some(Cow::Owned(normalized))
+}
+
+#[test]
+fn normalized_paths() {
+    for path in [
+        "/episode-012.m4a",
+        "//episode-012.m4a",
+        "/./episode-012.m4a",
+        "/files//../episode-012.m4a",
+        "/a/b/./../../episode-012.m4a",
+    ] {
+        let normalized = normalize_path(path).unwrap();
+        assert_eq!(normalized, "/episode-012.m4a", "{path}");
+        let file = parse_episode_path(&normalized).unwrap();
+        assert_eq!(file.number(), Some(12));
+    }
+    assert!(matches!(
+        normalize_path("/episode-012.m4a"),
+        Some(Cow::Borrowed(_))
+    ));
+    assert_eq!(normalize_path("/files/./").unwrap(), "/files/");
+    assert_eq!(normalize_path("/files/..").unwrap(), "/");
+    assert_eq!(normalize_path("/../episode-012.m4a"), None);
+    assert_eq!(normalize_path("/files/../../episode-012.m4a"), None);
+    assert_eq!(normalize_path("episode-012.m4a"), None);
+}
+
+#[test]
+fn suffixes() {
+    assert_eq!(
+        parse_episode_path("/episode-012-chaptered.m4a").unwrap(),
+        EpisodeFile {
+            identifier: "012",
+            suffix: Some("chaptered"),
+            extension: "m4a",
+        }
+    );
+    assert_eq!(
+        parse_episode_path("/way_of_the_crab_012b_final-v2.mp3").unwrap(),
+        EpisodeFile {
+            identifier: "012b",
+            suffix: Some("final-v2"),
+            extension: "mp3",
+        }
+    );
+    assert_eq!(parse_episode_path("/episode-012.m4a").unwrap().suffix, None);
+}
+
+#[test]
+fn episode_patterns() {
+    for pattern in DEFAULT_EPISODE_PATHS {
+        let pattern = EpisodePattern::new(pattern).unwrap();
+        for path in [
+            "/episode-012.m4a",
+            "/episode-012-chaptered.m4a",
+            "/way_of_the_crab_012b_final-v2.mp3",
+            "/episode-.m4a",
+            "/episode-012",
+        ] {
+            if let Some(file) = pattern.parse(path) {
+                assert_eq!(Some(file), parse_episode_path(path), "{path}");
+            }
+        }
+    }
+
+    let pattern = EpisodePattern::new("/podcasts/crab/ep{episode}.{ext}").unwrap();
+    assert_eq!(
+        pattern.parse("/podcasts/crab/ep12.m4a").unwrap(),
+        EpisodeFile {
+            identifier: "12",
+            suffix: None,
+            extension: "m4a",
+        }
+    );
+    assert_eq!(pattern.parse("/episode-012.m4a"), None);
+    assert_eq!(pattern.parse("/podcasts/crab/ep.m4a"), None);
+
+    let pattern = EpisodePattern::new("/s2/{episode}.{ext}/download").unwrap();
+    assert_eq!(
+        pattern.parse("/s2/012.mp3/download").unwrap().extension,
+        "mp3"
+    );
+    assert_eq!(pattern.parse("/s2/012.mp3"), None);
+
+    assert!(EpisodePattern::new("/episode.{ext}").is_err());
+    assert!(EpisodePattern::new("/{ext}/{episode}").is_err());
+    assert!(EpisodePattern::new("/{episode}{ext}").is_err());
+    assert!(EpisodePattern::new("/{episode}.{ext}.{ext}").is_err());
+}
+
+#[test]
+fn slugs() {
+    assert_eq!(
+        parse_slug_path("/the-great-crab-migration.m4a").unwrap(),
+        EpisodeFile {
+            identifier: "the-great-crab-migration",
+            suffix: None,
+            extension: "m4a",
+        }
+    );
+    assert_eq!(parse_slug_path("/files/the-great-crab-migration.m4a"), None);
+    assert_eq!(parse_slug_path("/the great crab migration.m4a"), None);
+    assert_eq!(parse_slug_path("/.m4a"), None);
+    assert_eq!(parse_slug_path("/the-great-crab-migration"), None);
+}
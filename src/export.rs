@@ -0,0 +1,314 @@
+use std::io::{BufWriter, Write};
+use std::time::{Duration, SystemTime};
+
+use bonsaidb::core::key::time::TimestampAsDays;
+use bonsaidb::core::schema::{SerializedCollection, SerializedView};
+use bonsaidb::local::Database;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::config::BadgeConfig;
+use crate::schema::{CompleteDownloads, EpisodeDateKey, ImportRun, PodcastDownloads};
+
+pub const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Returns the day `days` days before today.
+pub fn days_ago(days: u32) -> anyhow::Result<TimestampAsDays> {
+    Ok(TimestampAsDays::try_from(
+        SystemTime::try_from(TimestampAsDays::now())? - DAY * days,
+    )?)
+}
+
+/// A single episode's downloads on one day.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct DailyRecord {
+    pub date: String,
+    pub episode: u16,
+    pub full: u16,
+    pub partial: u16,
+}
+
+impl DailyRecord {
+    pub fn new(key: &EpisodeDateKey, downloads: &PodcastDownloads) -> anyhow::Result<Self> {
+        Ok(Self {
+            date: format_date(key.date)?,
+            episode: key.episode,
+            full: downloads.full_downloads,
+            partial: downloads.partial_downloads,
+        })
+    }
+}
+
+/// Formats `date` as `YYYY-MM-DD`.
+pub fn format_date(date: TimestampAsDays) -> anyhow::Result<String> {
+    let date = OffsetDateTime::from(SystemTime::try_from(date)?);
+    Ok(format!(
+        "{:04}-{:02}-{:02}",
+        date.year(),
+        date.month() as u8,
+        date.day()
+    ))
+}
+
+/// Writes each per-day record as a standalone JSON object followed by a
+/// newline, which is convenient for line-oriented tools like `jq`.
+///
+/// When `changed_since` is given, only records whose counts changed after it
+/// are written, for incrementally syncing another database. Records that
+/// haven't changed since before change times were tracked are skipped.
+pub fn export_json_lines<W: Write>(
+    db: &Database,
+    changed_since: Option<OffsetDateTime>,
+    output: W,
+) -> anyhow::Result<()> {
+    let mut output = BufWriter::new(output);
+    for document in PodcastDownloads::all(db).query()? {
+        if changed_since.is_some_and(|since| document.contents.written_at <= since.unix_timestamp())
+        {
+            continue;
+        }
+        serde_json::to_writer(
+            &mut output,
+            &DailyRecord::new(&document.header.id, &document.contents)?,
+        )?;
+        output.write_all(b"\n")?;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+/// Writes each recorded [`ImportRun`] as a JSON object followed by a newline,
+/// oldest first.
+pub fn write_history<W: Write>(db: &Database, output: W) -> anyhow::Result<()> {
+    let mut output = BufWriter::new(output);
+    for run in ImportRun::all(db).query()? {
+        serde_json::to_writer(&mut output, &run.contents)?;
+        output.write_all(b"\n")?;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+/// Writes the URL of each episode with downloads on its own line, for
+/// generating sitemaps.
+pub fn export_episode_urls<W: Write>(
+    db: &Database,
+    url_template: &str,
+    output: W,
+) -> anyhow::Result<()> {
+    let mut output = BufWriter::new(output);
+    for mapping in CompleteDownloads::entries(db).reduce_grouped()? {
+        writeln!(output, "{}", episode_url(url_template, mapping.key)?)?;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+/// Replaces `{episode}` in `template` with `episode`, or `{episode:0N}` with
+/// `episode` zero-padded to `N` digits.
+pub fn episode_url(template: &str, episode: u16) -> anyhow::Result<String> {
+    const PLACEHOLDER: &str = "{episode";
+
+    let Some(start) = template.find(PLACEHOLDER) else {
+        anyhow::bail!("episode url template is missing {{episode}}")
+    };
+    let Some(length) = template[start..].find('}') else {
+        anyhow::bail!("episode url template has an unterminated {{episode")
+    };
+    let format = &template[start + PLACEHOLDER.len()..start + length];
+    let number = if format.is_empty() {
+        episode.to_string()
+    } else if let Some(width) = format.strip_prefix(":0") {
+        format!("{episode:0width$}", width = width.parse::<usize>()?)
+    } else {
+        anyhow::bail!("unsupported episode number format: {format}")
+    };
+    Ok(format!(
+        "{}{number}{}",
+        &template[..start],
+        &template[start + length + 1..]
+    ))
+}
+
+/// Returns the full downloads of every episode combined.
+///
+/// This reduces the `CompleteDownloads` view as a whole, which BonsaiDb
+/// answers from its cached reductions rather than by visiting each episode.
+pub fn total_downloads(db: &Database) -> anyhow::Result<u64> {
+    Ok(u64::from(CompleteDownloads::entries(db).reduce()?))
+}
+
+/// The JSON served to a Shields.io endpoint badge.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Badge<'a> {
+    pub schema_version: u8,
+    pub label: &'a str,
+    pub message: String,
+    pub color: &'a str,
+}
+
+impl<'a> Badge<'a> {
+    pub fn new(downloads: u64, config: &'a BadgeConfig) -> Self {
+        Self {
+            schema_version: 1,
+            label: &config.label,
+            message: humanize(downloads),
+            color: &config.color,
+        }
+    }
+}
+
+/// Abbreviates `count` to at most one decimal place, such as `12.3k` for
+/// 12,345.
+pub fn humanize(count: u64) -> String {
+    const UNITS: [(f64, &str); 3] = [(1e3, "k"), (1e6, "M"), (1e9, "B")];
+
+    if count < 1_000 {
+        return count.to_string();
+    }
+    let count = count as f64;
+    let (divisor, suffix) = UNITS
+        .into_iter()
+        .find(|(divisor, _)| (count / divisor * 10.).round() < 10_000.)
+        .unwrap_or(UNITS[UNITS.len() - 1]);
+    let abbreviated = format!("{:.1}", count / divisor);
+    let abbreviated = abbreviated.strip_suffix(".0").unwrap_or(&abbreviated);
+    format!("{abbreviated}{suffix}")
+}
+
+#[test]
+fn json_lines() {
+    use crate::testing::{insert_downloads, memory_database};
+
+    let db = memory_database();
+    for episode in 1..=2 {
+        insert_downloads(
+            &db,
+            episode,
+            TimestampAsDays::now(),
+            PodcastDownloads {
+                full_downloads: episode * 10,
+                partial_downloads: episode,
+                ..PodcastDownloads::default()
+            },
+        );
+    }
+
+    let mut output = Vec::new();
+    export_json_lines(&db, None, &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let lines = output.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    for (line, episode) in lines.into_iter().zip(1..) {
+        let record: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert_eq!(record["episode"], episode);
+        assert_eq!(record["full"], episode * 10);
+        assert_eq!(record["partial"], episode);
+        assert_eq!(
+            record["date"],
+            format_date(TimestampAsDays::now()).unwrap().as_str()
+        );
+    }
+}
+
+#[test]
+fn episode_urls() {
+    use crate::testing::{insert_downloads, memory_database};
+
+    let db = memory_database();
+    for episode in [12, 1, 12] {
+        insert_downloads(
+            &db,
+            episode,
+            TimestampAsDays::now(),
+            PodcastDownloads::default(),
+        );
+    }
+
+    let mut output = Vec::new();
+    export_episode_urls(&db, "https://example.com/ep{episode:03}.mp3", &mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "https://example.com/ep001.mp3\nhttps://example.com/ep012.mp3\n"
+    );
+    assert_eq!(episode_url("/{episode}/", 7).unwrap(), "/7/");
+}
+
+#[test]
+fn shields_badge() {
+    let config = BadgeConfig::default();
+    assert_eq!(
+        serde_json::to_value(Badge::new(12_345, &config)).unwrap(),
+        serde_json::json!({
+            "schemaVersion": 1,
+            "label": "downloads",
+            "message": "12.3k",
+            "color": "blue",
+        })
+    );
+
+    for (count, expected) in [
+        (0, "0"),
+        (999, "999"),
+        (1_000, "1k"),
+        (999_949, "999.9k"),
+        (999_950, "1M"),
+        (2_500_000, "2.5M"),
+    ] {
+        assert_eq!(humanize(count), expected);
+    }
+}
+
+#[test]
+fn changed_since() {
+    use crate::testing::{insert_downloads, memory_database};
+
+    let db = memory_database();
+    for (episode, written_at) in [(1, 0), (2, 1_000), (3, 2_000)] {
+        insert_downloads(
+            &db,
+            episode,
+            TimestampAsDays::now(),
+            PodcastDownloads {
+                written_at,
+                ..PodcastDownloads::default()
+            },
+        );
+    }
+
+    let mut output = Vec::new();
+    export_json_lines(
+        &db,
+        Some(OffsetDateTime::from_unix_timestamp(1_000).unwrap()),
+        &mut output,
+    )
+    .unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let episodes = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["episode"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(episodes, [3]);
+}
+
+#[test]
+fn catalog_total() {
+    use crate::testing::{insert_downloads, memory_database};
+
+    let db = memory_database();
+    assert_eq!(total_downloads(&db).unwrap(), 0);
+    for (episode, full_downloads) in [(1, 10), (2, 5), (3, 3)] {
+        insert_downloads(
+            &db,
+            episode,
+            TimestampAsDays::now(),
+            PodcastDownloads {
+                full_downloads,
+                partial_downloads: 100,
+                ..PodcastDownloads::default()
+            },
+        );
+    }
+    assert_eq!(total_downloads(&db).unwrap(), 18);
+}
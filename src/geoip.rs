@@ -0,0 +1,89 @@
+use std::fmt;
+use std::net::IpAddr;
+#[cfg(feature = "geoip")]
+use std::path::Path;
+
+/// A two-letter ISO 3166-1 country code, such as `NZ`.
+#[derive(Clone, Copy, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct CountryCode([u8; 2]);
+
+impl CountryCode {
+    /// Returns the uppercase code for `code`, or None if it isn't two ASCII
+    /// letters.
+    pub fn new(code: &str) -> Option<Self> {
+        match code.as_bytes() {
+            &[first, second] if first.is_ascii_alphabetic() && second.is_ascii_alphabetic() => {
+                Some(Self([
+                    first.to_ascii_uppercase(),
+                    second.to_ascii_uppercase(),
+                ]))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.0).expect("only ASCII letters")
+    }
+}
+
+impl fmt::Debug for CountryCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Display for CountryCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Finds the country that requests from an address were made in.
+pub trait CountryLookup: fmt::Debug + Send + Sync {
+    /// Returns the country `ip` is in, or None if it isn't known.
+    fn country_for(&self, ip: IpAddr) -> Option<CountryCode>;
+}
+
+/// A MaxMind GeoIP2 or GeoLite2 Country or City database, read entirely into
+/// memory so that looking up an address doesn't touch the disk.
+#[cfg(feature = "geoip")]
+pub struct MaxMindCountries {
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+#[cfg(feature = "geoip")]
+impl MaxMindCountries {
+    /// Reads the `.mmdb` file at `path`.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        Ok(Self {
+            reader: maxminddb::Reader::open_readfile(path)?,
+        })
+    }
+}
+
+#[cfg(feature = "geoip")]
+impl fmt::Debug for MaxMindCountries {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MaxMindCountries")
+            .field("database_type", &self.reader.metadata.database_type)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "geoip")]
+impl CountryLookup for MaxMindCountries {
+    fn country_for(&self, ip: IpAddr) -> Option<CountryCode> {
+        let country: maxminddb::geoip2::Country<'_> = self.reader.lookup(ip).ok()?;
+        CountryCode::new(country.country?.iso_code?)
+    }
+}
+
+#[test]
+fn country_codes() {
+    assert_eq!(CountryCode::new("nz").unwrap().as_str(), "NZ");
+    assert_eq!(CountryCode::new("US").unwrap().to_string(), "US");
+    assert_eq!(CountryCode::new("USA"), None);
+    assert_eq!(CountryCode::new("1A"), None);
+    assert_eq!(CountryCode::new(""), None);
+}
@@ -0,0 +1,120 @@
+use std::io::Read;
+
+use libflate::{deflate, gzip};
+
+const FLAG_HEADER_CRC: u8 = 0b10;
+const FLAG_EXTRA: u8 = 0b100;
+const FLAG_NAME: u8 = 0b1000;
+const FLAG_COMMENT: u8 = 0b1_0000;
+
+/// The decompressed contents of a gzip file.
+#[derive(Debug)]
+pub struct Decompressed {
+    pub contents: Vec<u8>,
+    /// True if the compressed data was intact but the trailing checksum and
+    /// length were missing or did not match, which happens when a file is
+    /// copied while it is still being written.
+    pub invalid_footer: bool,
+}
+
+/// Decompresses a gzip file, including every member of a file that is
+/// several gzip streams concatenated together, as appending to a compressed
+/// log produces.
+///
+/// Errors in the compressed data itself are returned as errors, while a
+/// missing or invalid footer is reported through
+/// [`Decompressed::invalid_footer`].
+pub fn decompress(compressed: &[u8]) -> anyhow::Result<Decompressed> {
+    let mut contents = Vec::new();
+    let result = gzip::MultiDecoder::new(compressed)
+        .and_then(|mut decoder| decoder.read_to_end(&mut contents));
+    match result {
+        Ok(_) => Ok(Decompressed {
+            contents,
+            invalid_footer: false,
+        }),
+        Err(err) => {
+            // The footer is only read once the deflate stream has ended. If
+            // the stream decodes on its own, the footer was the only problem.
+            // Only a single member can be recovered this way.
+            let mut contents = Vec::new();
+            if deflate::Decoder::new(strip_header(compressed)?)
+                .read_to_end(&mut contents)
+                .is_ok()
+            {
+                Ok(Decompressed {
+                    contents,
+                    invalid_footer: true,
+                })
+            } else {
+                Err(err.into())
+            }
+        }
+    }
+}
+
+/// Returns the data following the gzip header in `compressed`.
+fn strip_header(compressed: &[u8]) -> anyhow::Result<&[u8]> {
+    let Some((header, mut data)) = compressed.split_first_chunk::<10>() else {
+        anyhow::bail!("gzip header truncated")
+    };
+    if header[..3] != [0x1f, 0x8b, 8] {
+        anyhow::bail!("not a gzip file");
+    }
+
+    let flags = header[3];
+    if flags & FLAG_EXTRA != 0 {
+        let Some((length, extra)) = data.split_first_chunk::<2>() else {
+            anyhow::bail!("gzip header truncated")
+        };
+        let length = usize::from(u16::from_le_bytes(*length));
+        let Some(remaining) = extra.get(length..) else {
+            anyhow::bail!("gzip header truncated")
+        };
+        data = remaining;
+    }
+    for flag in [FLAG_NAME, FLAG_COMMENT] {
+        if flags & flag != 0 {
+            let Some(end) = data.iter().position(|byte| *byte == 0) else {
+                anyhow::bail!("gzip header truncated")
+            };
+            data = &data[end + 1..];
+        }
+    }
+    if flags & FLAG_HEADER_CRC != 0 {
+        let Some(remaining) = data.get(2..) else {
+            anyhow::bail!("gzip header truncated")
+        };
+        data = remaining;
+    }
+    Ok(data)
+}
+
+#[test]
+fn missing_footer() {
+    use crabtrics::testing::{gzip_compress, SAMPLE_LOG};
+
+    let compressed = gzip_compress(SAMPLE_LOG.as_bytes());
+    let intact = decompress(&compressed).unwrap();
+    assert!(!intact.invalid_footer);
+    assert_eq!(intact.contents, SAMPLE_LOG.as_bytes());
+
+    let footerless = decompress(&compressed[..compressed.len() - 8]).unwrap();
+    assert!(footerless.invalid_footer);
+    assert_eq!(footerless.contents, SAMPLE_LOG.as_bytes());
+
+    assert!(decompress(&compressed[..compressed.len() / 2]).is_err());
+}
+
+#[test]
+fn concatenated_members() {
+    use crabtrics::testing::{gzip_compress, SAMPLE_LOG};
+
+    let mut compressed = Vec::new();
+    for line in SAMPLE_LOG.split_inclusive('\n') {
+        compressed.extend(gzip_compress(line.as_bytes()));
+    }
+    let decompressed = decompress(&compressed).unwrap();
+    assert!(!decompressed.invalid_footer);
+    assert_eq!(decompressed.contents, SAMPLE_LOG.as_bytes());
+}
@@ -0,0 +1,192 @@
+use std::ops::RangeInclusive;
+
+use serde::{Deserialize, Serialize};
+
+/// The precision used when `--hll-precision` isn't given, which uses 1 KiB
+/// per sketch for a typical error of about 3%.
+pub const DEFAULT_PRECISION: u8 = 10;
+/// The precisions a sketch can be created with.
+pub const PRECISIONS: RangeInclusive<u8> = 4..=16;
+
+/// A HyperLogLog sketch, which estimates the number of distinct values
+/// inserted into it using a fixed amount of memory.
+///
+/// A sketch has `2^precision` one-byte registers, and its estimates have a
+/// typical relative error of `1.04 / sqrt(2^precision)`. Sketches can be
+/// merged into the sketch of their union, including sketches created with a
+/// different precision, which merge at the lower of the two.
+///
+/// The default sketch has no registers and represents counts that were stored
+/// before distinct values were tracked. It estimates zero, and merging it into
+/// another sketch leaves that sketch unchanged.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HyperLogLog {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    pub fn new(precision: u8) -> Self {
+        assert!(
+            PRECISIONS.contains(&precision),
+            "unsupported hyperloglog precision: {precision}"
+        );
+        Self {
+            precision,
+            registers: vec![0; 1 << precision],
+        }
+    }
+
+    /// Inserts a value by its [`hash`].
+    pub fn insert(&mut self, hash: u64) {
+        // The leading bits select a register, which keeps the longest run of
+        // leading zeros seen in the remaining bits.
+        let remaining_bits = 64 - u32::from(self.precision);
+        let register = &mut self.registers[(hash >> remaining_bits) as usize];
+        let rank = (hash << self.precision).leading_zeros().min(remaining_bits) + 1;
+        *register = (*register).max(rank as u8);
+    }
+
+    /// Returns the estimated number of distinct values inserted.
+    pub fn estimate(&self) -> u64 {
+        if self.registers.is_empty() {
+            return 0;
+        }
+
+        let registers = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1. + 1.079 / registers),
+        };
+        let sum = self
+            .registers
+            .iter()
+            .map(|rank| 2_f64.powi(-i32::from(*rank)))
+            .sum::<f64>();
+        let raw = alpha * registers * registers / sum;
+        let empty = self.registers.iter().filter(|rank| **rank == 0).count();
+        let estimate = if raw <= 2.5 * registers && empty > 0 {
+            // Linear counting is more accurate for small cardinalities.
+            registers * (registers / empty as f64).ln()
+        } else {
+            raw
+        };
+        estimate.round() as u64
+    }
+
+    /// Returns the typical relative error of this sketch's estimates.
+    pub fn relative_error(&self) -> f64 {
+        1.04 / (self.registers.len() as f64).sqrt()
+    }
+
+    /// Adds every value inserted into `other` to `self`.
+    pub fn merge(&mut self, other: &Self) {
+        if other.registers.is_empty() {
+            return;
+        } else if self.registers.is_empty() {
+            *self = other.clone();
+            return;
+        }
+
+        if other.precision < self.precision {
+            *self = self.folded(other.precision);
+        }
+        let folded;
+        let other = if other.precision > self.precision {
+            folded = other.folded(self.precision);
+            &folded
+        } else {
+            other
+        };
+        for (rank, other) in self.registers.iter_mut().zip(&other.registers) {
+            *rank = (*rank).max(*other);
+        }
+    }
+
+    /// Returns the sketch that inserting the same values at the lower
+    /// `precision` would have produced.
+    fn folded(&self, precision: u8) -> Self {
+        let dropped_bits = u32::from(self.precision - precision);
+        let mut folded = Self::new(precision);
+        for (index, rank) in self.registers.iter().enumerate() {
+            if *rank == 0 {
+                continue;
+            }
+            // The dropped index bits become the leading bits that ranks are
+            // counted from.
+            let dropped = index & ((1 << dropped_bits) - 1);
+            let rank = if dropped == 0 {
+                *rank + dropped_bits as u8
+            } else {
+                (dropped_bits - (usize::BITS - dropped.leading_zeros()) + 1) as u8
+            };
+            let register = &mut folded.registers[index >> dropped_bits];
+            *register = (*register).max(rank);
+        }
+        folded
+    }
+}
+
+/// Hashes `bytes` for inserting into a sketch.
+///
+/// Sketches are stored and merged across runs, so this is 64-bit FNV-1a with
+/// SplitMix64's finalizer to spread FNV's low-entropy high bits, rather than
+/// the standard library's hasher whose output may change between Rust
+/// releases.
+pub fn hash(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+#[cfg(test)]
+fn sketch(precision: u8, values: std::ops::Range<u32>) -> HyperLogLog {
+    let mut sketch = HyperLogLog::new(precision);
+    for value in values {
+        sketch.insert(hash(&value.to_le_bytes()));
+    }
+    sketch
+}
+
+#[test]
+fn estimate_error() {
+    for (precision, distinct) in [(4, 50), (10, 500), (10, 100_000), (14, 1_000_000)] {
+        let sketch = sketch(precision, 0..distinct);
+        let error = (sketch.estimate() as f64 - f64::from(distinct)).abs() / f64::from(distinct);
+        // Three standard errors.
+        assert!(
+            error <= 3. * sketch.relative_error(),
+            "precision {precision} estimated {} of {distinct}",
+            sketch.estimate()
+        );
+    }
+    assert_eq!(HyperLogLog::new(10).estimate(), 0);
+    assert_eq!(HyperLogLog::default().estimate(), 0);
+}
+
+#[test]
+fn merging() {
+    let mut merged = sketch(12, 0..6_000);
+    merged.merge(&sketch(12, 4_000..10_000));
+    assert_eq!(merged, sketch(12, 0..10_000));
+
+    let mut mixed = sketch(8, 0..6_000);
+    mixed.merge(&sketch(12, 4_000..10_000));
+    assert_eq!(mixed, sketch(8, 0..10_000));
+    let mut mixed = sketch(12, 0..6_000);
+    mixed.merge(&sketch(8, 4_000..10_000));
+    assert_eq!(mixed, sketch(8, 0..10_000));
+
+    let mut untracked = HyperLogLog::default();
+    untracked.merge(&merged);
+    assert_eq!(untracked, merged);
+    merged.merge(&HyperLogLog::default());
+    assert_eq!(merged, sketch(12, 0..10_000));
+}
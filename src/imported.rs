@@ -0,0 +1,100 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bonsaidb::core::key::time::TimestampAsDays;
+use bonsaidb::core::schema::SerializedCollection;
+use bonsaidb::local::Database;
+use crabtrics::schema::ImportedLog;
+use time::OffsetDateTime;
+
+/// A log file's size and modification time, which tell whether it has changed
+/// since it was imported.
+#[derive(Debug, Clone, Copy, Eq, Hash, PartialEq)]
+pub struct LogVersion {
+    size: u64,
+    modified: u64,
+}
+
+impl LogVersion {
+    pub fn of(path: &Path) -> anyhow::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+        Ok(Self {
+            size: metadata.len(),
+            modified: u64::try_from(modified.as_nanos())?,
+        })
+    }
+}
+
+/// Returns the days the log at `path` had requests on when it was imported,
+/// or None if it hasn't been imported or has changed since.
+pub fn unchanged_days(db: &Database, path: &Path) -> anyhow::Result<Option<BTreeSet<i64>>> {
+    let Some(imported) = ImportedLog::get(&key(path), db)? else {
+        return Ok(None);
+    };
+    let version = LogVersion::of(path)?;
+    Ok(
+        (imported.contents.size == version.size && imported.contents.modified == version.modified)
+            .then_some(imported.contents.days),
+    )
+}
+
+/// Records that the log at `path`, as of `version`, has been imported with
+/// requests on `days`.
+pub fn record(
+    db: &Database,
+    path: &Path,
+    version: LogVersion,
+    days: &BTreeSet<TimestampAsDays>,
+) -> anyhow::Result<()> {
+    ImportedLog {
+        size: version.size,
+        modified: version.modified,
+        days: days
+            .iter()
+            .map(|day| unix_day(*day))
+            .collect::<anyhow::Result<_>>()?,
+    }
+    .overwrite_into(&key(path), db)?;
+    Ok(())
+}
+
+/// Returns the Unix timestamp of the start of `day`.
+pub fn unix_day(day: TimestampAsDays) -> anyhow::Result<i64> {
+    Ok(OffsetDateTime::from(SystemTime::try_from(day)?).unix_timestamp())
+}
+
+fn key(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+#[test]
+fn unchanged_logs() {
+    use crabtrics::testing::memory_database;
+
+    let dir = std::env::temp_dir().join("crabtrics-unchanged-logs");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let log = dir.join("access.log.2.gz");
+    fs::write(&log, "rotated").unwrap();
+
+    let db = memory_database();
+    assert_eq!(unchanged_days(&db, &log).unwrap(), None);
+    let today = TimestampAsDays::now();
+    record(
+        &db,
+        &log,
+        LogVersion::of(&log).unwrap(),
+        &BTreeSet::from([today]),
+    )
+    .unwrap();
+    assert_eq!(
+        unchanged_days(&db, &log).unwrap(),
+        Some(BTreeSet::from([unix_day(today).unwrap()]))
+    );
+
+    fs::write(&log, "rotated again").unwrap();
+    assert_eq!(unchanged_days(&db, &log).unwrap(), None);
+}
@@ -0,0 +1,49 @@
+//! The log analysis behind crabtrics, for embedding in other tools.
+//!
+//! [`access_logs`], [`episodes`], and [`query`] only need bytes to work with.
+//! Nothing in them touches the filesystem or the database so that they can
+//! also be built for `wasm32-unknown-unknown` and used in the browser.
+//!
+//! Everything else needs the filesystem. [`aggregation::aggregate_logs`]
+//! tallies access logs into an [`aggregation::Aggregation`], which
+//! [`aggregation::tally_downloads`] turns into database records, and
+//! [`report::generate_report`] writes the report from them. Records are read
+//! and written through [`store::DownloadStore`], which the BonsaiDB database
+//! implements.
+
+pub mod access_logs;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod aggregation;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod clients;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod config;
+pub mod episodes;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod export;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod geoip;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod hll;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod pages;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod players;
+pub mod query;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod report;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod schema;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod sizes;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod store;
+/// Helpers shared by the tests of the library and the binary, which aren't
+/// built into the library unless the `testing` feature is enabled.
+#[cfg(all(not(target_arch = "wasm32"), any(test, feature = "testing")))]
+#[doc(hidden)]
+pub mod testing;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod visitors;
+#[cfg(target_arch = "wasm32")]
+mod wasm;
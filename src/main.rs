@@ -3,253 +3,1410 @@
 //! - Anonymous metrics over time
 //! - Count number of full downloads of the podcast
 
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::{self, read_dir, File};
-use std::io::{BufReader, Read};
-use std::net::IpAddr;
-use std::path::Path;
+use std::io::{self, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
 use std::time::{Duration, SystemTime};
 
-use askama::Template;
 use bonsaidb::core::connection::Connection;
 use bonsaidb::core::key::time::TimestampAsDays;
 use bonsaidb::core::schema::{SerializedCollection, SerializedView};
-use bonsaidb::core::transaction::{Operation, Transaction};
 use bonsaidb::local::config::{Builder, StorageConfiguration};
 use bonsaidb::local::Database;
-use interner::global::{GlobalPool, GlobalString};
-use libflate::gzip::Decoder;
-use serde::Serialize;
-use time::{OffsetDateTime, Time};
-
-use crate::access_logs::LogReader;
-use crate::schema::{
-    CompleteDownloads, Crabtrics, DateEpisodeKey, DownloadsByDate, EpisodeDateKey, PodcastDownloads,
+use clap::{Parser, Subcommand, ValueEnum};
+use crabtrics::access_logs::LogFormat;
+use crabtrics::aggregation::{
+    aggregate_logs, apply_grace_period, flush_visitor_data, requestor_totals, Aggregation,
+    LineCounts,
 };
+use crabtrics::config::{Config, RowOrder, VisitorDataRetention};
+use crabtrics::export::{days_ago, export_episode_urls, export_json_lines, write_history};
+use crabtrics::hll;
+use crabtrics::report::generate_report;
+use crabtrics::schema::{
+    DateEpisodeKey, DownloadsByDate, EpisodeDateKey, ImportRun, PodcastDownloads, RequestorTotals,
+};
+use crabtrics::sizes::EpisodeSizes;
+use crabtrics::store::DownloadStore;
+use time::format_description::well_known::Rfc3339;
+use time::{Date, OffsetDateTime, Time};
+
+use crate::archive::{export_archive, import_archive};
+
+mod archive;
+mod database;
+mod gzip;
+mod imported;
+#[cfg(feature = "protobuf")]
+mod protobuf;
+mod server;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "statsd")]
+mod statsd;
+
+const DATABASE_PATH: &str = "crabtrics.bonsaidb";
+/// The default logs, episodes, and reports directories on the server.
+const PRODUCTION_DIRECTORIES: [&str; 3] = [
+    "/var/log/nginx",
+    "/home/wotc/episodes",
+    "/home/wotc/episodes/crabtrics",
+];
+/// The default directories with `--stage`, for trying crabtrics out on a copy
+/// of the server's files.
+const STAGE_DIRECTORIES: [&str; 3] = ["stage/nginx", "stage/episodes", "stage/reports"];
+
+#[derive(Debug, Parser)]
+#[command(about)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Exit with an error if the most recent complete day's downloads are
+    /// below this fraction of the trailing average.
+    #[arg(long, value_name = "FRACTION")]
+    fail_on_drop: Option<f64>,
+    /// The number of days before the most recent complete day that make up
+    /// the trailing average for `--fail-on-drop`.
+    #[arg(long, value_name = "DAYS", default_value_t = 7)]
+    drop_lookback_days: u32,
+    /// How to combine newly imported downloads with downloads already stored
+    /// for the same episode and date.
+    #[arg(long, value_enum, default_value_t = ConflictResolution::Overwrite)]
+    on_conflict: ConflictResolution,
+    /// The precision of the sketches estimating distinct visitors, from 4 to
+    /// 16. Each additional bit doubles the sketches' size and divides their
+    /// error by about 1.4. Sketches of different precisions can still be
+    /// combined, at the lower precision.
+    #[arg(
+        long,
+        value_name = "BITS",
+        default_value_t = hll::DEFAULT_PRECISION,
+        value_parser = clap::value_parser!(u8).range(4..=16),
+    )]
+    hll_precision: u8,
+    /// The order of the rows in `downloads.csv`. Both orders are read from an
+    /// index, so neither sorts the records in memory.
+    #[arg(long, value_enum, default_value_t = RowOrder::Episode)]
+    sort: RowOrder,
+    /// The format the access logs were written in.
+    #[arg(long, value_enum, default_value_t = LogFormat::NginxCombined)]
+    format: LogFormat,
+    /// Rebuild the database if its views were indexed by an older version of
+    /// crabtrics, so that every total reflects the current schema. Every
+    /// record is copied into the rebuilt database, so nothing is lost.
+    #[arg(long)]
+    migrate: bool,
+    /// Import every log file, including ones that haven't changed since they
+    /// were last imported.
+    #[arg(long)]
+    reimport: bool,
+    /// Use the episode file sizes recorded by previous imports instead of
+    /// reading the episode files, failing to import any log that requests a
+    /// file whose size wasn't recorded.
+    #[arg(long)]
+    episodes_from_db: bool,
+    /// A directory to import `access.log*` files from, instead of the default
+    /// one. Repeat this to import from several directories at once; a file
+    /// found in more than one of them is only imported once.
+    #[arg(long = "logs", visible_alias = "logs-dir", value_name = "DIR")]
+    log_directories: Vec<PathBuf>,
+    /// Import the logs piped to standard input instead of the log files, such
+    /// as with `zcat access.log.3.gz | crabtrics --stdin`.
+    #[arg(
+        long,
+        conflicts_with_all = ["log_directories", "reimport", "max_files", "tail"],
+    )]
+    stdin: bool,
+    /// The directory episode files are read from, to tell full downloads from
+    /// partial ones.
+    #[arg(long, value_name = "DIR")]
+    episodes_dir: Option<PathBuf>,
+    /// The directory the report is written to.
+    #[arg(long, value_name = "DIR")]
+    reports_dir: Option<PathBuf>,
+    /// Default to the directories in `stage/` instead of the server's. This
+    /// is also the default whenever a `stage` directory exists.
+    #[arg(long)]
+    stage: bool,
+    /// Keep running after the report is generated, importing new log lines
+    /// and regenerating the report every this many seconds until stopped
+    /// with SIGINT or SIGTERM. The report is only regenerated when a log
+    /// changed.
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        conflicts_with = "fail_on_drop",
+        value_parser = clap::value_parser!(u64).range(1..),
+    )]
+    tail: Option<u64>,
+    /// Import at most this many changed logs, oldest first, leaving the rest
+    /// for later runs. Unchanged logs that are imported again because they
+    /// share days with a changed one don't count towards the limit.
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+    )]
+    max_files: Option<usize>,
+    /// Import the logs without writing anything, printing how each record in
+    /// the database would change instead. Like a real import, only requests
+    /// from the last `IMPORT_DAYS` days (14 by default) are considered.
+    /// Combine this with `--stdin` to see what a sample log would count.
+    #[arg(long, conflicts_with_all = ["tail", "migrate"])]
+    dry_run: bool,
+    /// Also store the bytes each visitor downloaded of each episode on each
+    /// day, identified only by their visitor key, so that downloads can be
+    /// classified again once the logs are gone. This makes the database
+    /// considerably larger.
+    #[arg(long)]
+    keep_requestor_totals: bool,
+    /// A MaxMind GeoIP2 or GeoLite2 Country or City database to count each
+    /// episode's full downloads by country with.
+    #[cfg(feature = "geoip")]
+    #[arg(long, value_name = "FILE")]
+    geoip: Option<PathBuf>,
+}
+
+impl Args {
+    /// Returns the directories to import logs from, to read episode files
+    /// from, and to write the report to, falling back to the defaults for
+    /// any that weren't given.
+    fn directories(&self) -> (Vec<PathBuf>, PathBuf, PathBuf) {
+        let [logs, episodes, reports] = if self.stage || Path::new("stage").exists() {
+            STAGE_DIRECTORIES
+        } else {
+            PRODUCTION_DIRECTORIES
+        };
+        let logs = if self.log_directories.is_empty() {
+            vec![PathBuf::from(logs)]
+        } else {
+            self.log_directories.clone()
+        };
+        (
+            logs,
+            self.episodes_dir
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(episodes)),
+            self.reports_dir
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(reports)),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, ValueEnum)]
+enum ConflictResolution {
+    /// Replace the stored downloads, which is correct when reprocessing the
+    /// same logs.
+    Overwrite,
+    /// Add to the stored downloads, for merging logs from separate servers.
+    Sum,
+    /// Keep the larger of each stored and imported count.
+    Max,
+}
 
-mod access_logs;
-mod schema;
+impl ConflictResolution {
+    fn resolve(self, imported: &mut PodcastDownloads, stored: &PodcastDownloads) {
+        match self {
+            Self::Overwrite => {}
+            Self::Sum => imported.accumulate(stored),
+            Self::Max => imported.maximize(stored),
+        }
+    }
+}
+
+/// Commands that run instead of importing logs.
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Compact the database to reclaim disk space.
+    ///
+    /// Only run this while no other crabtrics process has the database open.
+    Compact,
+    /// Write every per-day download record as a line of JSON.
+    ExportJsonLines {
+        /// The file to write to instead of stdout.
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// Only export records whose counts changed after this RFC 3339
+        /// timestamp, such as `2023-06-01T00:00:00Z`.
+        #[arg(long, value_name = "TIMESTAMP", value_parser = parse_timestamp)]
+        changed_since: Option<OffsetDateTime>,
+    },
+    /// List the URL of every episode that has been downloaded, one per line.
+    ExportUrls {
+        /// The file to write to instead of stdout.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Write the entire database to a portable archive that doesn't depend on
+    /// the database's on-disk format.
+    ExportArchive {
+        /// The file to write to instead of stdout.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Write every per-day download record into a SQLite database, with
+    /// indexes by episode and date and an `episode_totals` view.
+    #[cfg(feature = "sqlite")]
+    ExportSqlite {
+        /// The database to create or replace the records of.
+        output: PathBuf,
+    },
+    /// Write every per-day download record as a length-delimited protobuf
+    /// message, following `proto/downloads.proto`.
+    #[cfg(feature = "protobuf")]
+    ExportProtobuf {
+        /// The file to write to instead of stdout.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Write a summary of every run that imported logs as a line of JSON,
+    /// oldest first.
+    History {
+        /// The file to write to instead of stdout.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Restore an archive written by `export-archive` into an empty database.
+    ImportArchive {
+        /// The archive to read.
+        input: PathBuf,
+    },
+    /// Print the downloads of an episode on a day as JSON, or `null` if none
+    /// were recorded.
+    Downloads {
+        episode: u16,
+        /// The day, such as `2023-05-08`.
+        #[arg(value_parser = parse_date)]
+        date: Date,
+    },
+    /// Serve a generated report over HTTP, requiring the username and
+    /// password configured in the `[server]` table.
+    Serve {
+        /// The directory the report was generated in.
+        reports: PathBuf,
+        /// The address to listen on.
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        address: String,
+    },
+}
 
 fn main() -> anyhow::Result<()> {
-    let db = Database::open::<Crabtrics>(StorageConfiguration::new("crabtrics.bonsaidb"))?;
+    let args = Args::parse();
+    let db = database::open(StorageConfiguration::new(DATABASE_PATH), args.migrate)?;
+    let mut config = Config::load(Path::new("crabtrics.toml"))?;
+    config.hll_precision = args.hll_precision;
+    config.csv_order = args.sort;
+    config.log_format = args.format;
+    #[cfg(feature = "geoip")]
+    if let Some(path) = &args.geoip {
+        config.countries = Some(Box::new(crabtrics::geoip::MaxMindCountries::open(path)?));
+    }
+    if let Some(command) = args.command {
+        return run_command(command, &db, &config);
+    }
+
     let days_back: i64 = std::env::var("IMPORT_DAYS")
         .ok()
         .and_then(|days| days.parse().ok())
         .unwrap_or(14);
 
-    let (logs_path, episodes_path, reports_path) = if Path::new("stage").exists() {
-        (
-            Path::new("stage/nginx"),
-            Path::new("stage/episodes"),
-            Path::new("stage/reports"),
-        )
-    } else {
-        (
-            Path::new("/var/log/nginx"),
-            Path::new("/home/wotc/episodes"),
-            Path::new("/home/wotc/episodes/crabtrics"),
-        )
+    let (log_directories, episodes_path, reports_path) = args.directories();
+    anyhow::ensure!(
+        config.visitor_data_retention == VisitorDataRetention::Run
+            || (!config.reconcile_partial_downloads && config.new_episode_grace_minutes == 0),
+        "visitor_data_retention = \"file\" can't be combined with reconcile_partial_downloads or \
+         new_episode_grace_minutes"
+    );
+    let mut import = LogImport {
+        log_directories,
+        stdin: args.stdin,
+        episodes: (!args.episodes_from_db).then_some(episodes_path.as_path()),
+        days_back,
+        reimport: args.reimport,
+        on_conflict: args.on_conflict,
+        max_files: args.max_files,
+        dry_run: args.dry_run,
+        keep_requestor_totals: args.keep_requestor_totals,
     };
+    import.run(&db, &config)?;
+    if args.dry_run {
+        return Ok(());
+    }
+    publish_report(&db, &config, &reports_path)?;
 
-    let mut aggregation = HashMap::new();
-    let threshold =
-        OffsetDateTime::now_utc().replace_time(Time::MIDNIGHT) - time::Duration::days(days_back);
-    for entry in read_dir(logs_path)? {
-        let Ok(entry) = entry else { continue };
-        let file_name = entry.file_name();
-        let Some(file_name) = file_name.to_str() else {
-            continue;
-        };
-        if file_name.starts_with("access.log") {
-            println!("Importing {file_name}");
-            let file = BufReader::new(File::open(&entry.path())?);
-
-            if file_name.ends_with(".gz") {
-                aggregate_logs(
-                    Decoder::new(file)?,
-                    &mut aggregation,
-                    episodes_path,
-                    threshold,
-                )?;
-            } else {
-                aggregate_logs(file, &mut aggregation, episodes_path, threshold)?;
+    if let Some(fraction) = args.fail_on_drop {
+        check_for_drop(&db, fraction, args.drop_lookback_days)?;
+    }
+
+    if let Some(interval) = args.tail {
+        let (stop, stopped) = mpsc::channel();
+        ctrlc::set_handler(move || {
+            let _ = stop.send(());
+        })?;
+        import.reimport = false;
+        println!("Importing new log lines every {interval} seconds");
+        tail(Duration::from_secs(interval), &stopped, || {
+            if import.run(&db, &config)? {
+                publish_report(&db, &config, &reports_path)?;
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+/// Where logs are imported from, and how.
+struct LogImport<'a> {
+    log_directories: Vec<PathBuf>,
+    /// Whether to import standard input instead of the log directories.
+    stdin: bool,
+    /// The directory to read episode sizes from, or None to only use the
+    /// sizes recorded by previous imports.
+    episodes: Option<&'a Path>,
+    /// How many days before today requests are counted from.
+    days_back: i64,
+    /// Whether to import logs that haven't changed since they were last
+    /// imported.
+    reimport: bool,
+    on_conflict: ConflictResolution,
+    /// The most changed logs to import, oldest first, or None to import all
+    /// of them.
+    max_files: Option<usize>,
+    /// Whether to print how the stored records would change instead of
+    /// writing anything.
+    dry_run: bool,
+    /// Whether to store each visitor's bytes as [`RequestorTotals`].
+    keep_requestor_totals: bool,
+}
+
+impl LogImport<'_> {
+    /// Imports every log that changed since it was last imported, along with
+    /// any unchanged log that has requests on the same days, or standard input
+    /// instead. Returns false if there was nothing to import.
+    ///
+    /// Each run that imports anything is recorded as an [`ImportRun`], unless
+    /// it's a dry run.
+    fn run(&self, db: &Database, config: &Config) -> anyhow::Result<bool> {
+        let started_at = OffsetDateTime::now_utc();
+        let mut aggregation = Aggregation::default();
+        let threshold = OffsetDateTime::now_utc().replace_time(Time::MIDNIGHT)
+            - time::Duration::days(self.days_back);
+        let mut sizes = EpisodeSizes::load(db, self.episodes)?;
+        let retain_per_file = config.visitor_data_retention == VisitorDataRetention::File;
+        let mut tallied = HashMap::new();
+        let mut kept_totals = HashMap::new();
+        let mut imported_logs = Vec::new();
+        let mut files = Vec::new();
+        let mut lines = LineCounts::default();
+        if self.stdin {
+            println!("Importing standard input");
+            lines = aggregate_logs(
+                io::stdin().lock(),
+                &mut aggregation,
+                &mut sizes,
+                threshold,
+                config,
+            )?;
+            files.push(String::from("<stdin>"));
+        } else {
+            // Unchanged logs are skipped unless they have requests on a day
+            // that another log is being imported for, since each day's
+            // downloads are recounted from every log that has requests on it.
+            let mut unchanged = Vec::new();
+            let mut pending = Vec::new();
+            for path in log_files(&self.log_directories)? {
+                match imported::unchanged_days(db, &path) {
+                    Ok(Some(days)) if !self.reimport => unchanged.push((path, days)),
+                    _ => pending.push(path),
+                }
+            }
+            if pending.is_empty() {
+                return Ok(false);
             }
+            // Sessions are split in the order requests are read, so logs are
+            // read oldest first.
+            sort_oldest_first(&mut pending);
+            if let Some(max_files) = self.max_files {
+                if pending.len() > max_files {
+                    println!(
+                        "Leaving {} changed log files for the next run",
+                        pending.len() - max_files
+                    );
+                    pending.truncate(max_files);
+                }
+            }
+
+            let mut imported_days = BTreeSet::new();
+            let mut read = Vec::new();
+            while !pending.is_empty() {
+                for path in pending.drain(..) {
+                    println!("Importing {}", path.display());
+                    let version = imported::LogVersion::of(&path);
+                    match import_log_file(&path, &mut aggregation, &mut sizes, threshold, config) {
+                        Ok((days, file_lines)) => {
+                            for day in &days {
+                                imported_days.insert(imported::unix_day(*day)?);
+                            }
+                            files.push(path.display().to_string());
+                            lines.read += file_lines.read;
+                            lines.counted += file_lines.counted;
+                            lines.malformed += file_lines.malformed;
+                            if let Ok(version) = version {
+                                imported_logs.push((path.clone(), version, days));
+                            }
+                        }
+                        Err(err) => eprintln!(
+                            "Skipping {}, none of its downloads were counted: {err}",
+                            path.display()
+                        ),
+                    }
+                    if retain_per_file {
+                        self.keep_totals(&aggregation, &mut kept_totals, config);
+                        flush_visitor_data(&mut aggregation, &mut tallied, config);
+                    }
+                    read.push(path);
+                }
+                let (overlapping, disjoint) = unchanged
+                    .into_iter()
+                    .partition::<Vec<_>, _>(|(_, days)| !days.is_disjoint(&imported_days));
+                unchanged = disjoint;
+                if overlapping.is_empty() {
+                    break;
+                }
+                pending.extend(overlapping.into_iter().map(|(path, _)| path));
+                if !retain_per_file {
+                    // The unchanged logs are usually older than the ones
+                    // already read, so everything is read again in order
+                    // rather than splitting their sessions out of order.
+                    aggregation = Aggregation::default();
+                    files.clear();
+                    lines = LineCounts::default();
+                    imported_logs.clear();
+                    pending.append(&mut read);
+                    sort_oldest_first(&mut pending);
+                }
+            }
+            if !unchanged.is_empty() {
+                println!("Skipped {} unchanged log files", unchanged.len());
+            }
+        }
+        if lines.malformed > 0 {
+            eprintln!("Skipped {} malformed log lines", lines.malformed);
+        }
+
+        if !self.dry_run {
+            sizes.save(db)?;
+        }
+
+        if config.new_episode_grace_minutes > 0 {
+            let mut first_seen = database::first_seen(db)?;
+            apply_grace_period(&mut aggregation, &mut first_seen, config)?;
+            if !self.dry_run {
+                database::set_first_seen(db, &first_seen)?;
+            }
+        }
+        self.keep_totals(&aggregation, &mut kept_totals, config);
+        flush_visitor_data(&mut aggregation, &mut tallied, config);
+        if self.dry_run {
+            print_changes(&diff_downloads(db, tallied, self.on_conflict)?)?;
+            return Ok(true);
+        }
+        let records_written = write_downloads(db, tallied, self.on_conflict)?;
+        if self.keep_requestor_totals {
+            database::write_requestor_totals(
+                db,
+                kept_totals,
+                self.on_conflict == ConflictResolution::Sum,
+            )?;
+        }
+        // Logs are only recorded once their downloads are written, so a failed
+        // run imports them again.
+        for (path, version, days) in imported_logs {
+            imported::record(db, &path, version, &days)?;
+        }
+        ImportRun {
+            started_at: started_at.unix_timestamp(),
+            version: String::from(env!("CARGO_PKG_VERSION")),
+            files,
+            lines_read: lines.read,
+            lines_skipped: lines.read - lines.counted,
+            records_written,
+        }
+        .push_into(db)?;
+        db.compact()?;
+        Ok(true)
+    }
+
+    /// Adds the requestor totals of `aggregation` to `kept_totals`, if they're
+    /// being kept.
+    fn keep_totals(
+        &self,
+        aggregation: &Aggregation,
+        kept_totals: &mut HashMap<EpisodeDateKey, RequestorTotals>,
+        config: &Config,
+    ) {
+        if !self.keep_requestor_totals {
+            return;
+        }
+        for (key, day) in requestor_totals(aggregation, config) {
+            kept_totals.entry(key).or_default().merge(day);
         }
     }
+}
+
+/// Generates the report and sends its metrics.
+fn publish_report(db: &Database, config: &Config, reports_path: &Path) -> anyhow::Result<()> {
+    generate_report(db, config, reports_path)?;
+    #[cfg(feature = "statsd")]
+    if let Some(host) = &config.statsd.host {
+        if let Err(err) = statsd::send_episode_totals(db, host, &config.statsd.prefix) {
+            eprintln!("Warning: couldn't send metrics to {host}: {err}");
+        }
+    }
+    Ok(())
+}
+
+/// Calls `update` every `interval` until a message is received on `stop`,
+/// or every sender to it is dropped. An update that is running when the
+/// message arrives is finished first, and one that fails is retried at the
+/// next interval.
+fn tail(interval: Duration, stop: &Receiver<()>, mut update: impl FnMut() -> anyhow::Result<()>) {
+    loop {
+        match stop.recv_timeout(interval) {
+            Err(RecvTimeoutError::Timeout) => {}
+            Ok(()) | Err(RecvTimeoutError::Disconnected) => return,
+        }
+        if let Err(err) = update() {
+            eprintln!("Warning: couldn't update the report: {err}");
+        }
+    }
+}
+
+fn write_downloads(
+    store: &dyn DownloadStore,
+    downloads: HashMap<EpisodeDateKey, PodcastDownloads>,
+    on_conflict: ConflictResolution,
+) -> anyhow::Result<u32> {
+    let written_at = OffsetDateTime::now_utc().unix_timestamp();
+    let mut changed = Vec::new();
+    for (key, mut downloads) in downloads {
+        if let Some(stored) = store.get(&key)? {
+            on_conflict.resolve(&mut downloads, &stored);
+            // Leave unchanged records alone so that they aren't exported as
+            // changed.
+            downloads.written_at = stored.written_at;
+            if downloads == stored {
+                continue;
+            }
+        }
+        downloads.written_at = written_at;
+        changed.push((key, downloads));
+    }
+    store.overwrite_all(&changed)?;
+    Ok(changed.len().try_into()?)
+}
+
+/// How importing a record would change the one stored for its episode and
+/// date.
+#[derive(Debug, Eq, PartialEq)]
+enum RecordChange {
+    /// Nothing is stored yet.
+    Added {
+        full: u16,
+        partial: u16,
+    },
+    /// The stored record would be overwritten, changing its downloads by
+    /// these amounts. Other fields may have changed even if both are 0.
+    Changed {
+        full: i32,
+        partial: i32,
+    },
+    Unchanged,
+}
 
-    let mut tx = Transaction::new();
-    for (key, info) in aggregation {
-        let mut partial_downloads = 0;
-        let mut full_downloads = 0;
-        for visitor in info.bytes_per_requestor.into_values() {
-            for (kind, bytes) in visitor {
-                if bytes >= *info.sizes.get(&kind).expect("size not computed") {
-                    full_downloads += 1;
+/// Compares `downloads` with the stored records, resolving conflicts the same
+/// way [`write_downloads`] would.
+fn diff_downloads(
+    store: &dyn DownloadStore,
+    downloads: HashMap<EpisodeDateKey, PodcastDownloads>,
+    on_conflict: ConflictResolution,
+) -> anyhow::Result<BTreeMap<EpisodeDateKey, RecordChange>> {
+    let mut changes = BTreeMap::new();
+    for (key, mut downloads) in downloads {
+        let change = match store.get(&key)? {
+            Some(stored) => {
+                on_conflict.resolve(&mut downloads, &stored);
+                downloads.written_at = stored.written_at;
+                if downloads == stored {
+                    RecordChange::Unchanged
                 } else {
-                    partial_downloads += 1;
+                    RecordChange::Changed {
+                        full: i32::from(downloads.full_downloads)
+                            - i32::from(stored.full_downloads),
+                        partial: i32::from(downloads.partial_downloads)
+                            - i32::from(stored.partial_downloads),
+                    }
                 }
             }
+            None => RecordChange::Added {
+                full: downloads.full_downloads,
+                partial: downloads.partial_downloads,
+            },
+        };
+        changes.insert(key, change);
+    }
+    Ok(changes)
+}
+
+fn print_changes(changes: &BTreeMap<EpisodeDateKey, RecordChange>) -> anyhow::Result<()> {
+    let mut unchanged = 0;
+    for (key, change) in changes {
+        let record = format!("Episode {} on {}", key.episode, format_date(key.date)?);
+        match change {
+            RecordChange::Added { full, partial } => {
+                println!("{record}: added with {full} full and {partial} partial downloads");
+            }
+            RecordChange::Changed { full, partial } => {
+                println!("{record}: {full:+} full and {partial:+} partial downloads");
+            }
+            RecordChange::Unchanged => unchanged += 1,
         }
+    }
+    println!(
+        "{} records would change, {unchanged} unchanged",
+        changes.len() - unchanged
+    );
+    Ok(())
+}
 
-        tx.push(Operation::overwrite_serialized::<PodcastDownloads, _>(
-            &key,
-            &PodcastDownloads {
-                full_downloads,
-                partial_downloads,
-            },
-        )?);
+fn run_command(command: Command, db: &Database, config: &Config) -> anyhow::Result<()> {
+    match command {
+        Command::Compact => compact(db, Path::new(DATABASE_PATH)),
+        Command::ExportJsonLines {
+            output,
+            changed_since,
+        } => export_json_lines(db, changed_since, open_output(output)?),
+        Command::ExportUrls { output } => {
+            export_episode_urls(db, &config.episode_url_template, open_output(output)?)
+        }
+        Command::ExportArchive { output } => export_archive(db, open_output(output)?),
+        Command::History { output } => write_history(db, open_output(output)?),
+        #[cfg(feature = "sqlite")]
+        Command::ExportSqlite { output } => {
+            let exported = sqlite::export_sqlite(db, &output)?;
+            println!("Exported {exported} records to {}", output.display());
+            Ok(())
+        }
+        #[cfg(feature = "protobuf")]
+        Command::ExportProtobuf { output } => {
+            protobuf::export_protobuf(db, open_output(output)?)?;
+            Ok(())
+        }
+        Command::ImportArchive { input } => {
+            let imported = import_archive(db, BufReader::new(File::open(input)?))?;
+            println!("Imported {imported} records");
+            Ok(())
+        }
+        Command::Downloads { episode, date } => {
+            let date = TimestampAsDays::try_from(SystemTime::from(date.midnight().assume_utc()))?;
+            let downloads = database::downloads_on(db, episode, date)?;
+            println!("{}", serde_json::to_string(&downloads)?);
+            Ok(())
+        }
+        Command::Serve { reports, address } => {
+            let credentials = server::Credentials::load(&config.server)?;
+            server::serve(&address, &reports, &config.report_files, &credentials)
+        }
     }
-    tx.apply(&db)?;
-    db.compact()?;
+}
 
-    generate_report(&db, reports_path)
+fn parse_timestamp(timestamp: &str) -> Result<OffsetDateTime, time::error::Parse> {
+    OffsetDateTime::parse(timestamp, &Rfc3339)
 }
 
-static STRINGS: GlobalPool<String> = GlobalPool::new();
+fn parse_date(date: &str) -> Result<Date, time::error::Parse> {
+    Date::parse(
+        date,
+        time::macros::format_description!("[year]-[month]-[day]"),
+    )
+}
 
-#[derive(Debug, Default)]
-struct EpisodeDownloads {
-    bytes_per_requestor: HashMap<IpAddr, HashMap<GlobalString, u32>>,
-    sizes: HashMap<GlobalString, u32>,
+/// Opens `path` for writing, or stdout if no path is given.
+fn open_output(path: Option<PathBuf>) -> io::Result<Box<dyn Write>> {
+    Ok(match path {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout().lock()),
+    })
 }
 
-fn aggregate_logs<R: Read>(
-    source: R,
-    aggregation: &mut HashMap<EpisodeDateKey, EpisodeDownloads>,
-    episodes_path: &Path,
-    threshold: OffsetDateTime,
-) -> anyhow::Result<()> {
-    let mut logs = LogReader::new(source);
-    while let Some(log) = logs.read_one()? {
-        // Filter errors.
-        if log.response_code < 200 || log.response_code > 299 || log.method != "GET" {
-            continue;
-        }
-        if log.time < threshold {
-            continue;
-        }
-        // Filter old logs we've already aggreg
-        // Find files matching /episode-{number}.{extension}.
-        let Some(file) = log
-            .path
-            .strip_prefix("/episode-")
-            .or_else(|| log.path.strip_prefix("/way_of_the_crab_"))
-        else {
-            continue;
-        };
-        let Some((episode, extension)) = file.split_once('.') else {
-            continue;
-        };
-        assert_eq!(extension, "m4a", "need to support counting sizes by type");
-        let episode = episode
-            .split_once(['_', '-'])
-            .map_or(episode, |(episode, _)| episode);
-        let Ok(episode): Result<u16, _> = episode.parse() else {
-            continue;
+fn compact(db: &Database, path: &Path) -> anyhow::Result<()> {
+    let before = directory_size(path)?;
+    db.compact()?;
+    let after = directory_size(path)?;
+    println!("Compacted database from {before} bytes to {after} bytes");
+    Ok(())
+}
+
+fn directory_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            directory_size(&entry.path())?
+        } else {
+            metadata.len()
         };
+    }
+    Ok(size)
+}
 
-        let episode_downloads = aggregation
-            .entry(EpisodeDateKey {
-                episode,
-                date: TimestampAsDays::try_from(SystemTime::from(log.time))?,
-            })
-            .or_default();
-
-        let extension = STRINGS.get(extension);
-        // Lookup the file size to be able to compute complete downloads.
-        if !episode_downloads.sizes.contains_key(&extension) {
-            let stat = fs::metadata(episodes_path.join(&log.path[1..]))?;
-            episode_downloads
-                .sizes
-                .insert(extension.clone(), stat.len().try_into()?);
-        }
-
-        *episode_downloads
-            .bytes_per_requestor
-            .entry(log.requestor)
-            .or_default()
-            .entry(extension)
-            .or_default() += log.bytes_sent;
+/// Returns an error if yesterday's downloads are less than `fraction` of the
+/// average of the `lookback_days` days before it.
+///
+/// Today is excluded because its logs are still being written. Days without
+/// any downloads count as zero.
+fn check_for_drop(db: &Database, fraction: f64, lookback_days: u32) -> anyhow::Result<()> {
+    let mut daily_totals = BTreeMap::new();
+    for mapping in DownloadsByDate::entries(db)
+        .with_key_range(DateEpisodeKey::range_starting_at(days_ago(
+            lookback_days + 1,
+        )?))
+        .query()?
+    {
+        *daily_totals.entry(mapping.key.date).or_insert(0) += mapping.value;
+    }
+
+    let latest = daily_totals.get(&days_ago(1)?).copied().unwrap_or(0);
+    let mut trailing = Vec::new();
+    for days in 2..=lookback_days + 1 {
+        trailing.push(daily_totals.get(&days_ago(days)?).copied().unwrap_or(0));
+    }
+    if let Some(average) = downloads_dropped(latest, &trailing, fraction) {
+        anyhow::bail!(
+            "downloads dropped to {latest} yesterday from a trailing average of {average:.1}"
+        );
     }
     Ok(())
 }
 
-#[derive(Debug, Serialize, Template)]
-#[template(path = "index.html")]
-struct Report {
-    episode_downloads: Vec<EpisodeReport>,
-    recent_downloads: BTreeMap<String, RecentDownloads>,
-    latest_episode: u16,
-}
-
-#[derive(Debug, Serialize)]
-struct EpisodeReport {
-    number: u16,
-    downloads: u32,
-}
-
-#[derive(Debug, Serialize, Default)]
-struct RecentDownloads {
-    episodes: BTreeMap<u16, u32>,
-}
-
-fn generate_report(db: &Database, export_dir: &Path) -> anyhow::Result<()> {
-    fs::create_dir_all(export_dir)?;
-    let mut csv = csv::Writer::from_path(export_dir.join("downloads.csv"))?;
-    csv.write_record(["date", "episode", "full", "partial"])?;
-    let mut manual_aggregation = HashMap::new();
-    for dl in PodcastDownloads::all(db).query()? {
-        let date = time::OffsetDateTime::from(SystemTime::try_from(dl.header.id.date)?);
-        let date = format!("{:04}-{:02}-{:02}", date.year(), date.month(), date.day());
-        csv.write_record([
-            &date,
-            &dl.header.id.episode.to_string(),
-            &dl.contents.full_downloads.to_string(),
-            &dl.contents.partial_downloads.to_string(),
-        ])?;
-        *manual_aggregation.entry(dl.header.id.episode).or_insert(0) += dl.contents.full_downloads;
-    }
-    csv.flush()?;
-    drop(csv);
-
-    let mut episode_downloads = Vec::new();
-    for mapping in CompleteDownloads::entries(db).reduce_grouped()? {
-        episode_downloads.push(EpisodeReport {
-            number: mapping.key,
-            downloads: mapping.value,
-        });
+/// Returns the average of `trailing` if `latest` is below `fraction` of it.
+fn downloads_dropped(latest: u32, trailing: &[u32], fraction: f64) -> Option<f64> {
+    if trailing.is_empty() {
+        return None;
     }
 
-    let mut recent_downloads = BTreeMap::default();
-    let recent_start =
-        SystemTime::try_from(TimestampAsDays::now())? - Duration::from_secs(8 * 24 * 60 * 60);
-    let dl_query = DownloadsByDate::entries(db)
-        .with_key_range(DateEpisodeKey::range_starting_at(
-            TimestampAsDays::try_from(recent_start)?,
-        ))
-        .query()?;
-    // Gather all the episode numbers to ensure every entry is complete
-    let mut latest_episode = 0;
-    for mapping in dl_query {
-        latest_episode = latest_episode.max(mapping.key.episode);
-        let date = OffsetDateTime::from(SystemTime::try_from(mapping.key.date)?);
-        let for_date = recent_downloads
-            .entry(format!(
-                "{:04}-{:02}-{:02}",
-                date.year(),
-                date.month() as u8,
-                date.day()
-            ))
-            .or_insert_with(RecentDownloads::default);
-        for_date.episodes.insert(mapping.key.episode, mapping.value);
-    }
-
-    let rendered = Report {
-        episode_downloads,
-        recent_downloads,
-        latest_episode,
-    }
-    .render()?;
-    fs::write(export_dir.join("index.html"), rendered.as_bytes())?;
-    Ok(())
+    let average = f64::from(trailing.iter().sum::<u32>()) / trailing.len() as f64;
+    (f64::from(latest) < average * fraction).then_some(average)
+}
+
+/// Sorts `paths` by when each file was last modified, oldest first. Files
+/// whose modification time can't be read come first.
+fn sort_oldest_first(paths: &mut [PathBuf]) {
+    paths.sort_by_key(|path| {
+        fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    });
+}
+
+/// Returns every `access.log*` file in `directories`, leaving out files with
+/// the same contents as one found earlier, such as a log copied into a backup
+/// directory that is also being imported.
+///
+/// A file found twice, such as through a symlinked directory, is left out by
+/// its canonical path. Otherwise files are only read and compared by a hash of
+/// their contents when their size and modification time match, as they do for
+/// a copy that kept its timestamps. A file that can't be read is still
+/// returned, so that importing it reports the error.
+fn log_files(directories: &[PathBuf]) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut canonical_paths = HashSet::new();
+    // The files found with each size and modification time, along with a hash
+    // of their contents once it's needed.
+    let mut versions: HashMap<imported::LogVersion, Vec<(PathBuf, Option<Option<u64>>)>> =
+        HashMap::new();
+    let hash = |path: &Path| fs::read(path).ok().map(|contents| hll::hash(&contents));
+    for directory in directories {
+        for entry in read_dir(directory)? {
+            let Ok(entry) = entry else { continue };
+            let file_name = entry.file_name();
+            if !file_name
+                .to_str()
+                .is_some_and(|name| name.starts_with("access.log"))
+            {
+                continue;
+            }
+
+            let path = entry.path();
+            if let Ok(canonical) = fs::canonicalize(&path) {
+                if !canonical_paths.insert(canonical) {
+                    println!("Skipping {}, which was already found", path.display());
+                    continue;
+                }
+            }
+            if let Ok(version) = imported::LogVersion::of(&path) {
+                let candidates = versions.entry(version).or_default();
+                if !candidates.is_empty() {
+                    let contents = hash(&path);
+                    let original = candidates.iter_mut().find_map(|(original, hashed)| {
+                        let hashed = *hashed.get_or_insert_with(|| hash(original));
+                        (contents.is_some() && hashed == contents).then_some(original)
+                    });
+                    if let Some(original) = original {
+                        println!(
+                            "Skipping {}, which has the same contents as {}",
+                            path.display(),
+                            original.display()
+                        );
+                        continue;
+                    }
+                }
+                candidates.push((path.clone(), None));
+            }
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// Aggregates a single log file into `aggregation`.
+///
+/// The file is aggregated into a staging map that is only merged once the
+/// entire file has been read. The staging map continues each requestor's
+/// session from the files already aggregated, so logs read in order split
+/// sessions as if they were one log. Compressed logs are fully decompressed
+/// before any of their entries are counted, so a file that is truncated
+/// mid-stream contributes nothing rather than part of its downloads. A file
+/// missing only its gzip footer is still counted, with a warning.
+///
+/// Returns the days the file had requests counted on, and how many of its
+/// lines were counted.
+fn import_log_file(
+    path: &Path,
+    aggregation: &mut Aggregation,
+    sizes: &mut EpisodeSizes,
+    threshold: OffsetDateTime,
+    config: &Config,
+) -> anyhow::Result<(BTreeSet<TimestampAsDays>, LineCounts)> {
+    let mut staging = aggregation.continuing_sessions();
+    let lines = if path.extension().is_some_and(|ext| ext == "gz") {
+        let decompressed = gzip::decompress(&fs::read(path)?)?;
+        if decompressed.invalid_footer {
+            eprintln!(
+                "Warning: {} has a missing or invalid gzip footer, counting its decompressed \
+                 contents anyway",
+                path.display()
+            );
+        }
+        aggregate_logs(
+            decompressed.contents.as_slice(),
+            &mut staging,
+            sizes,
+            threshold,
+            config,
+        )?
+    } else {
+        let file = BufReader::new(File::open(path)?);
+        aggregate_logs(file, &mut staging, sizes, threshold, config)?
+    };
+
+    let days = staging.days();
+    aggregation.merge(staging);
+    Ok((days, lines))
+}
+
+#[cfg(test)]
+use crabtrics::aggregation::tally_downloads;
+#[cfg(test)]
+use crabtrics::export::DAY;
+#[cfg(test)]
+use crabtrics::testing::{
+    gzip_compress, insert_downloads, memory_database, test_episodes_dir, SAMPLE_LOG,
+};
+
+#[cfg(test)]
+impl<'a> LogImport<'a> {
+    /// Imports every changed log in `log_directory`, as far back as they go,
+    /// with the episode sizes in `episodes`.
+    fn for_test(log_directory: &Path, episodes: &'a Path) -> Self {
+        Self {
+            log_directories: vec![log_directory.to_path_buf()],
+            stdin: false,
+            episodes: Some(episodes),
+            days_back: 100_000,
+            reimport: false,
+            on_conflict: ConflictResolution::Overwrite,
+            max_files: None,
+            dry_run: false,
+            keep_requestor_totals: false,
+        }
+    }
+}
+
+#[test]
+fn truncated_gzip_is_not_aggregated() {
+    let dir = test_episodes_dir("truncated-gzip", 213_001);
+    let compressed = gzip_compress(SAMPLE_LOG.as_bytes());
+
+    let intact = dir.join("access.log.2.gz");
+    fs::write(&intact, &compressed).unwrap();
+    let truncated = dir.join("access.log.3.gz");
+    fs::write(&truncated, &compressed[..compressed.len() / 2]).unwrap();
+
+    let mut aggregation = Aggregation::default();
+    assert!(import_log_file(
+        &truncated,
+        &mut aggregation,
+        &mut EpisodeSizes::from_directory(&dir),
+        OffsetDateTime::UNIX_EPOCH,
+        &Config::default(),
+    )
+    .is_err());
+    assert!(aggregation.days().is_empty());
+
+    import_log_file(
+        &intact,
+        &mut aggregation,
+        &mut EpisodeSizes::from_directory(&dir),
+        OffsetDateTime::UNIX_EPOCH,
+        &Config::default(),
+    )
+    .unwrap();
+    let tallied = tally_downloads(aggregation, &Config::default());
+    assert_eq!(tallied.len(), 1);
+    let downloads = tallied.values().next().unwrap();
+    assert_eq!(downloads.full_downloads, 1);
+    assert_eq!(downloads.partial_downloads, 0);
+}
+
+#[test]
+fn drop_detection() {
+    let db = memory_database();
+    let seed = |days: u32, full_downloads: u16| {
+        insert_downloads(
+            &db,
+            1,
+            days_ago(days).unwrap(),
+            PodcastDownloads {
+                full_downloads,
+                ..PodcastDownloads::default()
+            },
+        );
+    };
+    for days in 2..=8 {
+        seed(days, 100);
+    }
+    seed(1, 90);
+    check_for_drop(&db, 0.5, 7).unwrap();
+
+    seed(1, 10);
+    assert!(check_for_drop(&db, 0.5, 7).is_err());
+}
+
+#[test]
+fn log_files_across_directories() {
+    let root = std::env::temp_dir().join("crabtrics-log-files");
+    let _ = fs::remove_dir_all(&root);
+    let live = root.join("nginx");
+    let backup = root.join("backup");
+    fs::create_dir_all(&live).unwrap();
+    fs::create_dir_all(&backup).unwrap();
+    fs::write(live.join("access.log"), "today").unwrap();
+    fs::write(live.join("access.log.1"), "yesterday").unwrap();
+    fs::write(live.join("error.log"), "errors").unwrap();
+    fs::write(backup.join("access.log.1"), "yesterday").unwrap();
+    fs::write(backup.join("access.log.7"), "last week").unwrap();
+    // The backup was copied with its timestamp, and another log happens to
+    // have the same size and timestamp without having the same contents.
+    for path in [
+        live.join("access.log.1"),
+        backup.join("access.log.1"),
+        backup.join("access.log.7"),
+    ] {
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + DAY)
+            .unwrap();
+    }
+
+    let files = log_files(&[live.clone(), backup.clone()]).unwrap();
+    assert_eq!(files.len(), 3);
+    assert!(files.contains(&live.join("access.log")));
+    assert!(files.contains(&backup.join("access.log.7")));
+    assert_eq!(
+        files
+            .iter()
+            .filter(|file| file.file_name().unwrap() == "access.log.1")
+            .count(),
+        1
+    );
+}
+
+#[test]
+fn directory_arguments() {
+    let args = Args::parse_from(["crabtrics", "--stage", "--reports-dir", "out"]);
+    assert_eq!(
+        args.directories(),
+        (
+            vec![PathBuf::from("stage/nginx")],
+            PathBuf::from("stage/episodes"),
+            PathBuf::from("out")
+        )
+    );
+
+    let args = Args::parse_from([
+        "crabtrics",
+        "--logs-dir",
+        "/srv/logs",
+        "--logs",
+        "/srv/backup",
+        "--episodes-dir",
+        "/srv/episodes",
+        "--reports-dir",
+        "/srv/reports",
+    ]);
+    assert_eq!(
+        args.directories(),
+        (
+            vec![PathBuf::from("/srv/logs"), PathBuf::from("/srv/backup")],
+            PathBuf::from("/srv/episodes"),
+            PathBuf::from("/srv/reports")
+        )
+    );
+}
+
+#[test]
+fn tailing_logs() {
+    let root = std::env::temp_dir().join("crabtrics-tailing-logs");
+    let _ = fs::remove_dir_all(&root);
+    let logs = root.join("nginx");
+    let reports = root.join("reports");
+    fs::create_dir_all(&logs).unwrap();
+    fs::write(logs.join("access.log"), SAMPLE_LOG).unwrap();
+    let episodes = test_episodes_dir("tailing-logs-episodes", 213_001);
+    let db = memory_database();
+    let config = Config::default();
+    let import = LogImport {
+        keep_requestor_totals: true,
+        ..LogImport::for_test(&logs, &episodes)
+    };
+    let total_downloads = || {
+        let report: serde_json::Value =
+            serde_json::from_slice(&fs::read(reports.join("report.json")).unwrap()).unwrap();
+        report["episode_downloads"][0]["downloads"].clone()
+    };
+
+    assert!(import.run(&db, &config).unwrap());
+    publish_report(&db, &config, &reports).unwrap();
+    assert_eq!(total_downloads(), 1);
+    assert!(!import.run(&db, &config).unwrap());
+
+    // Another listener downloads the episode while crabtrics is running.
+    fs::OpenOptions::new()
+        .append(true)
+        .open(logs.join("access.log"))
+        .unwrap()
+        .write_all(
+            SAMPLE_LOG
+                .replace("172.56.208.121", "172.56.209.122")
+                .as_bytes(),
+        )
+        .unwrap();
+    let (stop, stopped) = mpsc::channel();
+    let mut updates = 0;
+    tail(Duration::from_millis(10), &stopped, || {
+        updates += 1;
+        if import.run(&db, &config)? {
+            publish_report(&db, &config, &reports)?;
+            stop.send(())?;
+        }
+        Ok(())
+    });
+    assert_eq!(updates, 1);
+    assert_eq!(total_downloads(), 2);
+    // The day's totals are replaced along with its downloads.
+    let kept = RequestorTotals::all(&db).query().unwrap();
+    assert_eq!(kept.len(), 1);
+    assert_eq!(kept[0].contents.requestors.len(), 2);
+}
+
+#[test]
+fn import_runs() {
+    let logs = std::env::temp_dir().join("crabtrics-import-runs");
+    let _ = fs::remove_dir_all(&logs);
+    fs::create_dir_all(&logs).unwrap();
+    fs::write(
+        logs.join("access.log"),
+        format!(
+            "{SAMPLE_LOG}172.56.208.121 - - [08/May/2023:15:08:31 +0000] \"GET /feed.xml \
+             HTTP/1.1\" 200 512 \"-\" \"Overcast/3.0\"\n"
+        ),
+    )
+    .unwrap();
+    let episodes = test_episodes_dir("import-runs-episodes", 213_001);
+    let db = memory_database();
+    let import = LogImport::for_test(&logs, &episodes);
+    assert!(import.run(&db, &Config::default()).unwrap());
+    // Runs that find nothing to import aren't recorded.
+    assert!(!import.run(&db, &Config::default()).unwrap());
+
+    let runs = ImportRun::all(&db).query().unwrap();
+    assert_eq!(runs.len(), 1);
+    let run = &runs[0].contents;
+    assert!(run.started_at > 1_686_000_000);
+    assert_eq!(run.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(run.files, [logs.join("access.log").display().to_string()]);
+    assert_eq!(run.lines_read, 3);
+    assert_eq!(run.lines_skipped, 1);
+    assert_eq!(run.records_written, 1);
+
+    let mut history = Vec::new();
+    write_history(&db, &mut history).unwrap();
+    let history: serde_json::Value = serde_json::from_slice(&history).unwrap();
+    assert_eq!(history["records_written"], 1);
+}
+
+#[test]
+fn batched_imports() {
+    let logs = std::env::temp_dir().join("crabtrics-batched-imports");
+    let _ = fs::remove_dir_all(&logs);
+    fs::create_dir_all(&logs).unwrap();
+    let backlog = (1..=5)
+        .map(|listener| {
+            let path = logs.join(format!("access.log.{}", 6 - listener));
+            fs::write(
+                &path,
+                SAMPLE_LOG.replace("172.56.208.121", &format!("172.56.{listener}.121")),
+            )
+            .unwrap();
+            File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(SystemTime::UNIX_EPOCH + DAY * listener)
+                .unwrap();
+            path
+        })
+        .collect::<Vec<_>>();
+    let episodes = test_episodes_dir("batched-imports-episodes", 213_001);
+    let db = memory_database();
+    let import = LogImport {
+        max_files: Some(2),
+        ..LogImport::for_test(&logs, &episodes)
+    };
+
+    let mut totals = Vec::new();
+    while import.run(&db, &Config::default()).unwrap() {
+        totals.push(crabtrics::export::total_downloads(&db).unwrap());
+    }
+    assert_eq!(totals, [2, 4, 5]);
+    let runs = ImportRun::all(&db).query().unwrap();
+    assert_eq!(
+        runs[0].contents.files,
+        [
+            backlog[0].display().to_string(),
+            backlog[1].display().to_string()
+        ]
+    );
+}
+
+#[test]
+fn logs_read_oldest_first() {
+    let logs = std::env::temp_dir().join("crabtrics-logs-read-oldest-first");
+    let _ = fs::remove_dir_all(&logs);
+    fs::create_dir_all(&logs).unwrap();
+    // Half of the file two hours before the other half, in the older log.
+    for (index, (name, time)) in [("access.log.1", "15:00:00"), ("access.log", "17:00:00")]
+        .into_iter()
+        .enumerate()
+    {
+        let path = logs.join(name);
+        fs::write(
+            &path,
+            format!(
+                "10.0.1.1 - - [08/May/2023:{time} +0000] \"GET /episode-001.m4a HTTP/1.1\" 206 \
+                 500 \"-\" \"AppleCoreMedia/1.0.0\"\n"
+            ),
+        )
+        .unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + DAY * (index as u32 + 1))
+            .unwrap();
+    }
+    let episodes = test_episodes_dir("logs-read-oldest-first-episodes", 1_000);
+    let db = memory_database();
+    let import = LogImport::for_test(&logs, &episodes);
+    let config = Config {
+        dedup_window_minutes: 60,
+        ..Config::default()
+    };
+    assert!(import.run(&db, &config).unwrap());
+
+    // The halves are separate attempts past the window, whichever log the
+    // directory lists first.
+    let documents = PodcastDownloads::all(&db).query().unwrap();
+    assert_eq!(documents.len(), 1);
+    assert_eq!(documents[0].contents.full_downloads, 0);
+    assert_eq!(documents[0].contents.partial_downloads, 2);
+}
+
+#[test]
+fn compaction_shrinks_database() {
+    let path = std::env::temp_dir().join("crabtrics-compaction.bonsaidb");
+    let _ = fs::remove_dir_all(&path);
+    let db = database::open(StorageConfiguration::new(&path), false).unwrap();
+    for full_downloads in 0..500 {
+        insert_downloads(
+            &db,
+            1,
+            TimestampAsDays::now(),
+            PodcastDownloads {
+                full_downloads,
+                ..PodcastDownloads::default()
+            },
+        );
+    }
+
+    let before = directory_size(&path).unwrap();
+    compact(&db, &path).unwrap();
+    assert!(directory_size(&path).unwrap() < before);
+}
+
+#[test]
+fn conflict_resolution() {
+    let db = memory_database();
+    let key = EpisodeDateKey {
+        episode: 1,
+        date: TimestampAsDays::now(),
+    };
+    for (on_conflict, full_downloads, partial_downloads) in [
+        (ConflictResolution::Overwrite, 2, 4),
+        (ConflictResolution::Sum, 7, 7),
+        (ConflictResolution::Max, 5, 4),
+    ] {
+        insert_downloads(
+            &db,
+            key.episode,
+            key.date,
+            PodcastDownloads {
+                full_downloads: 5,
+                partial_downloads: 3,
+                ..PodcastDownloads::default()
+            },
+        );
+        let imported = PodcastDownloads {
+            full_downloads: 2,
+            partial_downloads: 4,
+            ..PodcastDownloads::default()
+        };
+        write_downloads(&db, HashMap::from([(key, imported)]), on_conflict).unwrap();
+
+        let stored = PodcastDownloads::get(&key, &db).unwrap().unwrap();
+        assert_eq!(stored.contents.full_downloads, full_downloads);
+        assert_eq!(stored.contents.partial_downloads, partial_downloads);
+    }
+}
+
+#[test]
+fn dry_run_changes() {
+    let db = memory_database();
+    let key = |episode| EpisodeDateKey {
+        episode,
+        date: TimestampAsDays::now(),
+    };
+    let downloads = |full_downloads, partial_downloads| PodcastDownloads {
+        full_downloads,
+        partial_downloads,
+        ..PodcastDownloads::default()
+    };
+    insert_downloads(&db, 1, key(1).date, downloads(5, 3));
+    insert_downloads(&db, 2, key(2).date, downloads(2, 1));
+    let imported = HashMap::from([
+        (key(1), downloads(6, 1)),
+        (key(2), downloads(2, 1)),
+        (key(3), downloads(1, 0)),
+    ]);
+
+    assert_eq!(
+        diff_downloads(&db, imported, ConflictResolution::Overwrite).unwrap(),
+        BTreeMap::from([
+            (
+                key(1),
+                RecordChange::Changed {
+                    full: 1,
+                    partial: -2
+                }
+            ),
+            (key(2), RecordChange::Unchanged),
+            (
+                key(3),
+                RecordChange::Added {
+                    full: 1,
+                    partial: 0
+                }
+            ),
+        ])
+    );
+    assert!(PodcastDownloads::get(&key(3), &db).unwrap().is_none());
+
+    // A dry run of an import leaves the database as it was.
+    let logs = std::env::temp_dir().join("crabtrics-dry-run-changes");
+    let _ = fs::remove_dir_all(&logs);
+    fs::create_dir_all(&logs).unwrap();
+    fs::write(logs.join("access.log"), SAMPLE_LOG).unwrap();
+    let episodes = test_episodes_dir("dry-run-changes-episodes", 213_001);
+    let db = memory_database();
+    let import = LogImport {
+        dry_run: true,
+        ..LogImport::for_test(&logs, &episodes)
+    };
+    assert!(import.run(&db, &Config::default()).unwrap());
+    assert!(PodcastDownloads::all(&db).query().unwrap().is_empty());
+    assert!(ImportRun::all(&db).query().unwrap().is_empty());
+}
+
+#[test]
+fn unchanged_downloads_keep_written_at() {
+    let db = memory_database();
+    let key = EpisodeDateKey {
+        episode: 1,
+        date: TimestampAsDays::now(),
+    };
+    insert_downloads(
+        &db,
+        key.episode,
+        key.date,
+        PodcastDownloads {
+            full_downloads: 5,
+            written_at: 100,
+            ..PodcastDownloads::default()
+        },
+    );
+    let imported = |full_downloads| {
+        HashMap::from([(
+            key,
+            PodcastDownloads {
+                full_downloads,
+                ..PodcastDownloads::default()
+            },
+        )])
+    };
+
+    write_downloads(&db, imported(5), ConflictResolution::Overwrite).unwrap();
+    let stored = PodcastDownloads::get(&key, &db).unwrap().unwrap();
+    assert_eq!(stored.contents.written_at, 100);
+
+    write_downloads(&db, imported(6), ConflictResolution::Overwrite).unwrap();
+    let stored = PodcastDownloads::get(&key, &db).unwrap().unwrap();
+    assert_eq!(stored.contents.full_downloads, 6);
+    assert!(stored.contents.written_at > 100);
 }
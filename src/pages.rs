@@ -0,0 +1,121 @@
+use std::fs;
+use std::path::Path;
+
+use askama::Template;
+use rayon::prelude::*;
+
+use crate::export::format_date;
+use crate::store::DownloadStore;
+
+/// The directory, relative to the report, that episode pages are written to.
+pub const PAGES_DIR: &str = "episodes";
+
+#[derive(Debug, Template)]
+#[template(path = "episode.html")]
+struct EpisodePage {
+    number: u16,
+    /// The number shown on the page, after the configured offset.
+    shown_number: i32,
+    /// The file name of the report that the page links back to.
+    index_file: String,
+    full_downloads: u32,
+    partial_downloads: u32,
+    /// Each day with downloads, oldest first, with its full and partial
+    /// downloads.
+    days: Vec<(String, u16, u16)>,
+}
+
+impl EpisodePage {
+    fn query(
+        store: &dyn DownloadStore,
+        number: u16,
+        number_offset: i32,
+        index_file: &str,
+    ) -> anyhow::Result<Self> {
+        let mut page = Self {
+            number,
+            shown_number: i32::from(number) - number_offset,
+            index_file: index_file.to_string(),
+            full_downloads: 0,
+            partial_downloads: 0,
+            days: Vec::new(),
+        };
+        for (key, downloads) in store.episode_downloads(number)? {
+            page.full_downloads += u32::from(downloads.full_downloads);
+            page.partial_downloads += u32::from(downloads.partial_downloads);
+            page.days.push((
+                format_date(key.date)?,
+                downloads.full_downloads,
+                downloads.partial_downloads,
+            ));
+        }
+        Ok(page)
+    }
+}
+
+/// Writes a page for each episode to `episodes/{number}.html` in
+/// `export_dir`, each linking back to the report in `index_file` and titled
+/// with the episode's number less `number_offset`.
+///
+/// Each page only reads its own episode's documents, so the pages are queried
+/// and rendered in parallel.
+pub fn write_episode_pages(
+    store: &dyn DownloadStore,
+    export_dir: &Path,
+    number_offset: i32,
+    index_file: &str,
+) -> anyhow::Result<()> {
+    let pages_dir = export_dir.join(PAGES_DIR);
+    fs::create_dir_all(&pages_dir)?;
+    let episodes = store
+        .episode_totals()?
+        .into_iter()
+        .map(|(number, _)| number)
+        .collect::<Vec<_>>();
+    episodes.into_par_iter().try_for_each(|number| {
+        let page = EpisodePage::query(store, number, number_offset, index_file)?;
+        fs::write(
+            pages_dir.join(format!("{number}.html")),
+            page.render()?.as_bytes(),
+        )?;
+        Ok(())
+    })
+}
+
+#[test]
+fn episode_pages() {
+    use bonsaidb::core::key::time::TimestampAsDays;
+
+    use crate::schema::PodcastDownloads;
+    use crate::testing::{insert_downloads, memory_database};
+
+    let db = memory_database();
+    for episode in 1..=3 {
+        insert_downloads(
+            &db,
+            episode,
+            TimestampAsDays::now(),
+            PodcastDownloads {
+                full_downloads: episode * 10,
+                partial_downloads: episode,
+                ..PodcastDownloads::default()
+            },
+        );
+    }
+
+    let export_dir = std::env::temp_dir().join("crabtrics-episode-pages");
+    let _ = fs::remove_dir_all(&export_dir);
+    write_episode_pages(&db, &export_dir, 0, "stats.html").unwrap();
+
+    let page = EpisodePage::query(&db, 2, 0, "stats.html").unwrap();
+    assert_eq!(page.full_downloads, 20);
+    assert_eq!(page.partial_downloads, 2);
+    assert_eq!(page.days.len(), 1);
+    for episode in 1..=3 {
+        let rendered =
+            fs::read_to_string(export_dir.join(PAGES_DIR).join(format!("{episode}.html"))).unwrap();
+        assert!(rendered.contains(&format!("Episode {episode}")));
+        assert!(rendered.contains("href=\"../stats.html\""));
+    }
+    assert!(!export_dir.join(PAGES_DIR).join("4.html").exists());
+}
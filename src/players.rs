@@ -0,0 +1,132 @@
+use crate::config::PlayerRules;
+
+/// Where a download was most likely played.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Player {
+    /// The podcast's own website, such as its embedded web player.
+    FirstParty = 0,
+    /// A podcast app the listener subscribed with.
+    ThirdParty = 1,
+    /// A request that didn't clearly match either.
+    Unknown = 2,
+}
+
+impl Player {
+    /// Classifies a request by its referrer and user agent.
+    ///
+    /// A request is first-party when it was referred by a first-party host
+    /// using a browser that isn't a known podcast app, and third-party when
+    /// it had no referrer and came from a known podcast app.
+    pub fn classify(referrer: &str, user_agent: &str, rules: &PlayerRules) -> Self {
+        let user_agent = user_agent.to_ascii_lowercase();
+        let matches = |patterns: &[String]| {
+            patterns
+                .iter()
+                .any(|pattern| user_agent.contains(&pattern.to_ascii_lowercase()))
+        };
+        let app = matches(&rules.app_user_agents);
+        let browser = !app && matches(&rules.browser_user_agents);
+
+        match referrer_host(referrer) {
+            Some(host) if browser && is_first_party(&host, &rules.first_party_hosts) => {
+                Self::FirstParty
+            }
+            None if app => Self::ThirdParty,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// Returns the lowercased host of `referrer`, or None if no referrer was sent.
+fn referrer_host(referrer: &str) -> Option<String> {
+    if referrer.is_empty() || referrer == "-" {
+        return None;
+    }
+
+    let authority = referrer
+        .split_once("://")
+        .map_or(referrer, |(_, remaining)| remaining);
+    let host = authority.split(['/', '?', '#']).next().unwrap_or_default();
+    let host = host.rsplit_once('@').map_or(host, |(_, host)| host);
+    let host = host.split_once(':').map_or(host, |(host, _)| host);
+    Some(host.to_ascii_lowercase())
+}
+
+/// Returns the source a download was referred from: the lowercased host of
+/// `referrer` without any `www.`, or `direct` if no referrer was sent.
+pub fn referrer_source(referrer: &str) -> String {
+    match referrer_host(referrer) {
+        Some(host) if !host.is_empty() => match host.strip_prefix("www.") {
+            Some(domain) => domain.to_string(),
+            None => host,
+        },
+        _ => String::from("direct"),
+    }
+}
+
+/// Returns true if `host` is one of `first_party_hosts` or a subdomain of one.
+fn is_first_party(host: &str, first_party_hosts: &[String]) -> bool {
+    first_party_hosts.iter().any(|first_party| {
+        let first_party = first_party.to_ascii_lowercase();
+        host == first_party
+            || host
+                .strip_suffix(&first_party)
+                .map_or(false, |subdomain| subdomain.ends_with('.'))
+    })
+}
+
+#[test]
+fn classification() {
+    const SAFARI: &str = "Mozilla/5.0 (iPhone; CPU iPhone OS 16_4_1 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.4 Mobile/15E148 Safari/604.1";
+    const APPLE_PODCASTS: &str = "Podcasts/1.1.0 CFNetwork/1408.0.4 Darwin/22.5.0";
+    const OVERCAST: &str = "Overcast/3.0 (+http://overcast.fm/; iOS podcast app)";
+
+    let rules = PlayerRules::default();
+    for (referrer, user_agent, expected) in [
+        ("https://wayofthecrab.com/", SAFARI, Player::FirstParty),
+        (
+            "https://www.wayofthecrab.com/episode-1",
+            SAFARI,
+            Player::FirstParty,
+        ),
+        ("-", APPLE_PODCASTS, Player::ThirdParty),
+        ("", OVERCAST, Player::ThirdParty),
+        // Ambiguous requests
+        ("-", SAFARI, Player::Unknown),
+        ("https://wayofthecrab.com/", OVERCAST, Player::Unknown),
+        ("https://notwayofthecrab.com/", SAFARI, Player::Unknown),
+        ("https://example.com/", APPLE_PODCASTS, Player::Unknown),
+        ("-", "curl/8.1.2", Player::Unknown),
+    ] {
+        assert_eq!(
+            Player::classify(referrer, user_agent, &rules),
+            expected,
+            "{referrer} {user_agent}"
+        );
+    }
+}
+
+#[test]
+fn referrer_sources() {
+    for (referrer, expected) in [
+        ("https://wayofthecrab.com/", "wayofthecrab.com"),
+        (
+            "https://www.WayOfTheCrab.com/episode-1?t=30",
+            "wayofthecrab.com",
+        ),
+        (
+            "https://podcasts.apple.com/us/podcast/id1",
+            "podcasts.apple.com",
+        ),
+        ("http://user@localhost:8080/", "localhost"),
+        (
+            "android-app://com.google.android.gm/",
+            "com.google.android.gm",
+        ),
+        ("", "direct"),
+        ("-", "direct"),
+        ("https://", "direct"),
+    ] {
+        assert_eq!(referrer_source(referrer), expected, "{referrer}");
+    }
+}
@@ -0,0 +1,90 @@
+use std::io::{BufWriter, Write};
+
+use bonsaidb::core::schema::SerializedCollection;
+use bonsaidb::local::Database;
+use crabtrics::export::DailyRecord;
+use crabtrics::schema::PodcastDownloads;
+use prost::Message;
+
+/// The `DailyRecord` message of `proto/downloads.proto`.
+#[derive(Clone, Eq, PartialEq, Message)]
+pub struct DailyRecordMessage {
+    #[prost(string, tag = "1")]
+    pub date: String,
+    #[prost(uint32, tag = "2")]
+    pub episode: u32,
+    #[prost(uint32, tag = "3")]
+    pub full: u32,
+    #[prost(uint32, tag = "4")]
+    pub partial: u32,
+}
+
+impl From<DailyRecord> for DailyRecordMessage {
+    fn from(record: DailyRecord) -> Self {
+        Self {
+            date: record.date,
+            episode: u32::from(record.episode),
+            full: u32::from(record.full),
+            partial: u32::from(record.partial),
+        }
+    }
+}
+
+/// Writes every per-day record as a length-delimited `DailyRecord` message,
+/// returning how many were written.
+pub fn export_protobuf<W: Write>(db: &Database, output: W) -> anyhow::Result<usize> {
+    let mut output = BufWriter::new(output);
+    let mut buffer = Vec::new();
+    let mut exported = 0;
+    for document in PodcastDownloads::all(db).query()? {
+        buffer.clear();
+        DailyRecordMessage::from(DailyRecord::new(&document.header.id, &document.contents)?)
+            .encode_length_delimited(&mut buffer)?;
+        output.write_all(&buffer)?;
+        exported += 1;
+    }
+    output.flush()?;
+    Ok(exported)
+}
+
+#[test]
+fn protobuf_round_trip() {
+    use bonsaidb::core::key::time::TimestampAsDays;
+    use crabtrics::testing::{insert_downloads, memory_database};
+
+    let db = memory_database();
+    for (episode, full_downloads, partial_downloads) in [(1, 3, 1), (2, 500, 0)] {
+        insert_downloads(
+            &db,
+            episode,
+            TimestampAsDays::now(),
+            PodcastDownloads {
+                full_downloads,
+                partial_downloads,
+                ..PodcastDownloads::default()
+            },
+        );
+    }
+
+    let mut output = Vec::new();
+    assert_eq!(export_protobuf(&db, &mut output).unwrap(), 2);
+
+    let mut stream = output.as_slice();
+    let mut records = Vec::new();
+    while !stream.is_empty() {
+        let message = DailyRecordMessage::decode_length_delimited(&mut stream).unwrap();
+        records.push(DailyRecord {
+            date: message.date,
+            episode: message.episode.try_into().unwrap(),
+            full: message.full.try_into().unwrap(),
+            partial: message.partial.try_into().unwrap(),
+        });
+    }
+    let expected = PodcastDownloads::all(&db)
+        .query()
+        .unwrap()
+        .iter()
+        .map(|document| DailyRecord::new(&document.header.id, &document.contents).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(records, expected);
+}
@@ -0,0 +1,72 @@
+use std::borrow::Cow;
+
+/// Splits a request target into its path and its query string, if it has
+/// one.
+pub fn split_query(target: &str) -> (&str, Option<&str>) {
+    match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    }
+}
+
+/// Returns the decoded value of the first `name` parameter in `query`, such as
+/// `spring 2024` for `utm_campaign` in
+/// `utm_source=mastodon&utm_campaign=spring+2024`.
+///
+/// Values that aren't valid UTF-8 once decoded are ignored.
+pub fn query_value<'a>(query: &'a str, name: &str) -> Option<Cow<'a, str>> {
+    query
+        .split('&')
+        .filter_map(|parameter| {
+            let (key, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+            (percent_decode(key)? == name).then_some(value)
+        })
+        .next()
+        .and_then(percent_decode)
+}
+
+/// Decodes `+` as a space and `%XX` escapes as bytes, leaving invalid escapes
+/// as-is.
+fn percent_decode(encoded: &str) -> Option<Cow<'_, str>> {
+    if !encoded.contains(['%', '+']) {
+        return Some(Cow::Borrowed(encoded));
+    }
+
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut index = 0;
+    while index < encoded.len() {
+        let byte = encoded.as_bytes()[index];
+        let escaped = encoded
+            .get(index + 1..index + 3)
+            .filter(|hex| byte == b'%' && hex.bytes().all(|digit| digit.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        if let Some(escaped) = escaped {
+            decoded.push(escaped);
+            index += 3;
+            continue;
+        }
+        decoded.push(if byte == b'+' { b' ' } else { byte });
+        index += 1;
+    }
+    String::from_utf8(decoded).ok().map(Cow::Owned)
+}
+
+#[test]
+fn query_values() {
+    assert_eq!(
+        split_query("/episode-012.m4a?utm_campaign=spring2024"),
+        ("/episode-012.m4a", Some("utm_campaign=spring2024"))
+    );
+    assert_eq!(split_query("/episode-012.m4a"), ("/episode-012.m4a", None));
+
+    let query = "utm_source=mastodon&utm_campaign=spring+2024%21&utm_medium&utm_campaign=late";
+    assert_eq!(query_value(query, "utm_campaign").unwrap(), "spring 2024!");
+    assert_eq!(query_value(query, "utm_source").unwrap(), "mastodon");
+    assert_eq!(query_value(query, "utm_medium").unwrap(), "");
+    assert_eq!(query_value(query, "utm_term"), None);
+    assert_eq!(
+        query_value("utm%5Fcampaign=a%2", "utm_campaign").unwrap(),
+        "a%2"
+    );
+    assert_eq!(query_value("utm_campaign=%FF", "utm_campaign"), None);
+}
@@ -0,0 +1,1866 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use askama::Template;
+use bonsaidb::core::key::time::TimestampAsDays;
+use csv::{QuoteStyle, WriterBuilder};
+use serde::Serialize;
+use time::{Date, OffsetDateTime};
+
+use crate::config::{BandwidthCostConfig, Config, CsvConfig, CsvQuoteStyle, RowOrder};
+use crate::export::{days_ago, format_date, Badge, DailyRecord};
+use crate::hll::HyperLogLog;
+use crate::pages::write_episode_pages;
+use crate::players::Player;
+use crate::schema::{
+    CampaignDateKey, DateEpisodeKey, EpisodeDateKey, EpisodeMonthKey, EpisodeWeekKey,
+    PodcastDownloads,
+};
+use crate::store::DownloadStore;
+
+const GIB: f64 = (1_u64 << 30) as f64;
+/// How many of the latest weeks and months the HTML shows. `report.json`
+/// includes every one.
+const SHOWN_WEEKS: usize = 8;
+const SHOWN_MONTHS: usize = 12;
+/// How many of the most common referrers the HTML shows.
+const SHOWN_REFERRERS: usize = 10;
+
+/// The rendered `index.html`.
+///
+/// The template inlines all of its styles so that the report is a single
+/// self-contained file that can be emailed or archived without making any
+/// external requests.
+#[derive(Debug, Serialize, Template)]
+#[template(path = "index.html")]
+struct Report {
+    /// The sections that couldn't be generated.
+    failed_sections: Vec<&'static str>,
+    /// The days downloads were imported for, or None if there aren't any.
+    date_range: Option<DateRange>,
+    episode_downloads: Vec<EpisodeReport>,
+    recent_downloads: BTreeMap<String, RecentDownloads>,
+    latest_episode: u16,
+    /// Full downloads by ISO week, such as `2024-W01`.
+    weekly_downloads: BTreeMap<String, PeriodDownloads>,
+    /// Full downloads by calendar month, such as `2024-01`.
+    monthly_downloads: BTreeMap<String, PeriodDownloads>,
+    weekday_downloads: Vec<WeekdayReport>,
+    format_trends: ShareTrends,
+    protocol_trends: ShareTrends,
+    listening_minutes: ListeningMinutes,
+    /// The estimated number of distinct visitors across every episode and
+    /// day, or 0 if none were tracked.
+    distinct_visitors: u64,
+    player_downloads: Vec<EpisodePlayers>,
+    seasons: Vec<SeasonReport>,
+    losing_momentum: Vec<MomentumReport>,
+    campaigns: Vec<CampaignReport>,
+    client_downloads: Vec<DownloadShare>,
+    /// Full downloads by country, which is empty unless a GeoIP database was
+    /// given.
+    country_downloads: Vec<DownloadShare>,
+    /// Full downloads by where they were referred from, most common first.
+    referrer_downloads: Vec<DownloadShare>,
+    /// Full downloads by episode variant, which is empty when no variants
+    /// are configured.
+    variant_downloads: Vec<DownloadShare>,
+    /// The estimated cost of the bytes sent, or None if no price is
+    /// configured.
+    bandwidth_costs: Option<BandwidthCosts>,
+    /// Subtracted from each episode number shown in the HTML.
+    #[serde(skip)]
+    episode_number_offset: i32,
+}
+
+impl Report {
+    fn shown_number(&self, episode: u16) -> i32 {
+        i32::from(episode) - self.episode_number_offset
+    }
+
+    fn shown_weeks(&self) -> Vec<(&String, &PeriodDownloads)> {
+        latest_periods(&self.weekly_downloads, SHOWN_WEEKS)
+    }
+
+    fn shown_months(&self) -> Vec<(&String, &PeriodDownloads)> {
+        latest_periods(&self.monthly_downloads, SHOWN_MONTHS)
+    }
+
+    fn shown_referrers(&self) -> &[DownloadShare] {
+        &self.referrer_downloads[..self.referrer_downloads.len().min(SHOWN_REFERRERS)]
+    }
+}
+
+/// Returns the last `count` of `periods`, oldest first.
+fn latest_periods(
+    periods: &BTreeMap<String, PeriodDownloads>,
+    count: usize,
+) -> Vec<(&String, &PeriodDownloads)> {
+    let mut latest = periods.iter().rev().take(count).collect::<Vec<_>>();
+    latest.reverse();
+    latest
+}
+
+/// The first and last days with stored downloads, and the days between them
+/// without any, which usually means a log file wasn't imported.
+#[derive(Debug, Serialize, Eq, PartialEq)]
+struct DateRange {
+    first: String,
+    last: String,
+    missing_days: Vec<String>,
+}
+
+impl DateRange {
+    /// Returns the range covered by `dates`, or None if there aren't any.
+    fn new(dates: &BTreeSet<Date>) -> Option<Self> {
+        let (&first, &last) = (dates.first()?, dates.last()?);
+        let format = |date: Date| {
+            format!(
+                "{:04}-{:02}-{:02}",
+                date.year(),
+                date.month() as u8,
+                date.day()
+            )
+        };
+        Some(Self {
+            first: format(first),
+            last: format(last),
+            missing_days: std::iter::successors(Some(first), |date| date.next_day())
+                .take_while(|date| *date < last)
+                .filter(|date| !dates.contains(date))
+                .map(format)
+                .collect(),
+        })
+    }
+}
+
+/// Estimated listening time across every episode with a configured duration.
+#[derive(Debug, Serialize, Default)]
+struct ListeningMinutes {
+    last_30_days: u64,
+    all_time: u64,
+}
+
+/// `downloads.json`, every episode's downloads on each day sorted by episode
+/// and then date, along with each episode's totals from the report.
+#[derive(Debug, Serialize)]
+struct DownloadsJson<'a> {
+    episodes: &'a [EpisodeReport],
+    days: &'a [DailyRecord],
+}
+
+#[derive(Debug, Serialize)]
+struct EpisodeReport {
+    number: u16,
+    downloads: u32,
+    partial_downloads: u32,
+    next_milestone: Option<Milestone>,
+    /// The percentage of the episode's downloads that were full, or None if
+    /// it hasn't been downloaded at all.
+    completion_percent: Option<u32>,
+    /// The bytes sent for every download of the episode.
+    bytes_sent: u64,
+    /// The estimated number of distinct visitors who requested the episode
+    /// on any day.
+    unique_listeners: u32,
+}
+
+impl EpisodeReport {
+    fn gib_sent(&self) -> f64 {
+        self.bytes_sent as f64 / GIB
+    }
+}
+
+/// Returns the percentage of `full + partial` downloads that were full,
+/// rounded down, or None if there weren't any.
+fn completion_percent(full: u32, partial: u32) -> Option<u32> {
+    let attempts = u64::from(full) + u64::from(partial);
+    (attempts > 0).then(|| (u64::from(full) * 100 / attempts) as u32)
+}
+
+#[derive(Debug, Serialize, Eq, PartialEq)]
+struct Milestone {
+    downloads: u32,
+    remaining: u32,
+}
+
+impl Milestone {
+    /// Returns the smallest milestone that `downloads` hasn't reached yet.
+    fn next(downloads: u32, milestones: &[u32]) -> Option<Self> {
+        milestones
+            .iter()
+            .copied()
+            .filter(|milestone| *milestone > downloads)
+            .min()
+            .map(|milestone| Self {
+                downloads: milestone,
+                remaining: milestone - downloads,
+            })
+    }
+}
+
+#[derive(Debug, Serialize, Default)]
+struct RecentDownloads {
+    episodes: BTreeMap<u16, u32>,
+}
+
+/// Each episode's full downloads in a week or month.
+#[derive(Debug, Serialize, Default, Eq, PartialEq)]
+struct PeriodDownloads {
+    total: u32,
+    episodes: BTreeMap<u16, u32>,
+}
+
+impl PeriodDownloads {
+    fn by_week(downloads: Vec<(EpisodeWeekKey, u32)>) -> BTreeMap<String, Self> {
+        Self::by_period(downloads.into_iter().map(|(key, downloads)| {
+            (
+                format!("{:04}-W{:02}", key.year, key.week),
+                key.episode,
+                downloads,
+            )
+        }))
+    }
+
+    fn by_month(downloads: Vec<(EpisodeMonthKey, u32)>) -> BTreeMap<String, Self> {
+        Self::by_period(downloads.into_iter().map(|(key, downloads)| {
+            (
+                format!("{:04}-{:02}", key.year, key.month),
+                key.episode,
+                downloads,
+            )
+        }))
+    }
+
+    /// Groups each episode's `(period, episode, downloads)` by period.
+    fn by_period(
+        downloads: impl IntoIterator<Item = (String, u16, u32)>,
+    ) -> BTreeMap<String, Self> {
+        let mut periods = BTreeMap::<String, Self>::new();
+        for (period, episode, downloads) in downloads {
+            let period = periods.entry(period).or_default();
+            period.total += downloads;
+            *period.episodes.entry(episode).or_default() += downloads;
+        }
+        periods
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct WeekdayReport {
+    name: &'static str,
+    downloads: u32,
+}
+
+/// The total full downloads of every episode in a season.
+#[derive(Debug, Serialize, Eq, PartialEq)]
+struct SeasonReport {
+    name: String,
+    episodes: u32,
+    downloads: u32,
+}
+
+impl SeasonReport {
+    /// Totals `episode_downloads` by season, in order of each season's first
+    /// episode. Episodes without a season are totaled as "Unassigned", last.
+    /// Returns nothing if no seasons are configured.
+    fn totals(episode_downloads: &[EpisodeReport], seasons: &HashMap<u16, String>) -> Vec<Self> {
+        if seasons.is_empty() {
+            return Vec::new();
+        }
+
+        let mut totals = HashMap::<Option<&str>, (u16, Self)>::new();
+        for episode in episode_downloads {
+            let season = seasons.get(&episode.number).map(String::as_str);
+            let (first_episode, total) = totals.entry(season).or_insert_with(|| {
+                (
+                    episode.number,
+                    Self {
+                        name: season.unwrap_or("Unassigned").to_string(),
+                        episodes: 0,
+                        downloads: 0,
+                    },
+                )
+            });
+            *first_episode = (*first_episode).min(episode.number);
+            total.episodes += 1;
+            total.downloads += episode.downloads;
+        }
+
+        let mut totals = totals.into_iter().collect::<Vec<_>>();
+        totals.sort_by_key(|(season, (first_episode, _))| (season.is_none(), *first_episode));
+        totals.into_iter().map(|(_, (_, total))| total).collect()
+    }
+}
+
+/// An episode's full downloads by the kind of player they were played with.
+#[derive(Debug, Serialize)]
+struct EpisodePlayers {
+    number: u16,
+    first_party: u32,
+    third_party: u32,
+    unknown: u32,
+}
+
+/// The full downloads attributed to a marketing campaign.
+#[derive(Debug, Serialize, Eq, PartialEq)]
+struct CampaignReport {
+    name: String,
+    last_30_days: u32,
+    all_time: u32,
+}
+
+impl CampaignReport {
+    /// Totals each campaign's downloads, most downloaded first. Returns
+    /// nothing if every download was organic.
+    fn totals(downloads: Vec<(CampaignDateKey, u32)>, recent_start: TimestampAsDays) -> Vec<Self> {
+        let mut totals = BTreeMap::<String, Self>::new();
+        for (key, downloads) in downloads {
+            let total = totals.entry(key.campaign).or_insert_with_key(|name| Self {
+                name: name.clone(),
+                last_30_days: 0,
+                all_time: 0,
+            });
+            total.all_time += downloads;
+            if key.date >= recent_start {
+                total.last_30_days += downloads;
+            }
+        }
+        if totals.keys().all(|name| name == "organic") {
+            return Vec::new();
+        }
+
+        let mut totals = totals.into_values().collect::<Vec<_>>();
+        totals.sort_by(|a, b| b.all_time.cmp(&a.all_time).then(a.name.cmp(&b.name)));
+        totals
+    }
+}
+
+/// The full downloads of one group, such as those made with a podcast client.
+#[derive(Debug, Serialize, Eq, PartialEq)]
+struct DownloadShare {
+    name: String,
+    downloads: u32,
+    /// The share of every full download, rounded to the nearest percent.
+    percent: u32,
+}
+
+impl DownloadShare {
+    /// Lists each group's downloads, most downloaded first.
+    fn totals(downloads: BTreeMap<String, u32>) -> Vec<Self> {
+        let total = downloads
+            .values()
+            .map(|&downloads| u64::from(downloads))
+            .sum::<u64>();
+        let mut totals = downloads
+            .into_iter()
+            .map(|(name, downloads)| Self {
+                name,
+                downloads,
+                percent: ((u64::from(downloads) * 100 + total / 2) / total) as u32,
+            })
+            .collect::<Vec<_>>();
+        totals.sort_by(|a, b| b.downloads.cmp(&a.downloads).then(a.name.cmp(&b.name)));
+        totals
+    }
+}
+
+/// The estimated cost of sending every episode, given the configured price.
+#[derive(Debug, Serialize, PartialEq)]
+struct BandwidthCosts {
+    currency: String,
+    total: f64,
+    episodes: Vec<EpisodeCost>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+struct EpisodeCost {
+    number: u16,
+    gib_sent: f64,
+    cost: f64,
+}
+
+impl BandwidthCosts {
+    /// Prices the bytes sent for each episode, returning None if no price is
+    /// configured.
+    fn estimate(bytes_sent: &BTreeMap<u16, u64>, config: &BandwidthCostConfig) -> Option<Self> {
+        let price_per_gib = config.price_per_gib?;
+        let episodes = bytes_sent
+            .iter()
+            .map(|(number, bytes)| {
+                let gib_sent = *bytes as f64 / GIB;
+                EpisodeCost {
+                    number: *number,
+                    gib_sent,
+                    cost: gib_sent * price_per_gib,
+                }
+            })
+            .collect::<Vec<_>>();
+        Some(Self {
+            currency: config.currency.clone(),
+            total: bytes_sent.values().sum::<u64>() as f64 / GIB * price_per_gib,
+            episodes,
+        })
+    }
+}
+
+/// An episode's full downloads over the last two complete weeks, for finding
+/// episodes that are losing momentum.
+#[derive(Debug, Clone, Copy)]
+struct EpisodeWeeks {
+    first_download: TimestampAsDays,
+    previous_week: u32,
+    last_week: u32,
+}
+
+/// An episode whose full downloads fell from one week to the next.
+#[derive(Debug, Serialize, Eq, PartialEq)]
+struct MomentumReport {
+    number: u16,
+    previous_week: u32,
+    last_week: u32,
+    decline_percent: u32,
+}
+
+impl MomentumReport {
+    /// The number of declining episodes listed in the report.
+    const LIMIT: usize = 10;
+
+    /// Returns the episodes whose downloads fell the most relative to the
+    /// week before, steepest decline first. Episodes first downloaded after
+    /// `previous_week_start` haven't been out for two full weeks and are
+    /// skipped.
+    fn losing(
+        weeks: &BTreeMap<u16, EpisodeWeeks>,
+        previous_week_start: TimestampAsDays,
+    ) -> Vec<Self> {
+        let mut declining = weeks
+            .iter()
+            .filter(|(_, weeks)| {
+                weeks.first_download <= previous_week_start && weeks.last_week < weeks.previous_week
+            })
+            .map(|(number, weeks)| Self {
+                number: *number,
+                previous_week: weeks.previous_week,
+                last_week: weeks.last_week,
+                decline_percent: (weeks.previous_week - weeks.last_week) * 100
+                    / weeks.previous_week,
+            })
+            .collect::<Vec<_>>();
+        declining.sort_by(|a, b| {
+            b.decline_percent
+                .cmp(&a.decline_percent)
+                .then((b.previous_week - b.last_week).cmp(&(a.previous_week - a.last_week)))
+                .then(a.number.cmp(&b.number))
+        });
+        declining.truncate(Self::LIMIT);
+        declining
+    }
+}
+
+const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+/// Each month's share of full downloads by a category, such as the file
+/// extension or HTTP version.
+#[derive(Debug, Serialize, Default)]
+struct ShareTrends {
+    categories: Vec<String>,
+    months: Vec<MonthlyShares>,
+}
+
+#[derive(Debug, Serialize)]
+struct MonthlyShares {
+    month: String,
+    /// The percentage of the month's full downloads for each category.
+    shares: BTreeMap<String, f64>,
+}
+
+impl ShareTrends {
+    fn new(downloads_by_month: BTreeMap<String, BTreeMap<String, u32>>) -> Self {
+        let mut categories = BTreeSet::new();
+        let mut months = Vec::with_capacity(downloads_by_month.len());
+        for (month, by_category) in downloads_by_month {
+            let total: u32 = by_category.values().sum();
+            let mut shares = BTreeMap::new();
+            for (category, downloads) in by_category {
+                if total > 0 {
+                    shares.insert(
+                        category.clone(),
+                        f64::from(downloads) * 100. / f64::from(total),
+                    );
+                }
+                categories.insert(category);
+            }
+            months.push(MonthlyShares { month, shares });
+        }
+        Self {
+            categories: categories.into_iter().collect(),
+            months,
+        }
+    }
+}
+
+/// Adds a day's full downloads by category to its month's totals.
+fn add_monthly_downloads(
+    downloads_by_month: &mut BTreeMap<String, BTreeMap<String, u32>>,
+    month: &str,
+    downloads: &BTreeMap<String, u16>,
+) {
+    let by_category = downloads_by_month.entry(month.to_string()).or_default();
+    for (category, downloads) in downloads {
+        *by_category.entry(category.clone()).or_default() += u32::from(*downloads);
+    }
+}
+
+/// Creates a writer for `downloads.csv` in the configured format, writing the
+/// header row if enabled.
+fn downloads_csv<W: Write>(output: W, config: &CsvConfig) -> anyhow::Result<csv::Writer<W>> {
+    anyhow::ensure!(
+        config.delimiter.is_ascii(),
+        "csv delimiter must be an ascii character"
+    );
+    let quote_style = match config.quote_style {
+        CsvQuoteStyle::Always => QuoteStyle::Always,
+        CsvQuoteStyle::Necessary => QuoteStyle::Necessary,
+        CsvQuoteStyle::NonNumeric => QuoteStyle::NonNumeric,
+        CsvQuoteStyle::Never => QuoteStyle::Never,
+    };
+    let mut csv = WriterBuilder::new()
+        .delimiter(config.delimiter as u8)
+        .quote_style(quote_style)
+        .from_writer(output);
+    if config.write_header {
+        csv.write_record(
+            config
+                .columns
+                .iter()
+                .chain(&config.unique_column)
+                .chain(&config.bytes_column),
+        )?;
+    }
+    Ok(csv)
+}
+
+/// The results of the queries a report is built from. Each query is made
+/// independently, so a failure in one still produces every section that
+/// doesn't depend on it.
+struct ReportData {
+    documents: anyhow::Result<Vec<(EpisodeDateKey, PodcastDownloads)>>,
+    episode_totals: anyhow::Result<Vec<(u16, u32)>>,
+    partial_totals: anyhow::Result<BTreeMap<u16, u32>>,
+    episode_visitors: anyhow::Result<BTreeMap<u16, HyperLogLog>>,
+    recent_downloads: anyhow::Result<Vec<(DateEpisodeKey, u32)>>,
+    weekly_downloads: anyhow::Result<Vec<(EpisodeWeekKey, u32)>>,
+    monthly_downloads: anyhow::Result<Vec<(EpisodeMonthKey, u32)>>,
+    campaign_downloads: anyhow::Result<Vec<(CampaignDateKey, u32)>>,
+}
+
+impl ReportData {
+    fn query(store: &dyn DownloadStore, config: &Config) -> Self {
+        Self {
+            documents: store.downloads(config.csv_order),
+            episode_totals: store.episode_totals(),
+            partial_totals: store.partial_totals(),
+            episode_visitors: store.episode_visitors(),
+            recent_downloads: query_recent_downloads(store),
+            weekly_downloads: store.weekly_downloads(),
+            monthly_downloads: store.monthly_downloads(),
+            campaign_downloads: store.campaign_downloads(),
+        }
+    }
+}
+
+fn query_recent_downloads(store: &dyn DownloadStore) -> anyhow::Result<Vec<(DateEpisodeKey, u32)>> {
+    let recent_start =
+        SystemTime::try_from(TimestampAsDays::now())? - Duration::from_secs(8 * 24 * 60 * 60);
+    store.downloads_since(TimestampAsDays::try_from(recent_start)?)
+}
+
+/// Totals gathered from every stored document, which also writes
+/// `downloads.csv`.
+#[derive(Debug, Default)]
+struct DailySummary {
+    weekday_totals: [u32; 7],
+    format_downloads_by_month: BTreeMap<String, BTreeMap<String, u32>>,
+    protocol_downloads_by_month: BTreeMap<String, BTreeMap<String, u32>>,
+    all_time_listening_seconds: u64,
+    recent_listening_seconds: u64,
+    player_downloads: BTreeMap<u16, [u32; 3]>,
+    client_downloads: BTreeMap<String, u32>,
+    country_downloads: BTreeMap<String, u32>,
+    referrer_downloads: BTreeMap<String, u32>,
+    variant_downloads: BTreeMap<String, u32>,
+    visitors: HyperLogLog,
+    episode_weeks: BTreeMap<u16, EpisodeWeeks>,
+    bytes_sent: BTreeMap<u16, u64>,
+    /// Every day with at least one stored document.
+    dates: BTreeSet<Date>,
+    records: Vec<DailyRecord>,
+}
+
+impl DailySummary {
+    fn export(
+        documents: Vec<(EpisodeDateKey, PodcastDownloads)>,
+        config: &Config,
+        export_dir: &Path,
+    ) -> anyhow::Result<Self> {
+        let mut csv = downloads_csv(
+            File::create(export_dir.join(&config.report_files.csv))?,
+            &config.csv,
+        )?;
+        let mut summary = Self::default();
+        let listening_cutoff = days_ago(30)?;
+        // Today is excluded from the weeks because its logs are still being
+        // written.
+        let today = TimestampAsDays::now();
+        let last_week_start = days_ago(7)?;
+        let previous_week_start = days_ago(14)?;
+        for (key, dl) in documents {
+            let timestamp = OffsetDateTime::from(SystemTime::try_from(key.date)?);
+            let date = format!(
+                "{:04}-{:02}-{:02}",
+                timestamp.year(),
+                timestamp.month(),
+                timestamp.day()
+            );
+            let mut record = vec![
+                date,
+                key.episode.to_string(),
+                dl.full_downloads.to_string(),
+                dl.partial_downloads.to_string(),
+            ];
+            if config.csv.unique_column.is_some() {
+                record.push(dl.visitors.estimate().to_string());
+            }
+            if config.csv.bytes_column.is_some() {
+                record.push(dl.bytes_sent.to_string());
+            }
+            csv.write_record(&record)?;
+            summary.dates.insert(timestamp.date());
+            summary.records.push(DailyRecord::new(&key, &dl)?);
+            summary.visitors.merge(&dl.visitors);
+            *summary.bytes_sent.entry(key.episode).or_default() += dl.bytes_sent;
+            let weeks = summary
+                .episode_weeks
+                .entry(key.episode)
+                .or_insert(EpisodeWeeks {
+                    first_download: key.date,
+                    previous_week: 0,
+                    last_week: 0,
+                });
+            weeks.first_download = weeks.first_download.min(key.date);
+            let full_downloads = u32::from(dl.full_downloads);
+            if key.date >= last_week_start && key.date < today {
+                weeks.last_week += full_downloads;
+            } else if key.date >= previous_week_start && key.date < last_week_start {
+                weeks.previous_week += full_downloads;
+            }
+            summary.all_time_listening_seconds += u64::from(dl.listening_seconds);
+            if key.date >= listening_cutoff {
+                summary.recent_listening_seconds += u64::from(dl.listening_seconds);
+            }
+            for (total, downloads) in summary
+                .weekday_totals
+                .iter_mut()
+                .zip(dl.full_downloads_by_weekday)
+            {
+                *total += u32::from(downloads);
+            }
+            let by_player = summary.player_downloads.entry(key.episode).or_default();
+            for (total, downloads) in by_player.iter_mut().zip(dl.full_downloads_by_player) {
+                *total += u32::from(downloads);
+            }
+            for (client, downloads) in &dl.full_downloads_by_client {
+                *summary.client_downloads.entry(client.clone()).or_default() +=
+                    u32::from(*downloads);
+            }
+            for (country, downloads) in &dl.full_downloads_by_country {
+                *summary
+                    .country_downloads
+                    .entry(country.clone())
+                    .or_default() += u32::from(*downloads);
+            }
+            for (referrer, downloads) in &dl.full_downloads_by_referrer {
+                *summary
+                    .referrer_downloads
+                    .entry(referrer.clone())
+                    .or_default() += u32::from(*downloads);
+            }
+            for (variant, downloads) in &dl.full_downloads_by_variant {
+                *summary
+                    .variant_downloads
+                    .entry(variant.clone())
+                    .or_default() += u32::from(*downloads);
+            }
+            let month = format!("{:04}-{:02}", timestamp.year(), timestamp.month() as u8);
+            add_monthly_downloads(
+                &mut summary.format_downloads_by_month,
+                &month,
+                &dl.full_downloads_by_extension,
+            );
+            let by_protocol = dl
+                .contents
+                .full_downloads_by_protocol
+                .iter()
+                .map(|(protocol, downloads)| {
+                    let protocol = if protocol.is_empty() {
+                        "Unknown"
+                    } else {
+                        protocol
+                    };
+                    (protocol.to_string(), *downloads)
+                })
+                .collect();
+            add_monthly_downloads(
+                &mut summary.protocol_downloads_by_month,
+                &month,
+                &by_protocol,
+            );
+        }
+        csv.flush()?;
+        Ok(summary)
+    }
+}
+
+/// Writes the report and the episode pages for the downloads in `store` to
+/// `export_dir`, creating it if needed.
+pub fn generate_report(
+    store: &dyn DownloadStore,
+    config: &Config,
+    export_dir: &Path,
+) -> anyhow::Result<()> {
+    fs::create_dir_all(export_dir)?;
+    if let Err(err) = write_episode_pages(
+        store,
+        export_dir,
+        config.episode_number_offset,
+        &config.report_files.html,
+    ) {
+        eprintln!("Warning: couldn't write the episode pages: {err}");
+    }
+    write_report(ReportData::query(store, config), config, export_dir)?;
+    Ok(())
+}
+
+/// Writes the report's files, skipping any section whose data couldn't be
+/// gathered. Returns the names of the skipped sections, which are also listed
+/// in the report itself.
+fn write_report(
+    data: ReportData,
+    config: &Config,
+    export_dir: &Path,
+) -> anyhow::Result<Vec<&'static str>> {
+    fs::create_dir_all(export_dir)?;
+    let mut failed_sections = Vec::new();
+
+    let daily = attempt_section(&mut failed_sections, "daily downloads", || {
+        DailySummary::export(data.documents?, config, export_dir)
+    });
+
+    let episode_downloads = attempt_section(&mut failed_sections, "episode totals", || {
+        let partial_totals = data.partial_totals?;
+        let episode_visitors = data.episode_visitors?;
+        let episode_downloads = data
+            .episode_totals?
+            .into_iter()
+            .map(|(number, downloads)| {
+                let partial_downloads = partial_totals.get(&number).copied().unwrap_or_default();
+                EpisodeReport {
+                    number,
+                    downloads,
+                    partial_downloads,
+                    next_milestone: Milestone::next(downloads, &config.milestones),
+                    completion_percent: completion_percent(downloads, partial_downloads),
+                    bytes_sent: daily.bytes_sent.get(&number).copied().unwrap_or_default(),
+                    unique_listeners: episode_visitors.get(&number).map_or(0, |visitors| {
+                        u32::try_from(visitors.estimate()).unwrap_or(u32::MAX)
+                    }),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let badge_downloads = episode_downloads
+            .iter()
+            .filter(|episode| {
+                config
+                    .badge
+                    .episode
+                    .map_or(true, |number| episode.number == number)
+            })
+            .map(|episode| u64::from(episode.downloads))
+            .sum();
+        fs::write(
+            export_dir.join(&config.report_files.badge),
+            serde_json::to_vec(&Badge::new(badge_downloads, &config.badge))?,
+        )?;
+        write_totals_csv(
+            File::create(export_dir.join(&config.report_files.totals_csv))?,
+            &episode_downloads,
+        )?;
+        Ok(episode_downloads)
+    });
+
+    let (recent_downloads, latest_episode) =
+        attempt_section(&mut failed_sections, "recent downloads", || {
+            let mut recent_downloads = BTreeMap::default();
+            // Gather all the episode numbers to ensure every entry is complete
+            let mut latest_episode = 0;
+            for (key, downloads) in data.recent_downloads? {
+                latest_episode = latest_episode.max(key.episode);
+                let for_date = recent_downloads
+                    .entry(format_date(key.date)?)
+                    .or_insert_with(RecentDownloads::default);
+                for_date.episodes.insert(key.episode, downloads);
+            }
+            Ok((recent_downloads, latest_episode))
+        });
+
+    let weekly_downloads = attempt_section(&mut failed_sections, "weekly downloads", || {
+        Ok(PeriodDownloads::by_week(data.weekly_downloads?))
+    });
+    let monthly_downloads = attempt_section(&mut failed_sections, "monthly downloads", || {
+        Ok(PeriodDownloads::by_month(data.monthly_downloads?))
+    });
+    let seasons = SeasonReport::totals(&episode_downloads, &config.episode_seasons);
+    let campaigns = attempt_section(&mut failed_sections, "campaigns", || {
+        Ok(CampaignReport::totals(
+            data.campaign_downloads?,
+            days_ago(30)?,
+        ))
+    });
+    let losing_momentum = attempt_section(&mut failed_sections, "losing momentum", || {
+        Ok(MomentumReport::losing(&daily.episode_weeks, days_ago(14)?))
+    });
+    let weekday_downloads = WEEKDAYS
+        .into_iter()
+        .zip(daily.weekday_totals)
+        .map(|(name, downloads)| WeekdayReport { name, downloads })
+        .collect();
+
+    let report = Report {
+        failed_sections: failed_sections.clone(),
+        date_range: DateRange::new(&daily.dates),
+        episode_downloads,
+        recent_downloads,
+        latest_episode,
+        weekly_downloads,
+        monthly_downloads,
+        weekday_downloads,
+        format_trends: ShareTrends::new(daily.format_downloads_by_month),
+        protocol_trends: ShareTrends::new(daily.protocol_downloads_by_month),
+        listening_minutes: ListeningMinutes {
+            last_30_days: daily.recent_listening_seconds / 60,
+            all_time: daily.all_time_listening_seconds / 60,
+        },
+        distinct_visitors: daily.visitors.estimate(),
+        player_downloads: daily
+            .player_downloads
+            .into_iter()
+            .map(|(number, by_player)| EpisodePlayers {
+                number,
+                first_party: by_player[Player::FirstParty as usize],
+                third_party: by_player[Player::ThirdParty as usize],
+                unknown: by_player[Player::Unknown as usize],
+            })
+            .collect(),
+        seasons,
+        losing_momentum,
+        campaigns,
+        client_downloads: DownloadShare::totals(daily.client_downloads),
+        country_downloads: DownloadShare::totals(daily.country_downloads),
+        referrer_downloads: DownloadShare::totals(daily.referrer_downloads),
+        variant_downloads: DownloadShare::totals(daily.variant_downloads),
+        bandwidth_costs: BandwidthCosts::estimate(&daily.bytes_sent, &config.bandwidth_cost),
+        episode_number_offset: config.episode_number_offset,
+    };
+    fs::write(
+        export_dir.join(&config.report_files.json),
+        serde_json::to_vec_pretty(&report)?,
+    )?;
+    fs::write(
+        export_dir.join(&config.report_files.html),
+        report.render()?.as_bytes(),
+    )?;
+    if let Some(range) = &report.date_range {
+        println!("The report covers {} to {}", range.first, range.last);
+        if !range.missing_days.is_empty() {
+            eprintln!(
+                "Warning: no downloads were imported for {}, which may mean a log file is missing",
+                range.missing_days.join(", ")
+            );
+        }
+    }
+    let mut days = daily.records;
+    days.sort_by(|a, b| a.episode.cmp(&b.episode).then_with(|| a.date.cmp(&b.date)));
+    fs::write(
+        export_dir.join(&config.report_files.downloads_json),
+        serde_json::to_vec_pretty(&DownloadsJson {
+            episodes: &report.episode_downloads,
+            days: &days,
+        })?,
+    )?;
+    Ok(failed_sections)
+}
+
+/// Writes each episode's all-time totals as CSV, ordered by episode.
+fn write_totals_csv<W: Write>(output: W, episodes: &[EpisodeReport]) -> anyhow::Result<()> {
+    let mut csv = csv::Writer::from_writer(output);
+    csv.write_record(["episode", "full", "partial", "bytes"])?;
+    let mut episodes = episodes.iter().collect::<Vec<_>>();
+    episodes.sort_by_key(|episode| episode.number);
+    for episode in episodes {
+        csv.write_record([
+            episode.number.to_string(),
+            episode.downloads.to_string(),
+            episode.partial_downloads.to_string(),
+            episode.bytes_sent.to_string(),
+        ])?;
+    }
+    csv.flush()?;
+    Ok(())
+}
+
+/// Returns the result of `generate`, or the default value after recording
+/// `section` as failed.
+fn attempt_section<T: Default>(
+    failed_sections: &mut Vec<&'static str>,
+    section: &'static str,
+    generate: impl FnOnce() -> anyhow::Result<T>,
+) -> T {
+    generate().unwrap_or_else(|err| {
+        eprintln!("Warning: skipping the report's {section}: {err}");
+        failed_sections.push(section);
+        T::default()
+    })
+}
+
+#[cfg(test)]
+use bonsaidb::core::schema::{SerializedCollection, SerializedView};
+
+#[cfg(test)]
+use crate::hll;
+#[cfg(test)]
+use crate::schema::CompleteDownloads;
+#[cfg(test)]
+use crate::testing::{
+    insert_downloads, memory_database, tally_logs, tally_sample, test_episodes_dir, SAMPLE_LOG,
+};
+
+#[test]
+fn report_is_self_contained() {
+    let rendered = Report {
+        failed_sections: Vec::new(),
+        date_range: Some(DateRange {
+            first: String::from("2023-05-01"),
+            last: String::from("2023-05-08"),
+            missing_days: vec![String::from("2023-05-04")],
+        }),
+        episode_downloads: vec![EpisodeReport {
+            number: 1,
+            downloads: 10,
+            partial_downloads: 10,
+            next_milestone: None,
+            completion_percent: Some(50),
+            bytes_sent: 3 << 29,
+            unique_listeners: 7,
+        }],
+        recent_downloads: BTreeMap::new(),
+        latest_episode: 1,
+        weekly_downloads: BTreeMap::new(),
+        monthly_downloads: BTreeMap::new(),
+        weekday_downloads: Vec::new(),
+        format_trends: ShareTrends::default(),
+        protocol_trends: ShareTrends::default(),
+        listening_minutes: ListeningMinutes::default(),
+        distinct_visitors: 0,
+        player_downloads: Vec::new(),
+        seasons: Vec::new(),
+        losing_momentum: Vec::new(),
+        campaigns: Vec::new(),
+        client_downloads: Vec::new(),
+        country_downloads: Vec::new(),
+        referrer_downloads: Vec::new(),
+        variant_downloads: Vec::new(),
+        bandwidth_costs: None,
+        episode_number_offset: 0,
+    }
+    .render()
+    .unwrap();
+    for external in [
+        "src=\"http",
+        "src=\"//",
+        "href=\"http",
+        "href=\"//",
+        "url(http",
+        "url(//",
+        "@import",
+    ] {
+        assert!(
+            !rendered.contains(external),
+            "report references an external asset: {external}"
+        );
+    }
+}
+
+#[test]
+fn date_ranges() {
+    use time::macros::date;
+
+    assert_eq!(DateRange::new(&BTreeSet::new()), None);
+    assert_eq!(
+        DateRange::new(&BTreeSet::from([date!(2023 - 05 - 08)])),
+        Some(DateRange {
+            first: String::from("2023-05-08"),
+            last: String::from("2023-05-08"),
+            missing_days: Vec::new(),
+        })
+    );
+    let dates = BTreeSet::from([
+        date!(2023 - 12 - 29),
+        date!(2023 - 12 - 30),
+        date!(2024 - 01 - 02),
+        date!(2023 - 12 - 27),
+    ]);
+    assert_eq!(
+        DateRange::new(&dates),
+        Some(DateRange {
+            first: String::from("2023-12-27"),
+            last: String::from("2024-01-02"),
+            missing_days: ["2023-12-28", "2023-12-31", "2024-01-01"]
+                .map(String::from)
+                .to_vec(),
+        })
+    );
+}
+
+#[test]
+fn bandwidth_costs() {
+    let bytes_sent = BTreeMap::from([(1, 3 << 30), (2, 1 << 29)]);
+    assert_eq!(
+        BandwidthCosts::estimate(&bytes_sent, &BandwidthCostConfig::default()),
+        None
+    );
+
+    let config = BandwidthCostConfig {
+        price_per_gib: Some(0.08),
+        currency: String::from("EUR"),
+    };
+    let costs = BandwidthCosts::estimate(&bytes_sent, &config).unwrap();
+    assert_eq!(costs.currency, "EUR");
+    assert_eq!(
+        costs.episodes,
+        [
+            EpisodeCost {
+                number: 1,
+                gib_sent: 3.0,
+                cost: 3.0 * 0.08,
+            },
+            EpisodeCost {
+                number: 2,
+                gib_sent: 0.5,
+                cost: 0.5 * 0.08,
+            },
+        ]
+    );
+    assert_eq!(costs.total, 3.5 * 0.08);
+}
+
+#[test]
+fn format_trends() {
+    let trends = ShareTrends::new(BTreeMap::from([
+        (
+            String::from("2023-04"),
+            BTreeMap::from([(String::from("mp3"), 3), (String::from("m4a"), 1)]),
+        ),
+        (
+            String::from("2023-05"),
+            BTreeMap::from([(String::from("mp3"), 1), (String::from("m4a"), 3)]),
+        ),
+        (
+            String::from("2023-06"),
+            BTreeMap::from([(String::from("m4a"), 2)]),
+        ),
+    ]));
+    assert_eq!(trends.categories, ["m4a", "mp3"]);
+    assert_eq!(trends.months[0].shares["mp3"], 75.);
+    assert_eq!(trends.months[1].shares["mp3"], 25.);
+    assert_eq!(trends.months[1].shares["m4a"], 75.);
+    assert_eq!(trends.months[2].shares["m4a"], 100.);
+    assert!(!trends.months[2].shares.contains_key("mp3"));
+}
+
+#[test]
+fn milestones() {
+    let milestones = Config::default().milestones;
+    assert_eq!(
+        Milestone::next(4_863, &milestones),
+        Some(Milestone {
+            downloads: 5_000,
+            remaining: 137
+        })
+    );
+    assert_eq!(
+        Milestone::next(5_000, &milestones),
+        Some(Milestone {
+            downloads: 10_000,
+            remaining: 5_000
+        })
+    );
+    assert_eq!(
+        Milestone::next(0, &milestones),
+        Some(Milestone {
+            downloads: 10,
+            remaining: 10
+        })
+    );
+    assert_eq!(Milestone::next(1_000_000, &milestones), None);
+}
+
+#[test]
+fn custom_csv_format() {
+    let db = memory_database();
+    insert_downloads(
+        &db,
+        1,
+        TimestampAsDays::now(),
+        PodcastDownloads {
+            full_downloads: 10,
+            partial_downloads: 2,
+            ..PodcastDownloads::default()
+        },
+    );
+    let mut config = Config::default();
+    config.csv.delimiter = ';';
+    config.csv.columns = ["day", "ep", "complete", "incomplete"].map(String::from);
+    let dir = std::env::temp_dir().join("crabtrics-custom-csv-format");
+    generate_report(&db, &config, &dir).unwrap();
+
+    let exported = fs::read_to_string(dir.join("downloads.csv")).unwrap();
+    let lines = exported.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0], "day;ep;complete;incomplete");
+    assert!(lines[1].ends_with(";1;10;2"), "{}", lines[1]);
+
+    config.csv.write_header = false;
+    generate_report(&db, &config, &dir).unwrap();
+    let exported = fs::read_to_string(dir.join("downloads.csv")).unwrap();
+    assert_eq!(exported.lines().count(), 1);
+}
+
+#[test]
+fn date_ordered_csv() {
+    let db = memory_database();
+    for (episode, days) in [(1, 1), (2, 2), (1, 2), (2, 1)] {
+        insert_downloads(
+            &db,
+            episode,
+            days_ago(days).unwrap(),
+            PodcastDownloads {
+                full_downloads: 1,
+                ..PodcastDownloads::default()
+            },
+        );
+    }
+    let dir = std::env::temp_dir().join("crabtrics-date-ordered-csv");
+    let rows = |config: &Config| {
+        generate_report(&db, config, &dir).unwrap();
+        fs::read_to_string(dir.join("downloads.csv"))
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(|line| {
+                let mut fields = line.split(',');
+                let date = fields.next().unwrap().to_string();
+                (date, fields.next().unwrap().parse::<u16>().unwrap())
+            })
+            .collect::<Vec<_>>()
+    };
+    // downloads.csv names each date's month, unlike format_date.
+    let day = |days| {
+        let date = OffsetDateTime::from(SystemTime::try_from(days_ago(days).unwrap()).unwrap());
+        format!("{:04}-{:02}-{:02}", date.year(), date.month(), date.day())
+    };
+
+    assert_eq!(
+        rows(&Config::default()),
+        [(day(2), 1), (day(1), 1), (day(2), 2), (day(1), 2)]
+    );
+    let config = Config {
+        csv_order: RowOrder::Date,
+        ..Config::default()
+    };
+    assert_eq!(
+        rows(&config),
+        [(day(2), 1), (day(2), 2), (day(1), 1), (day(1), 2)]
+    );
+}
+
+#[test]
+fn episode_number_offset() {
+    let db = memory_database();
+    insert_downloads(
+        &db,
+        100,
+        TimestampAsDays::now(),
+        PodcastDownloads {
+            full_downloads: 5,
+            ..PodcastDownloads::default()
+        },
+    );
+    let dir = std::env::temp_dir().join("crabtrics-episode-number-offset");
+    let _ = fs::remove_dir_all(&dir);
+    let config = Config {
+        episode_number_offset: 99,
+        ..Config::default()
+    };
+    generate_report(&db, &config, &dir).unwrap();
+
+    let html = fs::read_to_string(dir.join("index.html")).unwrap();
+    assert!(
+        html.contains("<a href=\"episodes/100.html\">1</a>"),
+        "{html}"
+    );
+    let page = fs::read_to_string(dir.join("episodes").join("100.html")).unwrap();
+    assert!(page.contains("Episode 1<"), "{page}");
+
+    let exported = fs::read_to_string(dir.join("downloads.csv")).unwrap();
+    assert!(exported.lines().nth(1).unwrap().ends_with(",100,5,0"));
+    let report: serde_json::Value =
+        serde_json::from_slice(&fs::read(dir.join("report.json")).unwrap()).unwrap();
+    assert_eq!(report["episode_downloads"][0]["number"], 100);
+    assert_eq!(
+        CompleteDownloads::entries(&db).reduce_grouped().unwrap()[0].key,
+        100
+    );
+}
+
+#[test]
+fn unique_csv_column() {
+    let db = memory_database();
+    let mut visitors = HyperLogLog::new(hll::DEFAULT_PRECISION);
+    for visitor in 0_u64..3 {
+        visitors.insert(hll::hash(&visitor.to_le_bytes()));
+    }
+    insert_downloads(
+        &db,
+        1,
+        TimestampAsDays::now(),
+        PodcastDownloads {
+            full_downloads: 10,
+            partial_downloads: 2,
+            visitors,
+            ..PodcastDownloads::default()
+        },
+    );
+    let dir = std::env::temp_dir().join("crabtrics-unique-csv-column");
+    let mut config = Config::default();
+    generate_report(&db, &config, &dir).unwrap();
+    let exported = fs::read_to_string(dir.join("downloads.csv")).unwrap();
+    assert_eq!(exported.lines().next(), Some("date,episode,full,partial"));
+
+    config.csv.unique_column = Some(String::from("unique"));
+    generate_report(&db, &config, &dir).unwrap();
+    let exported = fs::read_to_string(dir.join("downloads.csv")).unwrap();
+    let lines = exported.lines().collect::<Vec<_>>();
+    assert_eq!(lines[0], "date,episode,full,partial,unique");
+    assert!(lines[1].ends_with(",1,10,2,3"), "{}", lines[1]);
+}
+
+#[test]
+fn bytes_csv_column() {
+    let db = memory_database();
+    insert_downloads(
+        &db,
+        1,
+        TimestampAsDays::now(),
+        PodcastDownloads {
+            full_downloads: 10,
+            partial_downloads: 2,
+            bytes_sent: 5_000_000_000,
+            ..PodcastDownloads::default()
+        },
+    );
+    let dir = std::env::temp_dir().join("crabtrics-bytes-csv-column");
+    let mut config = Config::default();
+    config.csv.bytes_column = Some(String::from("bytes"));
+    generate_report(&db, &config, &dir).unwrap();
+    let exported = fs::read_to_string(dir.join("downloads.csv")).unwrap();
+    let lines = exported.lines().collect::<Vec<_>>();
+    assert_eq!(lines[0], "date,episode,full,partial,bytes");
+    assert!(lines[1].ends_with(",1,10,2,5000000000"), "{}", lines[1]);
+
+    let report: serde_json::Value =
+        serde_json::from_slice(&fs::read(dir.join("report.json")).unwrap()).unwrap();
+    assert_eq!(
+        report["episode_downloads"][0]["bytes_sent"],
+        5_000_000_000_u64
+    );
+}
+
+#[test]
+fn totals_csv() {
+    let db = memory_database();
+    let today = TimestampAsDays::now();
+    let yesterday = days_ago(1).unwrap();
+    for (episode, date, full_downloads, partial_downloads, bytes_sent) in [
+        (2, yesterday, 4, 1, 2_000),
+        (1, today, 10, 2, 5_000),
+        (2, today, 3, 0, 1_000),
+        (1, yesterday, 1, 1, 500),
+    ] {
+        insert_downloads(
+            &db,
+            episode,
+            date,
+            PodcastDownloads {
+                full_downloads,
+                partial_downloads,
+                bytes_sent,
+                ..PodcastDownloads::default()
+            },
+        );
+    }
+    let dir = std::env::temp_dir().join("crabtrics-totals-csv");
+    generate_report(&db, &Config::default(), &dir).unwrap();
+    let exported = fs::read_to_string(dir.join("totals.csv")).unwrap();
+    let lines = exported.lines().collect::<Vec<_>>();
+    assert_eq!(
+        lines,
+        ["episode,full,partial,bytes", "1,11,3,5500", "2,7,1,3000"]
+    );
+    let episodes = PodcastDownloads::all(&db)
+        .query()
+        .unwrap()
+        .iter()
+        .map(|document| document.header.id.episode)
+        .collect::<BTreeSet<_>>();
+    assert_eq!(lines.len() - 1, episodes.len());
+}
+
+#[test]
+fn client_downloads() {
+    let downloads = tally_sample(SAMPLE_LOG, 213_001, &Config::default());
+    assert_eq!(
+        downloads.full_downloads_by_client,
+        BTreeMap::from([(String::from("Browser"), 1)])
+    );
+
+    assert_eq!(
+        DownloadShare::totals(BTreeMap::from([
+            (String::from("Apple Podcasts"), 2),
+            (String::from("Overcast"), 1),
+        ])),
+        [
+            DownloadShare {
+                name: String::from("Apple Podcasts"),
+                downloads: 2,
+                percent: 67,
+            },
+            DownloadShare {
+                name: String::from("Overcast"),
+                downloads: 1,
+                percent: 33,
+            },
+        ]
+    );
+}
+
+#[test]
+fn protocol_trends() {
+    let dir = test_episodes_dir("protocol-trends", 1_000);
+    let (first, _) = SAMPLE_LOG.split_once('\n').unwrap();
+    let june = |requestor: &str, protocol: &str| {
+        let line = first
+            .replace("08/May/2023", "08/Jun/2023")
+            .replace("172.56.208.121", requestor)
+            .replace(" HTTP/1.1", protocol);
+        format!("{line}\n")
+    };
+    let logs = [
+        format!("{first}\n"),
+        june("172.56.209.122", " HTTP/2.0"),
+        june("172.56.210.123", " HTTP/3"),
+        june("172.56.211.124", ""),
+        june("172.56.212.125", " HTTP/3"),
+    ]
+    .concat();
+
+    let mut downloads_by_month = BTreeMap::new();
+    for (key, downloads) in tally_logs(&logs, &dir, &Config::default()) {
+        let timestamp = OffsetDateTime::from(SystemTime::try_from(key.date).unwrap());
+        let month = format!("{:04}-{:02}", timestamp.year(), timestamp.month() as u8);
+        add_monthly_downloads(
+            &mut downloads_by_month,
+            &month,
+            &downloads.full_downloads_by_protocol,
+        );
+    }
+    let trends = ShareTrends::new(downloads_by_month);
+    assert_eq!(trends.categories, ["", "HTTP/1.1", "HTTP/2.0", "HTTP/3"]);
+    assert_eq!(trends.months[0].month, "2023-05");
+    assert_eq!(trends.months[0].shares["HTTP/1.1"], 100.);
+    assert_eq!(trends.months[1].shares["HTTP/2.0"], 25.);
+    assert_eq!(trends.months[1].shares["HTTP/3"], 50.);
+    assert_eq!(trends.months[1].shares[""], 25.);
+}
+
+#[test]
+fn partial_reports() {
+    let db = memory_database();
+    insert_downloads(
+        &db,
+        1,
+        TimestampAsDays::now(),
+        PodcastDownloads {
+            full_downloads: 10,
+            ..PodcastDownloads::default()
+        },
+    );
+    let dir = std::env::temp_dir().join("crabtrics-partial-reports");
+    let _ = fs::remove_dir_all(&dir);
+    let data = ReportData {
+        episode_totals: Err(anyhow::anyhow!("corrupt view")),
+        ..ReportData::query(&db, &Config::default())
+    };
+
+    let failed = write_report(data, &Config::default(), &dir).unwrap();
+    assert_eq!(failed, ["episode totals"]);
+    let exported = fs::read_to_string(dir.join("downloads.csv")).unwrap();
+    assert_eq!(exported.lines().count(), 2);
+    let report = fs::read_to_string(dir.join("index.html")).unwrap();
+    assert!(report.contains("episode totals"));
+    assert!(!dir.join("badge.json").exists());
+}
+
+#[test]
+fn downloads_json() {
+    let db = memory_database();
+    for (episode, days, full_downloads) in [(2, 0, 4), (1, 0, 3), (1, 1, 5)] {
+        insert_downloads(
+            &db,
+            episode,
+            days_ago(days).unwrap(),
+            PodcastDownloads {
+                full_downloads,
+                partial_downloads: 1,
+                ..PodcastDownloads::default()
+            },
+        );
+    }
+    let dir = std::env::temp_dir().join("crabtrics-downloads-json");
+    let _ = fs::remove_dir_all(&dir);
+    write_report(
+        ReportData::query(&db, &Config::default()),
+        &Config::default(),
+        &dir,
+    )
+    .unwrap();
+
+    #[derive(serde::Deserialize)]
+    struct Exported {
+        episodes: Vec<serde_json::Value>,
+        days: Vec<DailyRecord>,
+    }
+    let exported: Exported =
+        serde_json::from_slice(&fs::read(dir.join("downloads.json")).unwrap()).unwrap();
+    assert_eq!(
+        exported
+            .days
+            .iter()
+            .map(|day| (day.episode, day.full, day.partial))
+            .collect::<Vec<_>>(),
+        [(1, 5, 1), (1, 3, 1), (2, 4, 1)]
+    );
+    assert!(exported.days[0].date < exported.days[1].date);
+    let totals = CompleteDownloads::entries(&db).reduce_grouped().unwrap();
+    assert_eq!(exported.episodes.len(), totals.len());
+    for (episode, total) in exported.episodes.iter().zip(totals) {
+        assert_eq!(episode["number"], total.key);
+        assert_eq!(episode["downloads"], total.value);
+    }
+}
+
+#[test]
+fn configured_report_files() {
+    let db = memory_database();
+    insert_downloads(
+        &db,
+        1,
+        TimestampAsDays::now(),
+        PodcastDownloads {
+            full_downloads: 5,
+            ..PodcastDownloads::default()
+        },
+    );
+    let config = Config {
+        report_files: crate::config::ReportFiles {
+            html: String::from("stats.html"),
+            json: String::from("stats.json"),
+            badge: String::from("stats-badge.json"),
+            csv: String::from("stats.csv"),
+            downloads_json: String::from("stats-downloads.json"),
+            totals_csv: String::from("stats-totals.csv"),
+        },
+        ..Config::default()
+    };
+    let dir = std::env::temp_dir().join("crabtrics-configured-report-files");
+    let _ = fs::remove_dir_all(&dir);
+
+    generate_report(&db, &config, &dir).unwrap();
+    for file in [
+        "stats.html",
+        "stats.json",
+        "stats-badge.json",
+        "stats.csv",
+        "stats-downloads.json",
+        "stats-totals.csv",
+    ] {
+        assert!(dir.join(file).exists(), "{file}");
+    }
+    for file in [
+        "index.html",
+        "report.json",
+        "badge.json",
+        "downloads.csv",
+        "downloads.json",
+        "totals.csv",
+    ] {
+        assert!(!dir.join(file).exists(), "{file}");
+    }
+    let page = fs::read_to_string(dir.join("episodes/1.html")).unwrap();
+    assert!(page.contains("href=\"../stats.html\""));
+}
+
+#[test]
+fn completion_rates() {
+    assert_eq!(completion_percent(3, 1), Some(75));
+    assert_eq!(completion_percent(1, 2), Some(33));
+    assert_eq!(completion_percent(0, 4), Some(0));
+    assert_eq!(completion_percent(0, 0), None);
+
+    let db = memory_database();
+    for (episode, days, full_downloads, partial_downloads) in
+        [(1, 1, 3, 0), (1, 0, 6, 3), (2, 0, 0, 0)]
+    {
+        insert_downloads(
+            &db,
+            episode,
+            days_ago(days).unwrap(),
+            PodcastDownloads {
+                full_downloads,
+                partial_downloads,
+                ..PodcastDownloads::default()
+            },
+        );
+    }
+    let dir = std::env::temp_dir().join("crabtrics-completion-rates");
+    let _ = fs::remove_dir_all(&dir);
+
+    write_report(
+        ReportData::query(&db, &Config::default()),
+        &Config::default(),
+        &dir,
+    )
+    .unwrap();
+    let report: serde_json::Value =
+        serde_json::from_slice(&fs::read(dir.join("report.json")).unwrap()).unwrap();
+    assert_eq!(report["episode_downloads"][0]["partial_downloads"], 3);
+    assert_eq!(report["episode_downloads"][0]["completion_percent"], 75);
+    assert_eq!(report["episode_downloads"][1]["partial_downloads"], 0);
+    assert_eq!(
+        report["episode_downloads"][1]["completion_percent"],
+        serde_json::Value::Null
+    );
+}
+
+#[test]
+fn unique_listeners() {
+    let db = memory_database();
+    // The same visitors return on the second day, along with new ones.
+    for (days, range) in [(1, 0..1_000_u32), (0, 500..2_000)] {
+        let mut visitors = HyperLogLog::new(hll::DEFAULT_PRECISION);
+        for visitor in range {
+            visitors.insert(hll::hash(&visitor.to_le_bytes()));
+        }
+        insert_downloads(
+            &db,
+            1,
+            days_ago(days).unwrap(),
+            PodcastDownloads {
+                full_downloads: 1,
+                visitors,
+                ..PodcastDownloads::default()
+            },
+        );
+    }
+    let dir = std::env::temp_dir().join("crabtrics-unique-listeners");
+    let _ = fs::remove_dir_all(&dir);
+
+    write_report(
+        ReportData::query(&db, &Config::default()),
+        &Config::default(),
+        &dir,
+    )
+    .unwrap();
+    let report: serde_json::Value =
+        serde_json::from_slice(&fs::read(dir.join("report.json")).unwrap()).unwrap();
+    let estimate = report["episode_downloads"][0]["unique_listeners"]
+        .as_f64()
+        .unwrap();
+    assert!((estimate - 2_000.0).abs() < 2_000.0 * 0.1, "{estimate}");
+}
+
+#[test]
+fn season_totals() {
+    let episode_downloads = [(1, 10), (2, 20), (3, 30), (4, 40), (5, 50)]
+        .into_iter()
+        .map(|(number, downloads)| EpisodeReport {
+            number,
+            downloads,
+            partial_downloads: 0,
+            next_milestone: None,
+            completion_percent: None,
+            bytes_sent: 0,
+            unique_listeners: 0,
+        })
+        .collect::<Vec<_>>();
+    let seasons = HashMap::from([
+        (4, String::from("Season 2")),
+        (1, String::from("Season 1")),
+        (2, String::from("Season 1")),
+        (5, String::from("Season 2")),
+    ]);
+
+    assert_eq!(
+        SeasonReport::totals(&episode_downloads, &seasons),
+        [
+            SeasonReport {
+                name: String::from("Season 1"),
+                episodes: 2,
+                downloads: 30,
+            },
+            SeasonReport {
+                name: String::from("Season 2"),
+                episodes: 2,
+                downloads: 90,
+            },
+            SeasonReport {
+                name: String::from("Unassigned"),
+                episodes: 1,
+                downloads: 30,
+            },
+        ]
+    );
+    assert!(SeasonReport::totals(&episode_downloads, &HashMap::new()).is_empty());
+}
+
+#[test]
+fn losing_momentum() {
+    let db = memory_database();
+    let day = |days| days_ago(days).unwrap();
+    // Episode 1 is declining and episode 2 is growing. Episode 3 declined
+    // even more steeply, but was released too recently to compare weeks.
+    for (episode, days, full_downloads) in [
+        (1, 20, 5),
+        (1, 10, 40),
+        (1, 3, 10),
+        (2, 10, 5),
+        (2, 3, 20),
+        (3, 10, 40),
+        (3, 3, 1),
+        // Today's downloads aren't part of either week.
+        (1, 0, 100),
+    ] {
+        insert_downloads(
+            &db,
+            episode,
+            day(days),
+            PodcastDownloads {
+                full_downloads,
+                ..PodcastDownloads::default()
+            },
+        );
+    }
+    let dir = std::env::temp_dir().join("crabtrics-losing-momentum");
+    let _ = fs::remove_dir_all(&dir);
+
+    write_report(
+        ReportData::query(&db, &Config::default()),
+        &Config::default(),
+        &dir,
+    )
+    .unwrap();
+    let report: serde_json::Value =
+        serde_json::from_slice(&fs::read(dir.join("report.json")).unwrap()).unwrap();
+    assert_eq!(
+        report["losing_momentum"],
+        serde_json::json!([{
+            "number": 1,
+            "previous_week": 40,
+            "last_week": 10,
+            "decline_percent": 75,
+        }])
+    );
+    assert!(fs::read_to_string(dir.join("index.html"))
+        .unwrap()
+        .contains("Losing Momentum"));
+}
+
+#[test]
+fn campaign_attribution() {
+    let dir = test_episodes_dir("campaign-attribution", 213_001);
+    let (first, _) = SAMPLE_LOG.split_once('\n').unwrap();
+    let request = |address: &str, path: &str| {
+        format!(
+            "{}\n",
+            first
+                .replace("172.56.208.121", address)
+                .replace("/episode-001.m4a", path)
+                .replace(" 106500 ", " 213001 ")
+        )
+    };
+    let logs = [
+        request(
+            "10.0.1.1",
+            "/episode-001.m4a?utm_source=mastodon&utm_campaign=spring2024",
+        ),
+        request("10.0.2.1", "/episode-001.m4a?utm_campaign=spring2024"),
+        request("10.0.3.1", "/episode-001.m4a"),
+        request("10.0.4.1", "/episode-001.m4a?utm_source=mastodon"),
+    ]
+    .concat();
+    let (key, downloads) = tally_logs(&logs, &dir, &Config::default())
+        .into_iter()
+        .next()
+        .unwrap();
+    assert_eq!(key.episode, 1);
+    assert_eq!(
+        downloads.full_downloads_by_campaign,
+        BTreeMap::from([
+            (String::from("organic"), 2),
+            (String::from("spring2024"), 2)
+        ])
+    );
+
+    let db = memory_database();
+    insert_downloads(&db, key.episode, TimestampAsDays::now(), downloads);
+    insert_downloads(
+        &db,
+        2,
+        days_ago(60).unwrap(),
+        PodcastDownloads {
+            full_downloads_by_campaign: BTreeMap::from([(String::from("spring2024"), 5)]),
+            ..PodcastDownloads::default()
+        },
+    );
+    let campaign_downloads = ReportData::query(&db, &Config::default())
+        .campaign_downloads
+        .unwrap();
+    assert_eq!(
+        CampaignReport::totals(campaign_downloads, days_ago(30).unwrap()),
+        [
+            CampaignReport {
+                name: String::from("spring2024"),
+                last_30_days: 2,
+                all_time: 7,
+            },
+            CampaignReport {
+                name: String::from("organic"),
+                last_30_days: 2,
+                all_time: 2,
+            },
+        ]
+    );
+    assert!(CampaignReport::totals(Vec::new(), days_ago(30).unwrap()).is_empty());
+}
+
+#[test]
+fn weekly_and_monthly_downloads() {
+    use time::macros::date;
+
+    let db = memory_database();
+    for (episode, date, full_downloads) in [
+        (1, date!(2020 - 12 - 28), 1),
+        (1, date!(2020 - 12 - 31), 2),
+        (2, date!(2021 - 01 - 03), 4),
+        (1, date!(2021 - 01 - 04), 8),
+        (2, date!(2021 - 02 - 01), 16),
+    ] {
+        insert_downloads(
+            &db,
+            episode,
+            TimestampAsDays::try_from(SystemTime::from(date.midnight().assume_utc())).unwrap(),
+            PodcastDownloads {
+                full_downloads,
+                ..PodcastDownloads::default()
+            },
+        );
+    }
+
+    let data = ReportData::query(&db, &Config::default());
+    let weekly = PeriodDownloads::by_week(data.weekly_downloads.unwrap());
+    assert_eq!(
+        weekly,
+        BTreeMap::from([
+            (
+                String::from("2020-W53"),
+                PeriodDownloads {
+                    total: 7,
+                    episodes: BTreeMap::from([(1, 3), (2, 4)]),
+                }
+            ),
+            (
+                String::from("2021-W01"),
+                PeriodDownloads {
+                    total: 8,
+                    episodes: BTreeMap::from([(1, 8)]),
+                }
+            ),
+            (
+                String::from("2021-W05"),
+                PeriodDownloads {
+                    total: 16,
+                    episodes: BTreeMap::from([(2, 16)]),
+                }
+            ),
+        ])
+    );
+    assert_eq!(
+        latest_periods(&weekly, 2)
+            .into_iter()
+            .map(|(week, _)| week.as_str())
+            .collect::<Vec<_>>(),
+        ["2021-W01", "2021-W05"]
+    );
+
+    let monthly = PeriodDownloads::by_month(data.monthly_downloads.unwrap());
+    assert_eq!(
+        monthly,
+        BTreeMap::from([
+            (
+                String::from("2020-12"),
+                PeriodDownloads {
+                    total: 3,
+                    episodes: BTreeMap::from([(1, 3)]),
+                }
+            ),
+            (
+                String::from("2021-01"),
+                PeriodDownloads {
+                    total: 12,
+                    episodes: BTreeMap::from([(1, 8), (2, 4)]),
+                }
+            ),
+            (
+                String::from("2021-02"),
+                PeriodDownloads {
+                    total: 16,
+                    episodes: BTreeMap::from([(2, 16)]),
+                }
+            ),
+        ])
+    );
+}
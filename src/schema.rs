@@ -1,20 +1,315 @@
-use std::ops::RangeFrom;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::{RangeFrom, RangeInclusive};
+use std::time::SystemTime;
 
 use bonsaidb::core::document::Emit;
 use bonsaidb::core::key::time::TimestampAsDays;
 use bonsaidb::core::key::Key;
 use bonsaidb::core::schema::{Collection, CollectionMapReduce, Schema, View, ViewSchema};
 use serde::{Deserialize, Serialize};
+use time::{Date, OffsetDateTime};
+
+use crate::hll::HyperLogLog;
 
 #[derive(Schema, Debug)]
-#[schema(name = "crabtrics", collections = [PodcastDownloads])]
+#[schema(name = "crabtrics", collections = [PodcastDownloads, ImportedLog, ImportRun, RequestorTotals])]
 pub struct Crabtrics;
 
+/// A summary of a run that imported logs, kept as an audit trail of how the
+/// database was populated.
+#[derive(Debug, Collection, Serialize, Deserialize)]
+#[collection(name = "import-runs")]
+pub struct ImportRun {
+    /// The Unix timestamp the run started at.
+    pub started_at: i64,
+    /// The version of crabtrics that ran.
+    pub version: String,
+    /// The log files that were imported.
+    pub files: Vec<String>,
+    pub lines_read: u64,
+    /// The lines that weren't counted towards any episode, such as other
+    /// requests, errors, and requests from bots.
+    pub lines_skipped: u64,
+    /// The per-day download records that were added or changed.
+    pub records_written: u32,
+}
+
+/// A log file whose downloads have been written to the database, keyed by its
+/// path.
 #[derive(Debug, Collection, Serialize, Deserialize)]
-#[collection(name = "podcast-downloads", primary_key = EpisodeDateKey, views = [CompleteDownloads, DownloadsByDate])]
+#[collection(name = "imported-logs", primary_key = String)]
+pub struct ImportedLog {
+    pub size: u64,
+    /// When the file was last modified, in nanoseconds since the Unix epoch.
+    pub modified: u64,
+    /// The Unix timestamp of the start of each day the file had requests on
+    /// that were counted.
+    pub days: BTreeSet<i64>,
+}
+
+/// The bytes each visitor downloaded of an episode on a day, before they were
+/// classified into downloads. These are only kept with
+/// `--keep-requestor-totals`, so that downloads can be classified again
+/// after the logs they came from are gone.
+///
+/// Visitors are stored by their key, a hash of their network and optionally
+/// their user agent as configured by `[visitor_identity]`, rather than by
+/// their address.
+#[derive(Debug, Default, PartialEq, Collection, Serialize, Deserialize)]
+#[collection(name = "requestor-totals", primary_key = EpisodeDateKey)]
+pub struct RequestorTotals {
+    /// The size of each kind of file downloaded, such as `m4a`.
+    pub sizes: BTreeMap<String, u32>,
+    pub requestors: Vec<RequestorTotal>,
+}
+
+impl RequestorTotals {
+    /// Adds the visitors in `other`, such as from another server's logs.
+    pub fn merge(&mut self, other: Self) {
+        self.sizes.extend(other.sizes);
+        self.requestors.extend(other.requestors);
+    }
+}
+
+/// One visit's bytes of an episode, as kept in [`RequestorTotals`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RequestorTotal {
+    /// The [key](crate::visitors::VisitorKey) of the visitor.
+    pub visitor: u64,
+    /// Which of the visitor's sessions this was, counting up each time they
+    /// returned after the dedup window.
+    pub session: u32,
+    /// The Unix timestamp of the visit's first request.
+    pub first_request: i64,
+    /// The bytes downloaded of each kind of file, at most its size.
+    pub bytes: BTreeMap<String, u32>,
+}
+
+#[derive(Debug, Default, PartialEq, Collection, Serialize, Deserialize)]
+#[collection(name = "podcast-downloads", primary_key = EpisodeDateKey, views = [CompleteDownloads, PartialDownloads, EpisodeVisitors, DownloadsByDate, DownloadsByWeek, DownloadsByMonth, DownloadsByCampaign, DownloadsByCountry, DownloadsByReferrer])]
 pub struct PodcastDownloads {
     pub full_downloads: u16,
     pub partial_downloads: u16,
+    /// Full downloads by the weekday (Monday first) they began on, in the
+    /// reporting timezone at the time of import.
+    #[serde(default)]
+    pub full_downloads_by_weekday: [u16; 7],
+    /// Full downloads by the file extension that was downloaded.
+    #[serde(default)]
+    pub full_downloads_by_extension: BTreeMap<String, u16>,
+    /// The estimated number of seconds listened to, when the episode's
+    /// duration is configured.
+    #[serde(default)]
+    pub listening_seconds: u32,
+    /// Full downloads by the kind of player they were played with, indexed
+    /// by [`Player`](crate::players::Player).
+    #[serde(default)]
+    pub full_downloads_by_player: [u16; 3],
+    /// Full downloads by the HTTP version they were first requested with,
+    /// which is empty when the request didn't include one.
+    #[serde(default)]
+    pub full_downloads_by_protocol: BTreeMap<String, u16>,
+    /// Full downloads by the `utm_campaign` of the URL they were first
+    /// requested with, or `organic` for requests without one.
+    #[serde(default)]
+    pub full_downloads_by_campaign: BTreeMap<String, u16>,
+    /// Full downloads by the ISO 3166-1 code of the country their requestor's
+    /// address is in, which is only counted with a GeoIP database. Downloads
+    /// from addresses the database doesn't know aren't counted here.
+    #[serde(default)]
+    pub full_downloads_by_country: BTreeMap<String, u16>,
+    /// Full downloads by the [source](crate::players::referrer_source) their
+    /// first request was referred from, such as `wayofthecrab.com` or
+    /// `direct`.
+    #[serde(default)]
+    pub full_downloads_by_referrer: BTreeMap<String, u16>,
+    /// Full downloads by the [name](crate::clients::PodcastClient::name) of
+    /// the client their user agent identified.
+    #[serde(default)]
+    pub full_downloads_by_client: BTreeMap<String, u16>,
+    /// Full downloads by the configured variant of the episode that was
+    /// downloaded, or `plain` for the episode's usual files. This is empty
+    /// when no variants are configured.
+    #[serde(default)]
+    pub full_downloads_by_variant: BTreeMap<String, u16>,
+    /// A sketch of the distinct visitors who requested the episode, whether or
+    /// not they finished downloading it.
+    #[serde(default)]
+    pub visitors: HyperLogLog,
+    /// The bytes sent by every response for the episode, including partial
+    /// and repeated downloads.
+    #[serde(default)]
+    pub bytes_sent: u64,
+    /// The requests from bots, which aren't counted as downloads. This is
+    /// only tracked when `count_requests` is set in the `[bots]` table.
+    #[serde(default)]
+    pub bot_requests: u32,
+    /// The `HEAD` requests and conditional `GET`s answered with `304 Not
+    /// Modified`, which apps make to check an episode without downloading
+    /// it, and which aren't counted as downloads.
+    #[serde(default)]
+    pub probes: u32,
+    /// The Unix timestamp when these counts last changed, or 0 if they haven't
+    /// changed since before this was tracked.
+    #[serde(default)]
+    pub written_at: i64,
+}
+
+impl PodcastDownloads {
+    /// Adds the downloads counted in `other` to `self`. Each count saturates
+    /// at its type's maximum rather than overflowing.
+    pub fn accumulate(&mut self, other: &Self) {
+        self.full_downloads = self.full_downloads.saturating_add(other.full_downloads);
+        self.partial_downloads = self
+            .partial_downloads
+            .saturating_add(other.partial_downloads);
+        self.listening_seconds = self
+            .listening_seconds
+            .saturating_add(other.listening_seconds);
+        self.bytes_sent = self.bytes_sent.saturating_add(other.bytes_sent);
+        self.bot_requests = self.bot_requests.saturating_add(other.bot_requests);
+        self.probes = self.probes.saturating_add(other.probes);
+        self.visitors.merge(&other.visitors);
+        for (total, downloads) in self
+            .full_downloads_by_weekday
+            .iter_mut()
+            .zip(other.full_downloads_by_weekday)
+        {
+            *total = total.saturating_add(downloads);
+        }
+        for (total, downloads) in self
+            .full_downloads_by_player
+            .iter_mut()
+            .zip(other.full_downloads_by_player)
+        {
+            *total = total.saturating_add(downloads);
+        }
+        for (extension, downloads) in &other.full_downloads_by_extension {
+            let total = self
+                .full_downloads_by_extension
+                .entry(extension.clone())
+                .or_default();
+            *total = total.saturating_add(*downloads);
+        }
+        for (protocol, downloads) in &other.full_downloads_by_protocol {
+            let total = self
+                .full_downloads_by_protocol
+                .entry(protocol.clone())
+                .or_default();
+            *total = total.saturating_add(*downloads);
+        }
+        for (campaign, downloads) in &other.full_downloads_by_campaign {
+            let total = self
+                .full_downloads_by_campaign
+                .entry(campaign.clone())
+                .or_default();
+            *total = total.saturating_add(*downloads);
+        }
+        for (country, downloads) in &other.full_downloads_by_country {
+            let total = self
+                .full_downloads_by_country
+                .entry(country.clone())
+                .or_default();
+            *total = total.saturating_add(*downloads);
+        }
+        for (referrer, downloads) in &other.full_downloads_by_referrer {
+            let total = self
+                .full_downloads_by_referrer
+                .entry(referrer.clone())
+                .or_default();
+            *total = total.saturating_add(*downloads);
+        }
+        for (client, downloads) in &other.full_downloads_by_client {
+            let total = self
+                .full_downloads_by_client
+                .entry(client.clone())
+                .or_default();
+            *total = total.saturating_add(*downloads);
+        }
+        for (variant, downloads) in &other.full_downloads_by_variant {
+            let total = self
+                .full_downloads_by_variant
+                .entry(variant.clone())
+                .or_default();
+            *total = total.saturating_add(*downloads);
+        }
+    }
+
+    /// Replaces each count in `self` with the count in `other` when it is
+    /// larger. Visitor sketches are merged, which takes the larger of each
+    /// of their registers.
+    pub fn maximize(&mut self, other: &Self) {
+        self.full_downloads = self.full_downloads.max(other.full_downloads);
+        self.partial_downloads = self.partial_downloads.max(other.partial_downloads);
+        self.listening_seconds = self.listening_seconds.max(other.listening_seconds);
+        self.bytes_sent = self.bytes_sent.max(other.bytes_sent);
+        self.bot_requests = self.bot_requests.max(other.bot_requests);
+        self.probes = self.probes.max(other.probes);
+        self.visitors.merge(&other.visitors);
+        for (total, downloads) in self
+            .full_downloads_by_weekday
+            .iter_mut()
+            .zip(other.full_downloads_by_weekday)
+        {
+            *total = (*total).max(downloads);
+        }
+        for (total, downloads) in self
+            .full_downloads_by_player
+            .iter_mut()
+            .zip(other.full_downloads_by_player)
+        {
+            *total = (*total).max(downloads);
+        }
+        for (extension, downloads) in &other.full_downloads_by_extension {
+            let total = self
+                .full_downloads_by_extension
+                .entry(extension.clone())
+                .or_default();
+            *total = (*total).max(*downloads);
+        }
+        for (protocol, downloads) in &other.full_downloads_by_protocol {
+            let total = self
+                .full_downloads_by_protocol
+                .entry(protocol.clone())
+                .or_default();
+            *total = (*total).max(*downloads);
+        }
+        for (campaign, downloads) in &other.full_downloads_by_campaign {
+            let total = self
+                .full_downloads_by_campaign
+                .entry(campaign.clone())
+                .or_default();
+            *total = (*total).max(*downloads);
+        }
+        for (country, downloads) in &other.full_downloads_by_country {
+            let total = self
+                .full_downloads_by_country
+                .entry(country.clone())
+                .or_default();
+            *total = (*total).max(*downloads);
+        }
+        for (referrer, downloads) in &other.full_downloads_by_referrer {
+            let total = self
+                .full_downloads_by_referrer
+                .entry(referrer.clone())
+                .or_default();
+            *total = (*total).max(*downloads);
+        }
+        for (client, downloads) in &other.full_downloads_by_client {
+            let total = self
+                .full_downloads_by_client
+                .entry(client.clone())
+                .or_default();
+            *total = (*total).max(*downloads);
+        }
+        for (variant, downloads) in &other.full_downloads_by_variant {
+            let total = self
+                .full_downloads_by_variant
+                .entry(variant.clone())
+                .or_default();
+            *total = (*total).max(*downloads);
+        }
+    }
 }
 
 #[derive(Debug, Clone, View, ViewSchema, Serialize, Deserialize)]
@@ -41,12 +336,80 @@ impl CollectionMapReduce for CompleteDownloads {
     }
 }
 
+/// Each episode's partial downloads, the counterpart of [`CompleteDownloads`].
+#[derive(Debug, Clone, View, ViewSchema, Serialize, Deserialize)]
+#[view(name = "partial", key = u16, value = u32, collection = PodcastDownloads)]
+pub struct PartialDownloads;
+
+impl CollectionMapReduce for PartialDownloads {
+    fn map<'doc>(
+        &self,
+        document: bonsaidb::core::document::CollectionDocument<<Self::View as View>::Collection>,
+    ) -> bonsaidb::core::schema::ViewMapResult<'doc, Self> {
+        document.header.emit_key_and_value(
+            document.header.id.episode,
+            document.contents.partial_downloads as u32,
+        )
+    }
+
+    fn reduce(
+        &self,
+        mappings: &[bonsaidb::core::schema::ViewMappedValue<'_, Self>],
+        _rereduce: bool,
+    ) -> bonsaidb::core::schema::ReduceResult<Self::View> {
+        Ok(mappings.iter().map(|mapping| mapping.value).sum())
+    }
+}
+
+/// Each episode's distinct visitors across every day, merged from each day's
+/// sketch. Merged sketches are rereduced like any other value, so adding a
+/// day doesn't require reading the others again.
+#[derive(Debug, Clone, View, ViewSchema, Serialize, Deserialize)]
+#[view(name = "visitors", key = u16, value = HyperLogLog, collection = PodcastDownloads)]
+pub struct EpisodeVisitors;
+
+impl CollectionMapReduce for EpisodeVisitors {
+    fn map<'doc>(
+        &self,
+        document: bonsaidb::core::document::CollectionDocument<<Self::View as View>::Collection>,
+    ) -> bonsaidb::core::schema::ViewMapResult<'doc, Self> {
+        document
+            .header
+            .emit_key_and_value(document.header.id.episode, document.contents.visitors)
+    }
+
+    fn reduce(
+        &self,
+        mappings: &[bonsaidb::core::schema::ViewMappedValue<'_, Self>],
+        _rereduce: bool,
+    ) -> bonsaidb::core::schema::ReduceResult<Self::View> {
+        let mut visitors = HyperLogLog::default();
+        for mapping in mappings {
+            visitors.merge(&mapping.value);
+        }
+        Ok(visitors)
+    }
+}
+
 #[derive(Debug, Hash, Copy, Clone, Eq, PartialEq, Key, Ord, PartialOrd)]
 pub struct EpisodeDateKey {
     pub episode: u16,
     pub date: TimestampAsDays,
 }
 
+impl EpisodeDateKey {
+    /// Returns the range of keys covering every day of `episode`.
+    pub fn range_for_episode(episode: u16) -> RangeInclusive<EpisodeDateKey> {
+        Self {
+            episode,
+            date: TimestampAsDays::MIN,
+        }..=Self {
+            episode,
+            date: TimestampAsDays::MAX,
+        }
+    }
+}
+
 #[derive(Debug, Hash, Copy, Clone, Eq, PartialEq, Key, Ord, PartialOrd)]
 pub struct DateEpisodeKey {
     pub date: TimestampAsDays,
@@ -80,3 +443,398 @@ impl CollectionMapReduce for DownloadsByDate {
         )
     }
 }
+
+/// Returns the UTC calendar date of `date`.
+fn utc_date(date: TimestampAsDays) -> Result<Date, bonsaidb::core::Error> {
+    Ok(OffsetDateTime::from(SystemTime::try_from(date)?).date())
+}
+
+/// An episode and an ISO 8601 week, which starts on a Monday and belongs to
+/// the year its Thursday is in. The last days of December can be in week 1 of
+/// the next year, and the first days of January in week 52 or 53 of the last.
+#[derive(Debug, Hash, Copy, Clone, Eq, PartialEq, Key, Ord, PartialOrd)]
+pub struct EpisodeWeekKey {
+    pub episode: u16,
+    pub year: i32,
+    pub week: u8,
+}
+
+impl EpisodeWeekKey {
+    pub fn new(episode: u16, date: Date) -> Self {
+        let (year, week, _) = date.to_iso_week_date();
+        Self {
+            episode,
+            year,
+            week,
+        }
+    }
+}
+
+/// Each episode's full downloads by ISO week.
+#[derive(Debug, Clone, View, ViewSchema)]
+#[view(name = "by-week", collection = PodcastDownloads, key = EpisodeWeekKey, value = u32)]
+pub struct DownloadsByWeek;
+
+impl CollectionMapReduce for DownloadsByWeek {
+    fn map<'doc>(
+        &self,
+        document: bonsaidb::core::document::CollectionDocument<<Self::View as View>::Collection>,
+    ) -> bonsaidb::core::schema::ViewMapResult<'doc, Self> {
+        document.header.emit_key_and_value(
+            EpisodeWeekKey::new(
+                document.header.id.episode,
+                utc_date(document.header.id.date)?,
+            ),
+            u32::from(document.contents.full_downloads),
+        )
+    }
+
+    fn reduce(
+        &self,
+        mappings: &[bonsaidb::core::schema::ViewMappedValue<'_, Self>],
+        _rereduce: bool,
+    ) -> bonsaidb::core::schema::ReduceResult<Self::View> {
+        Ok(mappings.iter().map(|mapping| mapping.value).sum())
+    }
+}
+
+/// An episode and a calendar month, with `month` counting from 1.
+#[derive(Debug, Hash, Copy, Clone, Eq, PartialEq, Key, Ord, PartialOrd)]
+pub struct EpisodeMonthKey {
+    pub episode: u16,
+    pub year: i32,
+    pub month: u8,
+}
+
+impl EpisodeMonthKey {
+    pub fn new(episode: u16, date: Date) -> Self {
+        Self {
+            episode,
+            year: date.year(),
+            month: date.month() as u8,
+        }
+    }
+}
+
+/// Each episode's full downloads by calendar month.
+#[derive(Debug, Clone, View, ViewSchema)]
+#[view(name = "by-month", collection = PodcastDownloads, key = EpisodeMonthKey, value = u32)]
+pub struct DownloadsByMonth;
+
+impl CollectionMapReduce for DownloadsByMonth {
+    fn map<'doc>(
+        &self,
+        document: bonsaidb::core::document::CollectionDocument<<Self::View as View>::Collection>,
+    ) -> bonsaidb::core::schema::ViewMapResult<'doc, Self> {
+        document.header.emit_key_and_value(
+            EpisodeMonthKey::new(
+                document.header.id.episode,
+                utc_date(document.header.id.date)?,
+            ),
+            u32::from(document.contents.full_downloads),
+        )
+    }
+
+    fn reduce(
+        &self,
+        mappings: &[bonsaidb::core::schema::ViewMappedValue<'_, Self>],
+        _rereduce: bool,
+    ) -> bonsaidb::core::schema::ReduceResult<Self::View> {
+        Ok(mappings.iter().map(|mapping| mapping.value).sum())
+    }
+}
+
+#[derive(Debug, Hash, Clone, Eq, PartialEq, Key, Ord, PartialOrd)]
+pub struct CampaignDateKey {
+    pub campaign: String,
+    pub date: TimestampAsDays,
+}
+
+/// Full downloads by marketing campaign and day, across every episode.
+#[derive(Debug, Clone, View, ViewSchema)]
+#[view(name = "by-campaign", collection = PodcastDownloads, key = CampaignDateKey, value = u32)]
+pub struct DownloadsByCampaign;
+
+impl CollectionMapReduce for DownloadsByCampaign {
+    fn map<'doc>(
+        &self,
+        document: bonsaidb::core::document::CollectionDocument<<Self::View as View>::Collection>,
+    ) -> bonsaidb::core::schema::ViewMapResult<'doc, Self> {
+        document
+            .contents
+            .full_downloads_by_campaign
+            .iter()
+            .map(|(campaign, downloads)| {
+                document.header.emit_key_and_value(
+                    CampaignDateKey {
+                        campaign: campaign.clone(),
+                        date: document.header.id.date,
+                    },
+                    u32::from(*downloads),
+                )
+            })
+            .collect()
+    }
+
+    fn reduce(
+        &self,
+        mappings: &[bonsaidb::core::schema::ViewMappedValue<'_, Self>],
+        _rereduce: bool,
+    ) -> bonsaidb::core::schema::ReduceResult<Self::View> {
+        Ok(mappings.iter().map(|mapping| mapping.value).sum())
+    }
+}
+
+#[derive(Debug, Hash, Clone, Eq, PartialEq, Key, Ord, PartialOrd)]
+pub struct EpisodeCountryKey {
+    pub episode: u16,
+    pub country: String,
+}
+
+/// Each episode's full downloads by country, across every day.
+#[derive(Debug, Clone, View, ViewSchema)]
+#[view(name = "by-country", collection = PodcastDownloads, key = EpisodeCountryKey, value = u32)]
+pub struct DownloadsByCountry;
+
+impl CollectionMapReduce for DownloadsByCountry {
+    fn map<'doc>(
+        &self,
+        document: bonsaidb::core::document::CollectionDocument<<Self::View as View>::Collection>,
+    ) -> bonsaidb::core::schema::ViewMapResult<'doc, Self> {
+        document
+            .contents
+            .full_downloads_by_country
+            .iter()
+            .map(|(country, downloads)| {
+                document.header.emit_key_and_value(
+                    EpisodeCountryKey {
+                        episode: document.header.id.episode,
+                        country: country.clone(),
+                    },
+                    u32::from(*downloads),
+                )
+            })
+            .collect()
+    }
+
+    fn reduce(
+        &self,
+        mappings: &[bonsaidb::core::schema::ViewMappedValue<'_, Self>],
+        _rereduce: bool,
+    ) -> bonsaidb::core::schema::ReduceResult<Self::View> {
+        Ok(mappings.iter().map(|mapping| mapping.value).sum())
+    }
+}
+
+#[derive(Debug, Hash, Clone, Eq, PartialEq, Key, Ord, PartialOrd)]
+pub struct EpisodeReferrerKey {
+    pub episode: u16,
+    pub referrer: String,
+}
+
+/// Each episode's full downloads by referrer source, across every day.
+#[derive(Debug, Clone, View, ViewSchema)]
+#[view(name = "by-referrer", collection = PodcastDownloads, key = EpisodeReferrerKey, value = u32)]
+pub struct DownloadsByReferrer;
+
+impl CollectionMapReduce for DownloadsByReferrer {
+    fn map<'doc>(
+        &self,
+        document: bonsaidb::core::document::CollectionDocument<<Self::View as View>::Collection>,
+    ) -> bonsaidb::core::schema::ViewMapResult<'doc, Self> {
+        document
+            .contents
+            .full_downloads_by_referrer
+            .iter()
+            .map(|(referrer, downloads)| {
+                document.header.emit_key_and_value(
+                    EpisodeReferrerKey {
+                        episode: document.header.id.episode,
+                        referrer: referrer.clone(),
+                    },
+                    u32::from(*downloads),
+                )
+            })
+            .collect()
+    }
+
+    fn reduce(
+        &self,
+        mappings: &[bonsaidb::core::schema::ViewMappedValue<'_, Self>],
+        _rereduce: bool,
+    ) -> bonsaidb::core::schema::ReduceResult<Self::View> {
+        Ok(mappings.iter().map(|mapping| mapping.value).sum())
+    }
+}
+
+#[test]
+fn week_and_month_boundaries() {
+    use time::macros::date;
+
+    for (date, year, week) in [
+        (date!(2020 - 12 - 28), 2020, 53),
+        (date!(2020 - 12 - 31), 2020, 53),
+        (date!(2021 - 01 - 03), 2020, 53),
+        (date!(2021 - 01 - 04), 2021, 1),
+        (date!(2024 - 12 - 29), 2024, 52),
+        (date!(2024 - 12 - 30), 2025, 1),
+        (date!(2026 - 01 - 01), 2026, 1),
+    ] {
+        assert_eq!(
+            EpisodeWeekKey::new(1, date),
+            EpisodeWeekKey {
+                episode: 1,
+                year,
+                week
+            },
+            "{date}"
+        );
+    }
+
+    assert_eq!(
+        EpisodeMonthKey::new(1, date!(2020 - 12 - 31)),
+        EpisodeMonthKey {
+            episode: 1,
+            year: 2020,
+            month: 12
+        }
+    );
+    assert_eq!(
+        EpisodeMonthKey::new(1, date!(2021 - 01 - 01)),
+        EpisodeMonthKey {
+            episode: 1,
+            year: 2021,
+            month: 1
+        }
+    );
+    assert!(
+        EpisodeWeekKey::new(1, date!(2020 - 12 - 31))
+            < EpisodeWeekKey::new(1, date!(2021 - 01 - 04))
+    );
+}
+
+#[cfg(test)]
+fn days_since_epoch(days: u64) -> TimestampAsDays {
+    use std::time::{Duration, SystemTime};
+
+    TimestampAsDays::try_from(SystemTime::UNIX_EPOCH + Duration::from_secs(days * 24 * 60 * 60))
+        .unwrap()
+}
+
+/// Asserts that `a` and `b` survive encoding and that their encoded bytes sort
+/// the same way as their `Ord` implementation, which range queries rely on.
+#[cfg(test)]
+fn assert_key_encoding<K>(a: K, b: K)
+where
+    K: for<'k> Key<'k> + Ord + std::fmt::Debug,
+{
+    use bonsaidb::core::key::{ByteSource, KeyEncoding};
+
+    let a_bytes = a.as_ord_bytes().unwrap();
+    let b_bytes = b.as_ord_bytes().unwrap();
+    assert_eq!(
+        K::from_ord_bytes(ByteSource::Borrowed(&a_bytes[..])).unwrap(),
+        a
+    );
+    assert_eq!(
+        K::from_ord_bytes(ByteSource::Borrowed(&b_bytes[..])).unwrap(),
+        b
+    );
+    assert_eq!(a.cmp(&b), a_bytes.cmp(&b_bytes));
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn episode_date_key_encoding(
+        a_episode: u16,
+        a_days in 0_u64..100_000,
+        b_episode: u16,
+        b_days in 0_u64..100_000,
+    ) {
+        assert_key_encoding(
+            EpisodeDateKey { episode: a_episode, date: days_since_epoch(a_days) },
+            EpisodeDateKey { episode: b_episode, date: days_since_epoch(b_days) },
+        );
+    }
+
+    #[test]
+    fn episode_range(a_episode: u16, b_episode: u16, b_days in 0_u64..100_000) {
+        let b = EpisodeDateKey { episode: b_episode, date: days_since_epoch(b_days) };
+        proptest::prop_assert_eq!(
+            EpisodeDateKey::range_for_episode(a_episode).contains(&b),
+            a_episode == b_episode
+        );
+    }
+
+    #[test]
+    fn date_episode_key_encoding(
+        a_episode: u16,
+        a_days in 0_u64..100_000,
+        b_episode: u16,
+        b_days in 0_u64..100_000,
+    ) {
+        let a = DateEpisodeKey { date: days_since_epoch(a_days), episode: a_episode };
+        let b = DateEpisodeKey { date: days_since_epoch(b_days), episode: b_episode };
+        proptest::prop_assert_eq!(
+            DateEpisodeKey::range_starting_at(a.date).contains(&b),
+            b_days >= a_days
+        );
+        assert_key_encoding(a, b);
+    }
+
+    #[test]
+    fn episode_week_key_encoding(
+        a_episode: u16,
+        a_days in 0_u64..100_000,
+        b_episode: u16,
+        b_days in 0_u64..100_000,
+    ) {
+        let week = |episode, days| {
+            EpisodeWeekKey::new(episode, utc_date(days_since_epoch(days)).unwrap())
+        };
+        assert_key_encoding(week(a_episode, a_days), week(b_episode, b_days));
+    }
+
+    #[test]
+    fn episode_month_key_encoding(
+        a_episode: u16,
+        a_days in 0_u64..100_000,
+        b_episode: u16,
+        b_days in 0_u64..100_000,
+    ) {
+        let month = |episode, days| {
+            EpisodeMonthKey::new(episode, utc_date(days_since_epoch(days)).unwrap())
+        };
+        assert_key_encoding(month(a_episode, a_days), month(b_episode, b_days));
+    }
+
+    #[test]
+    fn campaign_date_key_encoding(
+        a_campaign in "[a-z0-9 ]{0,8}",
+        a_days in 0_u64..100_000,
+        b_campaign in "[a-z0-9 ]{0,8}",
+        b_days in 0_u64..100_000,
+    ) {
+        assert_key_encoding(
+            CampaignDateKey { campaign: a_campaign, date: days_since_epoch(a_days) },
+            CampaignDateKey { campaign: b_campaign, date: days_since_epoch(b_days) },
+        );
+    }
+}
+
+#[test]
+fn accumulate_saturates() {
+    let mut downloads = PodcastDownloads {
+        full_downloads: u16::MAX - 1,
+        full_downloads_by_country: [(String::from("NZ"), u16::MAX)].into(),
+        ..PodcastDownloads::default()
+    };
+    downloads.accumulate(&PodcastDownloads {
+        full_downloads: 2,
+        full_downloads_by_country: [(String::from("NZ"), 1)].into(),
+        ..PodcastDownloads::default()
+    });
+    assert_eq!(downloads.full_downloads, u16::MAX);
+    assert_eq!(downloads.full_downloads_by_country["NZ"], u16::MAX);
+}
@@ -0,0 +1,313 @@
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::time::Duration;
+
+use crabtrics::config::{ReportFiles, ServerConfig};
+
+/// The largest request head that is read before responding.
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// Returns the file served at `path` and its content type. Each file is
+/// served at its name, and the HTML report is also served at `/`.
+fn route<'a>(path: &str, files: &'a ReportFiles) -> Option<(&'a str, &'static str)> {
+    let routes = [
+        (files.html.as_str(), "text/html; charset=utf-8"),
+        (files.json.as_str(), "application/json"),
+        (files.badge.as_str(), "application/json"),
+        (files.csv.as_str(), "text/csv"),
+        (files.downloads_json.as_str(), "application/json"),
+        (files.totals_csv.as_str(), "text/csv"),
+    ];
+    if path == "/" {
+        return Some(routes[0]);
+    }
+    let name = path.strip_prefix('/')?;
+    routes.into_iter().find(|(file, _)| *file == name)
+}
+
+/// The `username:password` pair every request must present with HTTP Basic
+/// authentication.
+pub struct Credentials {
+    /// The `Authorization` header value that grants access.
+    expected: String,
+}
+
+impl Credentials {
+    /// Reads the credentials from the `CRABTRICS_USERNAME` and
+    /// `CRABTRICS_PASSWORD` environment variables, falling back to the
+    /// `[server]` table. Serving without credentials isn't supported, as the
+    /// report is private.
+    pub fn load(config: &ServerConfig) -> anyhow::Result<Self> {
+        let username = std::env::var("CRABTRICS_USERNAME")
+            .ok()
+            .or_else(|| config.username.clone());
+        let password = std::env::var("CRABTRICS_PASSWORD")
+            .ok()
+            .or_else(|| config.password.clone());
+        match (username, password) {
+            (Some(username), Some(password)) => Ok(Self::new(&username, &password)),
+            _ => anyhow::bail!(
+                "set a username and password in the [server] table or the CRABTRICS_USERNAME \
+                 and CRABTRICS_PASSWORD environment variables before serving the report"
+            ),
+        }
+    }
+
+    pub fn new(username: &str, password: &str) -> Self {
+        Self {
+            expected: format!(
+                "Basic {}",
+                base64(format!("{username}:{password}").as_bytes())
+            ),
+        }
+    }
+
+    /// Returns true if `authorization` is the expected `Authorization` header.
+    ///
+    /// Every byte is compared regardless of where the first difference is, so
+    /// that the time taken doesn't reveal how much of a guess was right.
+    fn accepts(&self, authorization: &[u8]) -> bool {
+        let expected = self.expected.as_bytes();
+        let mut difference = u8::from(expected.len() != authorization.len());
+        for (index, byte) in expected.iter().enumerate() {
+            difference |= byte ^ authorization.get(index).copied().unwrap_or(0);
+        }
+        difference == 0
+    }
+}
+
+/// Serves the report in `reports`, written to the file names in `files`, over
+/// HTTP at `address`, one request at a time, until the process is stopped.
+pub fn serve(
+    address: &str,
+    reports: &Path,
+    files: &ReportFiles,
+    credentials: &Credentials,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    println!(
+        "Serving {} at http://{}",
+        reports.display(),
+        listener.local_addr()?
+    );
+    for stream in listener.incoming() {
+        if let Err(err) = stream
+            .map_err(anyhow::Error::from)
+            .and_then(|stream| handle_connection(stream, reports, files, credentials))
+        {
+            eprintln!("Warning: couldn't respond to a request: {err}");
+        }
+    }
+    Ok(())
+}
+
+fn handle_connection(
+    mut stream: TcpStream,
+    reports: &Path,
+    files: &ReportFiles,
+    credentials: &Credentials,
+) -> anyhow::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    let response = loop {
+        let read = match stream.read(&mut buffer) {
+            Ok(read) => read,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+        request.extend_from_slice(&buffer[..read]);
+        match respond(&request, reports, files, credentials) {
+            Some(response) => break response,
+            None if read == 0 => return Ok(()),
+            None if request.len() > MAX_REQUEST_SIZE => {
+                break Response::text(431, "Request Header Fields Too Large")
+            }
+            None => {}
+        }
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        response.reason,
+        response.content_type,
+        response.body.len()
+    )?;
+    if response.status == 401 {
+        stream.write_all(b"WWW-Authenticate: Basic realm=\"crabtrics\", charset=\"UTF-8\"\r\n")?;
+    }
+    stream.write_all(b"\r\n")?;
+    stream.write_all(&response.body)?;
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    reason: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn text(status: u16, reason: &'static str) -> Self {
+        Self {
+            status,
+            reason,
+            content_type: "text/plain; charset=utf-8",
+            body: reason.as_bytes().to_vec(),
+        }
+    }
+}
+
+/// Returns the response to `request`, or None if the request's head hasn't
+/// been completely received yet.
+fn respond(
+    request: &[u8],
+    reports: &Path,
+    files: &ReportFiles,
+    credentials: &Credentials,
+) -> Option<Response> {
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut parsed = httparse::Request::new(&mut headers);
+    match parsed.parse(request) {
+        Ok(httparse::Status::Complete(_)) => {}
+        Ok(httparse::Status::Partial) => return None,
+        Err(_) => return Some(Response::text(400, "Bad Request")),
+    }
+
+    let authorized = parsed
+        .headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("authorization"))
+        .is_some_and(|header| credentials.accepts(header.value));
+    if !authorized {
+        return Some(Response::text(401, "Unauthorized"));
+    }
+    if parsed.method != Some("GET") {
+        return Some(Response::text(405, "Method Not Allowed"));
+    }
+
+    let path = parsed.path.unwrap_or_default();
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+    let Some((file, content_type)) = route(path, files) else {
+        return Some(Response::text(404, "Not Found"));
+    };
+    Some(match fs::read(reports.join(file)) {
+        Ok(body) => Response {
+            status: 200,
+            reason: "OK",
+            content_type,
+            body,
+        },
+        Err(err) if err.kind() == ErrorKind::NotFound => Response::text(404, "Not Found"),
+        Err(err) => {
+            eprintln!("Warning: couldn't read {file}: {err}");
+            Response::text(500, "Internal Server Error")
+        }
+    })
+}
+
+/// Encodes `bytes` as padded standard base64.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk
+            .iter()
+            .enumerate()
+            .fold(0_u32, |group, (index, byte)| {
+                group | (u32::from(*byte) << (16 - 8 * index))
+            });
+        for index in 0..4 {
+            if index <= chunk.len() {
+                encoded.push(char::from(
+                    ALPHABET[((group >> (18 - 6 * index)) & 63) as usize],
+                ));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[test]
+fn basic_auth() {
+    assert_eq!(
+        base64(b"Aladdin:open sesame"),
+        "QWxhZGRpbjpvcGVuIHNlc2FtZQ=="
+    );
+    assert_eq!(base64(b"ab"), "YWI=");
+    assert_eq!(base64(b"abc"), "YWJj");
+
+    let reports = std::env::temp_dir().join("crabtrics-basic-auth");
+    let _ = fs::remove_dir_all(&reports);
+    fs::create_dir_all(&reports).unwrap();
+    fs::write(reports.join("index.html"), "<p>private</p>").unwrap();
+    let credentials = Credentials::new("Aladdin", "open sesame");
+    let request = |authorization: Option<&str>| {
+        let authorization = authorization
+            .map(|value| format!("Authorization: {value}\r\n"))
+            .unwrap_or_default();
+        let request = format!("GET / HTTP/1.1\r\nHost: localhost\r\n{authorization}\r\n");
+        respond(
+            request.as_bytes(),
+            &reports,
+            &ReportFiles::default(),
+            &credentials,
+        )
+        .unwrap()
+    };
+
+    assert_eq!(request(None).status, 401);
+    assert_eq!(
+        request(Some("Basic QWxhZGRpbjpvcGVuIHNlc2FtZR==")).status,
+        401
+    );
+    assert_eq!(request(Some("Basic QWxhZGRpbjpvcGVuIHNlc2FtZ")).status, 401);
+    let authorized = request(Some("Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ=="));
+    assert_eq!(authorized.status, 200);
+    assert_eq!(authorized.body, b"<p>private</p>");
+
+    assert!(respond(
+        b"GET / HTTP/1.1\r\nHost:",
+        &reports,
+        &ReportFiles::default(),
+        &credentials
+    )
+    .is_none());
+}
+
+#[test]
+fn configured_routes() {
+    let files = ReportFiles {
+        html: String::from("stats.html"),
+        csv: String::from("stats.csv"),
+        ..ReportFiles::default()
+    };
+    assert_eq!(
+        route("/", &files),
+        Some(("stats.html", "text/html; charset=utf-8"))
+    );
+    assert_eq!(
+        route("/stats.html", &files),
+        Some(("stats.html", "text/html; charset=utf-8"))
+    );
+    assert_eq!(route("/stats.csv", &files), Some(("stats.csv", "text/csv")));
+    assert_eq!(
+        route("/totals.csv", &files),
+        Some(("totals.csv", "text/csv"))
+    );
+    assert_eq!(
+        route("/report.json", &files),
+        Some(("report.json", "application/json"))
+    );
+    assert_eq!(route("/index.html", &files), None);
+    assert_eq!(route("/downloads.csv", &files), None);
+}
@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bonsaidb::core::keyvalue::KeyValue;
+use bonsaidb::local::Database;
+
+/// The key-value entry storing the size of every episode file seen so far.
+const SIZES_KEY: &str = "episode-sizes";
+
+/// The sizes of episode files, which are needed to tell full downloads from
+/// partial ones.
+///
+/// Sizes are read from the episodes directory and recorded in the database,
+/// so that a later import can run where the files aren't present by using
+/// only the recorded sizes.
+#[derive(Debug)]
+pub struct EpisodeSizes {
+    directory: Option<PathBuf>,
+    recorded: HashMap<String, u32>,
+}
+
+impl EpisodeSizes {
+    /// Reads each size from the file in `directory`.
+    pub fn from_directory(directory: &Path) -> Self {
+        Self {
+            directory: Some(directory.to_path_buf()),
+            recorded: HashMap::new(),
+        }
+    }
+
+    /// Loads the sizes recorded by previous imports. Sizes are read from the
+    /// files in `directory` when it is given, or else only the recorded sizes
+    /// are used.
+    pub fn load(db: &Database, directory: Option<&Path>) -> anyhow::Result<Self> {
+        let recorded: Option<HashMap<String, u32>> = db.get_key(SIZES_KEY).into()?;
+        Ok(Self {
+            directory: directory.map(Path::to_path_buf),
+            recorded: recorded.unwrap_or_default(),
+        })
+    }
+
+    /// Records every size that has been read.
+    pub fn save(&self, db: &Database) -> anyhow::Result<()> {
+        db.set_key(SIZES_KEY, &self.recorded).execute()?;
+        Ok(())
+    }
+
+    /// Returns true if no sizes have been read or recorded.
+    pub fn is_empty(&self) -> bool {
+        self.recorded.is_empty()
+    }
+
+    /// Returns the size of `file`, a path relative to the episodes directory.
+    pub fn size(&mut self, file: &str) -> anyhow::Result<u32> {
+        if let Some(directory) = &self.directory {
+            let size = fs::metadata(directory.join(file))?.len().try_into()?;
+            self.recorded.insert(file.to_string(), size);
+            return Ok(size);
+        }
+
+        match self.recorded.get(file) {
+            Some(size) => Ok(*size),
+            None => anyhow::bail!(
+                "the size of {file} hasn't been recorded, import once with the episode files \
+                 present first"
+            ),
+        }
+    }
+}
+
+#[test]
+fn recorded_sizes() {
+    use crate::testing::memory_database;
+
+    let directory = std::env::temp_dir().join("crabtrics-recorded-sizes");
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir_all(&directory).unwrap();
+    fs::write(directory.join("episode-001.m4a"), vec![0; 1_000]).unwrap();
+
+    let db = memory_database();
+    let mut sizes = EpisodeSizes::load(&db, Some(&directory)).unwrap();
+    assert_eq!(sizes.size("episode-001.m4a").unwrap(), 1_000);
+    assert!(sizes.size("episode-002.m4a").is_err());
+    sizes.save(&db).unwrap();
+
+    fs::remove_dir_all(&directory).unwrap();
+    let mut sizes = EpisodeSizes::load(&db, None).unwrap();
+    assert_eq!(sizes.size("episode-001.m4a").unwrap(), 1_000);
+    assert!(sizes.size("episode-002.m4a").is_err());
+}
@@ -0,0 +1,106 @@
+use std::path::Path;
+
+use bonsaidb::core::schema::SerializedCollection;
+use bonsaidb::local::Database;
+use crabtrics::export::DailyRecord;
+use crabtrics::schema::PodcastDownloads;
+use rusqlite::{params, Connection};
+
+/// The tables, indexes, and views of an exported database. Every statement is
+/// safe to run against a database that was already exported to.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS downloads (
+        date TEXT NOT NULL,
+        episode INTEGER NOT NULL,
+        full INTEGER NOT NULL,
+        partial INTEGER NOT NULL,
+        PRIMARY KEY (episode, date)
+    );
+    CREATE INDEX IF NOT EXISTS downloads_by_date ON downloads (date, episode);
+    CREATE VIEW IF NOT EXISTS episode_totals AS
+        SELECT episode, SUM(full) AS full, SUM(partial) AS partial
+        FROM downloads
+        GROUP BY episode;
+";
+
+/// Writes every per-day record into the SQLite database at `path`, creating
+/// it if needed, for querying from BI tools.
+///
+/// The `downloads` table's primary key indexes it by episode and a separate
+/// index covers queries by date. The `episode_totals` view sums each
+/// episode's downloads. Exporting again replaces the previous records.
+pub fn export_sqlite(db: &Database, path: &Path) -> anyhow::Result<usize> {
+    let mut sqlite = Connection::open(path)?;
+    let tx = sqlite.transaction()?;
+    tx.execute_batch(SCHEMA)?;
+    tx.execute("DELETE FROM downloads", [])?;
+    let mut exported = 0;
+    {
+        let mut insert = tx.prepare(
+            "INSERT INTO downloads (date, episode, full, partial) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        for document in PodcastDownloads::all(db).query()? {
+            let record = DailyRecord::new(&document.header.id, &document.contents)?;
+            insert.execute(params![
+                record.date,
+                record.episode,
+                record.full,
+                record.partial
+            ])?;
+            exported += 1;
+        }
+    }
+    tx.commit()?;
+    Ok(exported)
+}
+
+#[test]
+fn episode_totals_view() {
+    use bonsaidb::core::key::time::TimestampAsDays;
+    use crabtrics::testing::{insert_downloads, memory_database};
+
+    let db = memory_database();
+    for (episode, full_downloads, partial_downloads) in [(1, 3, 1), (2, 5, 0)] {
+        insert_downloads(
+            &db,
+            episode,
+            TimestampAsDays::now(),
+            PodcastDownloads {
+                full_downloads,
+                partial_downloads,
+                ..PodcastDownloads::default()
+            },
+        );
+    }
+    let path = std::env::temp_dir().join("crabtrics-episode-totals-view.sqlite");
+    let _ = std::fs::remove_file(&path);
+
+    // Exporting twice leaves a single copy of each record.
+    assert_eq!(export_sqlite(&db, &path).unwrap(), 2);
+    assert_eq!(export_sqlite(&db, &path).unwrap(), 2);
+
+    let sqlite = Connection::open(&path).unwrap();
+    let totals = sqlite
+        .prepare("SELECT episode, full, partial FROM episode_totals ORDER BY episode")
+        .unwrap()
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, u16>(0)?,
+                row.get::<_, u32>(1)?,
+                row.get::<_, u32>(2)?,
+            ))
+        })
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(totals, [(1, 3, 1), (2, 5, 0)]);
+
+    let plan: String = sqlite
+        .query_row(
+            "EXPLAIN QUERY PLAN SELECT SUM(full) FROM downloads WHERE date >= '2023-01-01'",
+            [],
+            |row| row.get(3),
+        )
+        .unwrap();
+    assert!(plan.contains("downloads_by_date"), "{plan}");
+}
@@ -0,0 +1,73 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+
+use bonsaidb::core::schema::SerializedView;
+use bonsaidb::local::Database;
+use crabtrics::schema::CompleteDownloads;
+
+/// Sends each episode's full downloads to the StatsD server at `host` as a
+/// gauge, such as `crabtrics.episode.12.full:345|g`.
+///
+/// Each metric is sent in its own packet. UDP doesn't report whether packets
+/// arrive, so an error only means that they couldn't be sent at all.
+pub fn send_episode_totals(db: &Database, host: &str, prefix: &str) -> anyhow::Result<()> {
+    let Some(address) = host.to_socket_addrs()?.next() else {
+        anyhow::bail!("{host} didn't resolve to any addresses")
+    };
+    let local = match address {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(address)?;
+    for mapping in CompleteDownloads::entries(db).reduce_grouped()? {
+        socket.send(gauge(prefix, mapping.key, mapping.value).as_bytes())?;
+    }
+    Ok(())
+}
+
+fn gauge(prefix: &str, episode: u16, full_downloads: u32) -> String {
+    format!("{prefix}.episode.{episode}.full:{full_downloads}|g")
+}
+
+#[test]
+fn episode_gauges() {
+    use std::time::Duration;
+
+    use bonsaidb::core::key::time::TimestampAsDays;
+    use crabtrics::schema::PodcastDownloads;
+    use crabtrics::testing::{insert_downloads, memory_database};
+
+    let db = memory_database();
+    for (episode, full_downloads) in [(12, 345), (3, 7)] {
+        insert_downloads(
+            &db,
+            episode,
+            TimestampAsDays::now(),
+            PodcastDownloads {
+                full_downloads,
+                ..PodcastDownloads::default()
+            },
+        );
+    }
+
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    server
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let host = server.local_addr().unwrap().to_string();
+    send_episode_totals(&db, &host, "crabtrics").unwrap();
+
+    let mut packets = Vec::new();
+    let mut buffer = [0; 512];
+    for _ in 0..2 {
+        let received = server.recv(&mut buffer).unwrap();
+        packets.push(String::from_utf8(buffer[..received].to_vec()).unwrap());
+    }
+    assert_eq!(
+        packets,
+        [
+            "crabtrics.episode.3.full:7|g",
+            "crabtrics.episode.12.full:345|g"
+        ]
+    );
+}
@@ -0,0 +1,254 @@
+use std::collections::BTreeMap;
+
+use bonsaidb::core::key::time::TimestampAsDays;
+use bonsaidb::core::schema::{SerializedCollection, SerializedView};
+use bonsaidb::core::transaction::{Operation, Transaction};
+use bonsaidb::local::Database;
+
+use crate::config::RowOrder;
+use crate::hll::HyperLogLog;
+use crate::schema::{
+    CampaignDateKey, CompleteDownloads, DateEpisodeKey, DownloadsByCampaign, DownloadsByDate,
+    DownloadsByMonth, DownloadsByWeek, EpisodeDateKey, EpisodeMonthKey, EpisodeVisitors,
+    EpisodeWeekKey, PartialDownloads, PodcastDownloads,
+};
+
+/// Where each episode's downloads on each day are stored, which imports write
+/// to and the report is generated from.
+///
+/// The BonsaiDB [`Database`] is the default store. Another backend only needs
+/// to keep the per-day records and answer the totals below, which BonsaiDB
+/// reads from the views in [`crate::schema`].
+pub trait DownloadStore: Send + Sync {
+    /// Returns the downloads stored for `key`, or None if none were stored.
+    fn get(&self, key: &EpisodeDateKey) -> anyhow::Result<Option<PodcastDownloads>>;
+
+    /// Stores `downloads` for `key`, replacing any already stored.
+    fn overwrite(&self, key: &EpisodeDateKey, downloads: &PodcastDownloads) -> anyhow::Result<()>;
+
+    /// Stores every one of `downloads`. Stores that can should write them all
+    /// or none of them, so that a failed import leaves the previous records.
+    fn overwrite_all(
+        &self,
+        downloads: &[(EpisodeDateKey, PodcastDownloads)],
+    ) -> anyhow::Result<()> {
+        for (key, downloads) in downloads {
+            self.overwrite(key, downloads)?;
+        }
+        Ok(())
+    }
+
+    /// Returns every stored record, ordered by episode and then date, or by
+    /// date and then episode.
+    fn downloads(&self, order: RowOrder)
+        -> anyhow::Result<Vec<(EpisodeDateKey, PodcastDownloads)>>;
+
+    /// Returns every stored record of `episode`, oldest first.
+    fn episode_downloads(
+        &self,
+        episode: u16,
+    ) -> anyhow::Result<Vec<(EpisodeDateKey, PodcastDownloads)>>;
+
+    /// Returns each episode's full downloads, ordered by episode.
+    fn episode_totals(&self) -> anyhow::Result<Vec<(u16, u32)>>;
+
+    /// Returns each episode's partial downloads.
+    fn partial_totals(&self) -> anyhow::Result<BTreeMap<u16, u32>>;
+
+    /// Returns each episode's distinct visitors across every day.
+    fn episode_visitors(&self) -> anyhow::Result<BTreeMap<u16, HyperLogLog>>;
+
+    /// Returns each episode's full downloads on each day from `start` on.
+    fn downloads_since(&self, start: TimestampAsDays)
+        -> anyhow::Result<Vec<(DateEpisodeKey, u32)>>;
+
+    /// Returns each episode's full downloads in each ISO week.
+    fn weekly_downloads(&self) -> anyhow::Result<Vec<(EpisodeWeekKey, u32)>>;
+
+    /// Returns each episode's full downloads in each calendar month.
+    fn monthly_downloads(&self) -> anyhow::Result<Vec<(EpisodeMonthKey, u32)>>;
+
+    /// Returns each campaign's full downloads on each day.
+    fn campaign_downloads(&self) -> anyhow::Result<Vec<(CampaignDateKey, u32)>>;
+}
+
+impl DownloadStore for Database {
+    fn get(&self, key: &EpisodeDateKey) -> anyhow::Result<Option<PodcastDownloads>> {
+        Ok(PodcastDownloads::get(key, self)?.map(|document| document.contents))
+    }
+
+    fn overwrite(&self, key: &EpisodeDateKey, downloads: &PodcastDownloads) -> anyhow::Result<()> {
+        let mut tx = Transaction::new();
+        tx.push(Operation::overwrite_serialized::<PodcastDownloads, _>(
+            key, downloads,
+        )?);
+        tx.apply(self)?;
+        Ok(())
+    }
+
+    /// Writes every record in a single transaction.
+    fn overwrite_all(
+        &self,
+        downloads: &[(EpisodeDateKey, PodcastDownloads)],
+    ) -> anyhow::Result<()> {
+        let mut tx = Transaction::new();
+        for (key, downloads) in downloads {
+            tx.push(Operation::overwrite_serialized::<PodcastDownloads, _>(
+                key, downloads,
+            )?);
+        }
+        tx.apply(self)?;
+        Ok(())
+    }
+
+    /// Records ordered by date follow the `DownloadsByDate` index, so neither
+    /// order is sorted in memory.
+    fn downloads(
+        &self,
+        order: RowOrder,
+    ) -> anyhow::Result<Vec<(EpisodeDateKey, PodcastDownloads)>> {
+        let documents = match order {
+            RowOrder::Episode => PodcastDownloads::all(self).query()?,
+            RowOrder::Date => {
+                let mut mapped = DownloadsByDate::entries(self).query_with_collection_docs()?;
+                mapped
+                    .mappings
+                    .iter()
+                    .filter_map(|mapping| mapped.documents.remove(&mapping.source.id))
+                    .collect()
+            }
+        };
+        Ok(documents
+            .into_iter()
+            .map(|document| (document.header.id, document.contents))
+            .collect())
+    }
+
+    fn episode_downloads(
+        &self,
+        episode: u16,
+    ) -> anyhow::Result<Vec<(EpisodeDateKey, PodcastDownloads)>> {
+        Ok(
+            PodcastDownloads::list(EpisodeDateKey::range_for_episode(episode), self)?
+                .into_iter()
+                .map(|document| (document.header.id, document.contents))
+                .collect(),
+        )
+    }
+
+    fn episode_totals(&self) -> anyhow::Result<Vec<(u16, u32)>> {
+        Ok(CompleteDownloads::entries(self)
+            .reduce_grouped()?
+            .into_iter()
+            .map(|mapping| (mapping.key, mapping.value))
+            .collect())
+    }
+
+    fn partial_totals(&self) -> anyhow::Result<BTreeMap<u16, u32>> {
+        Ok(PartialDownloads::entries(self)
+            .reduce_grouped()?
+            .into_iter()
+            .map(|mapping| (mapping.key, mapping.value))
+            .collect())
+    }
+
+    fn episode_visitors(&self) -> anyhow::Result<BTreeMap<u16, HyperLogLog>> {
+        Ok(EpisodeVisitors::entries(self)
+            .reduce_grouped()?
+            .into_iter()
+            .map(|mapping| (mapping.key, mapping.value))
+            .collect())
+    }
+
+    fn downloads_since(
+        &self,
+        start: TimestampAsDays,
+    ) -> anyhow::Result<Vec<(DateEpisodeKey, u32)>> {
+        Ok(DownloadsByDate::entries(self)
+            .with_key_range(DateEpisodeKey::range_starting_at(start))
+            .query()?
+            .into_iter()
+            .map(|mapping| (mapping.key, mapping.value))
+            .collect())
+    }
+
+    fn weekly_downloads(&self) -> anyhow::Result<Vec<(EpisodeWeekKey, u32)>> {
+        Ok(DownloadsByWeek::entries(self)
+            .reduce_grouped()?
+            .into_iter()
+            .map(|mapping| (mapping.key, mapping.value))
+            .collect())
+    }
+
+    fn monthly_downloads(&self) -> anyhow::Result<Vec<(EpisodeMonthKey, u32)>> {
+        Ok(DownloadsByMonth::entries(self)
+            .reduce_grouped()?
+            .into_iter()
+            .map(|mapping| (mapping.key, mapping.value))
+            .collect())
+    }
+
+    fn campaign_downloads(&self) -> anyhow::Result<Vec<(CampaignDateKey, u32)>> {
+        Ok(DownloadsByCampaign::entries(self)
+            .reduce_grouped()?
+            .into_iter()
+            .map(|mapping| (mapping.key, mapping.value))
+            .collect())
+    }
+}
+
+#[test]
+fn database_store() {
+    use crate::export::days_ago;
+    use crate::testing::memory_database;
+
+    let db = memory_database();
+    let store: &dyn DownloadStore = &db;
+    let key = |episode, days| EpisodeDateKey {
+        episode,
+        date: days_ago(days).unwrap(),
+    };
+    let downloads = |full_downloads| PodcastDownloads {
+        full_downloads,
+        ..PodcastDownloads::default()
+    };
+    store
+        .overwrite_all(&[
+            (key(1, 1), downloads(1)),
+            (key(2, 2), downloads(2)),
+            (key(1, 2), downloads(3)),
+        ])
+        .unwrap();
+    store.overwrite(&key(1, 1), &downloads(4)).unwrap();
+
+    assert_eq!(store.get(&key(1, 1)).unwrap(), Some(downloads(4)));
+    assert_eq!(store.get(&key(2, 1)).unwrap(), None);
+    let order = |order| {
+        store
+            .downloads(order)
+            .unwrap()
+            .into_iter()
+            .map(|(key, downloads)| (key, downloads.full_downloads))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        order(RowOrder::Episode),
+        [(key(1, 2), 3), (key(1, 1), 4), (key(2, 2), 2)]
+    );
+    assert_eq!(
+        order(RowOrder::Date),
+        [(key(1, 2), 3), (key(2, 2), 2), (key(1, 1), 4)]
+    );
+    assert_eq!(store.episode_downloads(2).unwrap().len(), 1);
+    assert_eq!(store.episode_totals().unwrap(), [(1, 7), (2, 2)]);
+    assert_eq!(
+        store.downloads_since(days_ago(1).unwrap()).unwrap(),
+        [(
+            DateEpisodeKey {
+                date: days_ago(1).unwrap(),
+                episode: 1
+            },
+            4
+        )]
+    );
+}
@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bonsaidb::core::key::time::TimestampAsDays;
+use bonsaidb::core::transaction::{Operation, Transaction};
+use bonsaidb::local::config::{Builder, StorageConfiguration};
+use bonsaidb::local::Database;
+use time::OffsetDateTime;
+
+use crate::aggregation::{aggregate_logs, tally_downloads, Aggregation};
+use crate::config::Config;
+use crate::schema::{Crabtrics, EpisodeDateKey, PodcastDownloads};
+use crate::sizes::EpisodeSizes;
+
+pub const SAMPLE_LOG: &str = r#"172.56.208.121 - - [08/May/2023:15:08:30 +0000] "GET /episode-001.m4a HTTP/1.1" 206 106500 "https://wayofthecrab.com/" "Mozilla/5.0 (iPhone; CPU iPhone OS 16_4_1 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.4 Mobile/15E148 Safari/604.1"
+172.56.208.121 - - [08/May/2023:15:08:30 +0000] "GET /episode-001.m4a HTTP/1.1" 206 106501 "https://wayofthecrab.com/" "Mozilla/5.0 (iPhone; CPU iPhone OS 16_4_1 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.4 Mobile/15E148 Safari/604.1"
+"#;
+
+/// Creates an empty directory in the system temp dir containing an
+/// `episode-001.m4a` of `episode_size` bytes.
+pub fn test_episodes_dir(name: &str, episode_size: usize) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("crabtrics-{name}"));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("episode-001.m4a"), vec![0; episode_size]).unwrap();
+    dir
+}
+
+/// Aggregates `logs` against the episode files in `dir` and tallies the
+/// downloads of every episode and day.
+pub fn tally_logs(
+    logs: &str,
+    dir: &Path,
+    config: &Config,
+) -> HashMap<EpisodeDateKey, PodcastDownloads> {
+    let mut aggregation = Aggregation::default();
+    aggregate_logs(
+        logs.as_bytes(),
+        &mut aggregation,
+        &mut EpisodeSizes::from_directory(dir),
+        OffsetDateTime::UNIX_EPOCH,
+        config,
+    )
+    .unwrap();
+    tally_downloads(aggregation, config)
+}
+
+/// Tallies `logs` against an `episode-001.m4a` of `episode_size` bytes,
+/// returning the downloads of the one episode and day they request.
+pub fn tally_sample(logs: &str, episode_size: usize, config: &Config) -> PodcastDownloads {
+    static SAMPLES: AtomicUsize = AtomicUsize::new(0);
+    let name = format!(
+        "sample-{}-{}",
+        std::process::id(),
+        SAMPLES.fetch_add(1, Ordering::Relaxed)
+    );
+    let dir = test_episodes_dir(&name, episode_size);
+    let tallied = tally_logs(logs, &dir, config);
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(tallied.len(), 1, "{tallied:?}");
+    tallied.into_values().next().unwrap()
+}
+
+pub fn gzip_compress(contents: &[u8]) -> Vec<u8> {
+    let mut encoder = libflate::gzip::Encoder::new(Vec::new()).unwrap();
+    encoder.write_all(contents).unwrap();
+    encoder.finish().into_result().unwrap()
+}
+
+pub fn memory_database() -> Database {
+    Database::open::<Crabtrics>(StorageConfiguration::default().memory_only()).unwrap()
+}
+
+pub fn insert_downloads(
+    db: &Database,
+    episode: u16,
+    date: TimestampAsDays,
+    downloads: PodcastDownloads,
+) {
+    let mut tx = Transaction::new();
+    tx.push(
+        Operation::overwrite_serialized::<PodcastDownloads, _>(
+            &EpisodeDateKey { episode, date },
+            &downloads,
+        )
+        .unwrap(),
+    );
+    tx.apply(db).unwrap();
+}
@@ -0,0 +1,129 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::config::VisitorIdentity;
+use crate::hll;
+
+/// Decides which requests come from the same visitor.
+///
+/// [`VisitorIdentity`] covers grouping by network and user agent. Any other
+/// notion of a visitor can be plugged in by implementing this, or by passing a
+/// closure taking the address and user agent.
+pub trait VisitorKey {
+    /// Returns a hash identifying the visitor that made requests from
+    /// `address` with `user_agent`. Requests with the same key are counted as
+    /// one visitor, and the key is what's counted in the visitor sketch.
+    fn visitor_key(&self, address: IpAddr, user_agent: &str) -> u64;
+}
+
+impl VisitorKey for VisitorIdentity {
+    fn visitor_key(&self, address: IpAddr, user_agent: &str) -> u64 {
+        VisitorId::new(address, user_agent, self).sketch_hash()
+    }
+}
+
+impl<F> VisitorKey for F
+where
+    F: Fn(IpAddr, &str) -> u64,
+{
+    fn visitor_key(&self, address: IpAddr, user_agent: &str) -> u64 {
+        self(address, user_agent)
+    }
+}
+
+/// An approximation of a single device, derived from a request's address and
+/// user agent as configured by [`VisitorIdentity`].
+///
+/// Identities only exist in memory while logs are imported. The database only
+/// stores the resulting download counts and a sketch of their hashes.
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub struct VisitorId {
+    network: IpAddr,
+    user_agent: Option<u64>,
+}
+
+impl VisitorId {
+    pub fn new(address: IpAddr, user_agent: &str, identity: &VisitorIdentity) -> Self {
+        let network = requestor_key(address, identity.ipv4_prefix, identity.ipv6_prefix);
+        let user_agent = identity
+            .include_user_agent
+            .then(|| hll::hash(user_agent.as_bytes()));
+        Self {
+            network,
+            user_agent,
+        }
+    }
+
+    /// Returns the hash that counts this visitor in a
+    /// [`HyperLogLog`](hll::HyperLogLog).
+    pub fn sketch_hash(&self) -> u64 {
+        let mut bytes = match self.network {
+            IpAddr::V4(network) => network.octets().to_vec(),
+            IpAddr::V6(network) => network.octets().to_vec(),
+        };
+        if let Some(user_agent) = self.user_agent {
+            bytes.extend(user_agent.to_le_bytes());
+        }
+        hll::hash(&bytes)
+    }
+}
+
+/// Returns the network `ip` is in, keeping its first `v4_bits` or `v6_bits`
+/// and zeroing the rest, so that a listener whose address changes within
+/// their provider's network is still one requestor.
+pub fn requestor_key(ip: IpAddr, v4_bits: u8, v6_bits: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(address) => {
+            let mask = u32::MAX
+                .checked_shl(32 - u32::from(v4_bits.min(32)))
+                .unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(address) & mask))
+        }
+        IpAddr::V6(address) => {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(v6_bits.min(128)))
+                .unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(address) & mask))
+        }
+    }
+}
+
+#[test]
+fn network_prefixes() {
+    let identity = VisitorIdentity {
+        ipv4_prefix: 24,
+        ipv6_prefix: 64,
+        include_user_agent: false,
+    };
+    assert_eq!(
+        requestor_key("172.56.208.121".parse().unwrap(), 24, 64),
+        "172.56.208.0".parse::<IpAddr>().unwrap()
+    );
+    assert_eq!(
+        requestor_key("2001:db8:1:2:3::1".parse().unwrap(), 24, 64),
+        "2001:db8:1:2::".parse::<IpAddr>().unwrap()
+    );
+    assert_eq!(
+        requestor_key("172.56.208.121".parse().unwrap(), 32, 128),
+        "172.56.208.121".parse::<IpAddr>().unwrap()
+    );
+    let id = |address: &str| VisitorId::new(address.parse().unwrap(), "agent", &identity);
+    assert_eq!(id("172.56.208.121"), id("172.56.208.9"));
+    assert_ne!(id("172.56.208.121"), id("172.56.209.121"));
+    assert_eq!(id("2001:db8:1:2:3::1"), id("2001:db8:1:2:4::1"));
+    assert_ne!(id("2001:db8:1:2::1"), id("2001:db8:1:3::1"));
+
+    let everything = VisitorIdentity {
+        ipv4_prefix: 0,
+        ipv6_prefix: 0,
+        include_user_agent: true,
+    };
+    let address = "172.56.208.121".parse().unwrap();
+    assert_eq!(
+        VisitorId::new(address, "agent", &everything),
+        VisitorId::new("10.0.0.1".parse().unwrap(), "agent", &everything)
+    );
+    assert_ne!(
+        VisitorId::new(address, "agent", &everything),
+        VisitorId::new(address, "other agent", &everything)
+    );
+}
@@ -0,0 +1,75 @@
+//! Browser bindings for the log parser, built with `wasm-pack build`.
+
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+
+use crate::access_logs::LogReader;
+use crate::episodes::{normalize_path, parse_episode_path};
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ParsedEntry {
+    requestor: String,
+    /// Seconds since the Unix epoch.
+    timestamp: i64,
+    method: String,
+    path: String,
+    protocol: String,
+    response_code: u16,
+    bytes_sent: u32,
+    referrer: String,
+    user_agent: String,
+    range: Option<String>,
+    /// The episode the request was for, if it requested an episode's file.
+    episode: Option<u16>,
+}
+
+/// Parses the contents of an access log into an array of entries.
+#[wasm_bindgen(js_name = parseLogs)]
+pub fn parse_logs(logs: &[u8]) -> Result<JsValue, JsError> {
+    let mut reader = LogReader::new(logs);
+    let mut entries = Vec::new();
+    while let Some(entry) = reader
+        .read_one()
+        .map_err(|err| JsError::new(&err.to_string()))?
+    {
+        entries.push(ParsedEntry {
+            requestor: entry.requestor.to_string(),
+            timestamp: entry.time.unix_timestamp(),
+            method: entry.method.to_string(),
+            path: entry.path.to_string(),
+            protocol: entry.protocol.to_string(),
+            response_code: entry.response_code,
+            bytes_sent: entry.bytes_sent,
+            referrer: entry.referrer.to_string(),
+            user_agent: entry.user_agent.to_string(),
+            range: entry.range.map(str::to_string),
+            episode: normalize_path(entry.path)
+                .and_then(|path| parse_episode_path(&path).and_then(|file| file.number())),
+        });
+    }
+    Ok(serde_wasm_bindgen::to_value(&entries)?)
+}
+
+#[cfg(test)]
+#[wasm_bindgen_test::wasm_bindgen_test]
+fn parses_sample_logs() {
+    const SAMPLE_LOGS: &str = r#"172.56.208.121 - - [08/May/2023:15:08:30 +0000] "GET /episode-001.m4a HTTP/1.1" 206 212698 "https://wayofthecrab.com/" "Mozilla/5.0 (iPhone; CPU iPhone OS 16_4_1 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.4 Mobile/15E148 Safari/604.1"
+172.56.208.121 - - [08/May/2023:15:08:30 +0000] "GET /index.html HTTP/1.1" 200 303 "https://wayofthecrab.com/" "Mozilla/5.0 (iPhone; CPU iPhone OS 16_4_1 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.4 Mobile/15E148 Safari/604.1"
+"#;
+
+    #[derive(serde::Deserialize)]
+    struct Entry {
+        path: String,
+        timestamp: i64,
+        episode: Option<u16>,
+    }
+
+    let parsed = parse_logs(SAMPLE_LOGS.as_bytes()).unwrap();
+    let entries: Vec<Entry> = serde_wasm_bindgen::from_value(parsed).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].path, "/episode-001.m4a");
+    assert_eq!(entries[0].timestamp, 1_683_558_510);
+    assert_eq!(entries[0].episode, Some(1));
+    assert_eq!(entries[1].episode, None);
+}